@@ -0,0 +1,82 @@
+use std::fmt;
+
+use crate::IndexBase;
+
+/// Errors of writing a BMS file
+#[derive(Debug)]
+pub enum BmsError {
+    /// Objects are placed beyond measure #999
+    TooManyBars {
+        measures: usize,
+    },
+    /// An index of a definition does not fit in two characters of the base
+    IndexOverflow {
+        index: usize,
+        base: IndexBase,
+    },
+    /// A BPM of the BPM channel is over FF
+    BpmOverflow {
+        bpm: usize,
+    },
+    /// A channel ID is not between 01 and 99, or objects are pushed to the measure length channel
+    InvalidChannel {
+        channel: u8,
+    },
+    /// A measure length is not a positive number
+    InvalidMeasureLength {
+        measure: usize,
+        length: f64,
+    },
+    /// A header command is not alphanumeric, a comment line starts with `#`,
+    /// or a header value contains a line break
+    InvalidHeader {
+        header: String,
+    },
+    /// A text header contains characters which Shift_JIS cannot encode
+    Unencodable {
+        text: String,
+    },
+    Io(std::io::Error),
+}
+
+impl fmt::Display for BmsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BmsError::TooManyBars { measures } => {
+                write!(f, "{measures} measures exceed the limit of 999")
+            }
+            BmsError::IndexOverflow { index, base } => write!(
+                f,
+                "index {index} exceeds the maximum {} of {base:?}",
+                base.max_index()
+            ),
+            BmsError::BpmOverflow { bpm } => {
+                write!(f, "BPM {bpm} does not fit in the BPM channel")
+            }
+            BmsError::InvalidChannel { channel } => write!(f, "invalid channel: {channel}"),
+            BmsError::InvalidMeasureLength { measure, length } => {
+                write!(f, "invalid length of measure #{measure:03}: {length}")
+            }
+            BmsError::InvalidHeader { header } => write!(f, "invalid header: {header:?}"),
+            BmsError::Unencodable { text } => {
+                write!(f, "cannot be encoded in Shift_JIS: {text}")
+            }
+            BmsError::Io(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for BmsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BmsError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for BmsError {
+    fn from(err: std::io::Error) -> Self {
+        BmsError::Io(err)
+    }
+}
@@ -1,24 +1,176 @@
-use std::{collections::BTreeMap, io::Write};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    io::Write,
+};
 
 use encoding_rs::SHIFT_JIS;
 
+mod error;
+mod metadata;
+mod parser;
+mod position;
+
+pub use error::BmsError;
+pub use metadata::{JudgeRank, Metadata, Player};
+pub use position::{lcm, Position};
+
 pub const KEYBOARD_CHANNELS: [u8; 7] = [11, 12, 13, 14, 15, 18, 19];
 pub const SCRATCH_CHANNEL: u8 = 16;
 pub const BGM_CHANNEL: u8 = 1;
+/// Channel of measure lengths, which hold a decimal ratio to 4/4 instead of objects
+pub const MEASURE_LENGTH_CHANNEL: u8 = 2;
+pub const BPM_CHANNEL: u8 = 3;
+pub const EXTENDED_BPM_CHANNEL: u8 = 8;
+pub const STOP_CHANNEL: u8 = 9;
+/// Offset from a key channel to its long note channel (e.g. 11 to 51)
+pub const LONG_NOTE_CHANNEL_OFFSET: u8 = 40;
+
+/// Objects of a measure in the order they are pushed
+type Bar = Vec<(Position, usize)>;
 
-type Bar = Vec<Vec<Option<usize>>>;
+/// Rows are not merged beyond this resolution, which keeps lines short enough for players
+const MAX_RESOLUTION: usize = 1920;
+
+static INDEX_CHARS: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+/// Radix of the two-character indices of definitions
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum IndexBase {
+    /// `00` to `ZZ`, case-insensitive
+    #[default]
+    Base36,
+    /// `00` to `zz` with `#BASE 62`, where lowercase letters follow uppercase ones
+    Base62,
+}
+
+impl IndexBase {
+    fn radix(self) -> usize {
+        match self {
+            IndexBase::Base36 => 36,
+            IndexBase::Base62 => 62,
+        }
+    }
+
+    /// The largest 0-based index which can be written, as `00` is reserved for no object
+    pub fn max_index(self) -> usize {
+        self.radix() * self.radix() - 2
+    }
+}
 
-fn to_bms_index(idx: usize) -> String {
-    static CHARS: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ";
-    format!(
+fn to_bms_index(idx: usize, base: IndexBase) -> Option<String> {
+    if idx > base.max_index() {
+        return None;
+    }
+    let radix = base.radix();
+    Some(format!(
         "{}{}",
-        CHARS[(idx + 1) / 36] as char,
-        CHARS[(idx + 1) % 36] as char
+        INDEX_CHARS[(idx + 1) / radix] as char,
+        INDEX_CHARS[(idx + 1) % radix] as char
+    ))
+}
+
+fn from_bms_index(s: &str, base: IndexBase) -> Option<usize> {
+    let mut value = 0;
+    for c in s.bytes() {
+        let c = match base {
+            IndexBase::Base36 => c.to_ascii_uppercase(),
+            IndexBase::Base62 => c,
+        };
+        let digit = INDEX_CHARS[..base.radix()].iter().position(|&x| x == c)?;
+        value = value * base.radix() + digit;
+    }
+    value.checked_sub(1)
+}
+
+fn write_index(index: usize, base: IndexBase) -> Result<String, BmsError> {
+    to_bms_index(index, base).ok_or(BmsError::IndexOverflow { index, base })
+}
+
+/// Formats an object of the channel. Objects of the BPM channel hold the BPM itself in hexadecimal,
+/// while the others hold 0-based indices of definitions.
+fn format_object(
+    channel_idx: u8,
+    object: Option<usize>,
+    base: IndexBase,
+) -> Result<String, BmsError> {
+    match object {
+        Some(bpm) if channel_idx == BPM_CHANNEL => {
+            if bpm > 0xFF {
+                return Err(BmsError::BpmOverflow { bpm });
+            }
+            Ok(format!("{bpm:02X}"))
+        }
+        Some(idx) => write_index(idx, base),
+        None => Ok("00".to_owned()),
+    }
+}
+
+/// Whether objects of the channel are indices of keysounds, rather than BPMs, stops or images
+fn refers_to_keysound(channel_id: u8) -> bool {
+    !matches!(
+        channel_id,
+        BPM_CHANNEL | EXTENDED_BPM_CHANNEL | STOP_CHANNEL | 4 | 6 | 7
     )
 }
 
-fn to_shift_jis(s: &str) -> Vec<u8> {
-    SHIFT_JIS.encode(s).0.to_vec()
+fn to_shift_jis(s: &str) -> Result<Vec<u8>, BmsError> {
+    let (encoded, _, had_errors) = SHIFT_JIS.encode(s);
+    if had_errors {
+        return Err(BmsError::Unencodable {
+            text: s.trim_end().to_owned(),
+        });
+    }
+    Ok(encoded.into_owned())
+}
+
+/// Writes a header holding text, unless the text is empty
+/// Whether a header command can be written, such as `LNTYPE` or `BMP01`
+fn is_header_command(command: &str) -> bool {
+    command
+        .bytes()
+        .all(|c| c.is_ascii_alphanumeric() || c == b'_')
+        && command
+            .bytes()
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic())
+}
+
+fn write_text_header(w: &mut impl Write, command: &str, value: &str) -> Result<(), BmsError> {
+    if !value.is_empty() {
+        w.write_all(&to_shift_jis(&format!("#{command} {value}\n"))?)?;
+    }
+    Ok(())
+}
+
+/// Lays out the objects of a measure in as few rows as possible. An object goes to the first row
+/// without an object at the same position, and each row has the least common multiple of the
+/// denominators of its positions as the resolution.
+fn bar_rows(bar: &Bar) -> Vec<Vec<Option<usize>>> {
+    let mut rows: Vec<(usize, Vec<(Position, usize)>)> = Vec::new();
+    for &(position, object) in bar.iter() {
+        let fits = |(resolution, row): &&mut (usize, Vec<(Position, usize)>)| {
+            lcm(*resolution, position.denominator()) <= MAX_RESOLUTION.max(*resolution)
+                && row.iter().all(|&(other, _)| other != position)
+        };
+        match rows.iter_mut().find(fits) {
+            Some((resolution, row)) => {
+                *resolution = lcm(*resolution, position.denominator());
+                row.push((position, object));
+            }
+            None => rows.push((position.denominator(), vec![(position, object)])),
+        }
+    }
+
+    rows.into_iter()
+        .map(|(resolution, row)| {
+            let mut objects = vec![None; resolution];
+            for (position, object) in row {
+                objects[position.numerator() * (resolution / position.denominator())] =
+                    Some(object);
+            }
+            objects
+        })
+        .collect()
 }
 
 #[derive(Clone, Debug)]
@@ -31,27 +183,42 @@ impl Channel {
         Channel { bars: Vec::new() }
     }
 
-    fn push_to_bar(&mut self, bar_idx: usize, bar: Vec<Option<usize>>) {
-        if self.bars.len() < bar_idx + 1 {
-            self.bars.resize_with(bar_idx + 1, Vec::new);
+    fn bar_mut(&mut self, measure: usize) -> &mut Bar {
+        if self.bars.len() < measure + 1 {
+            self.bars.resize_with(measure + 1, Vec::new);
         }
 
-        self.bars[bar_idx].push(bar);
+        &mut self.bars[measure]
+    }
+
+    fn push_to_bar(&mut self, measure: usize, row: Vec<Option<usize>>) {
+        let resolution = row.len();
+        let bar = self.bar_mut(measure);
+        for (i, object) in row.into_iter().enumerate() {
+            if let (Some(object), Some(position)) = (object, Position::new(i, resolution)) {
+                bar.push((position, object));
+            }
+        }
+    }
+
+    fn rows(&self) -> impl Iterator<Item = (usize, Vec<Option<usize>>)> + '_ {
+        self.bars
+            .iter()
+            .enumerate()
+            .flat_map(|(measure, bar)| bar_rows(bar).into_iter().map(move |row| (measure, row)))
     }
 
-    fn write(&self, channel_idx: u8, w: &mut impl Write) -> std::io::Result<()> {
-        for (bar_idx, bar) in self.bars.iter().enumerate() {
-            for single_bar in bar.iter() {
-                write!(w, "#{bar_idx:03}{channel_idx:02}:", bar_idx = bar_idx + 1)?;
-                for &sound_idx in single_bar.iter() {
-                    if let Some(sound_idx) = sound_idx {
-                        write!(w, "{}", to_bms_index(sound_idx))?;
-                    } else {
-                        write!(w, "00")?;
-                    }
-                }
-                writeln!(w)?;
+    fn objects_mut(&mut self) -> impl Iterator<Item = &mut usize> {
+        self.bars.iter_mut().flatten().map(|(_, object)| object)
+    }
+
+    fn write(&self, channel_idx: u8, base: IndexBase, w: &mut impl Write) -> Result<(), BmsError> {
+        for (measure, row) in self.rows() {
+            write!(w, "#{measure:03}{channel_idx:02}:")?;
+            for object in row {
+                write!(w, "{}", format_object(channel_idx, object, base)?)?;
             }
+            writeln!(w)?;
         }
 
         Ok(())
@@ -62,11 +229,14 @@ impl Channel {
 pub struct BmsWriter {
     channels: BTreeMap<u8, Channel>,
     keysounds: BTreeMap<usize, String>,
+    bpm_definitions: BTreeMap<usize, f32>,
+    stop_definitions: BTreeMap<usize, u32>,
+    measure_lengths: BTreeMap<usize, f64>,
+    lnobj: Option<usize>,
+    base: IndexBase,
     bpm: f32,
     total: f32,
-    title: String,
-    genre: String,
-    artist: String,
+    metadata: Metadata,
 }
 
 impl BmsWriter {
@@ -74,20 +244,36 @@ impl BmsWriter {
         BmsWriter {
             bpm: 120.0,
             total: 300.0,
+            metadata: Metadata {
+                playlevel: Some(1),
+                ..Default::default()
+            },
             ..Default::default()
         }
     }
 
+    pub fn set_metadata(&mut self, metadata: Metadata) {
+        self.metadata = metadata;
+    }
+
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    pub fn metadata_mut(&mut self) -> &mut Metadata {
+        &mut self.metadata
+    }
+
     pub fn set_artist(&mut self, artist: &str) {
-        self.artist = artist.to_owned();
+        self.metadata.artist = artist.to_owned();
     }
 
     pub fn set_title(&mut self, title: &str) {
-        self.title = title.to_owned();
+        self.metadata.title = title.to_owned();
     }
 
     pub fn set_genre(&mut self, genre: &str) {
-        self.genre = genre.to_owned();
+        self.metadata.genre = genre.to_owned();
     }
 
     pub fn set_bpm(&mut self, bpm: f32) {
@@ -98,33 +284,505 @@ impl BmsWriter {
         self.total = total;
     }
 
+    pub fn set_playlevel(&mut self, playlevel: u32) {
+        self.metadata.playlevel = Some(playlevel);
+    }
+
+    /// Sets the difficulty name shown by players (1: BEGINNER, 2: NORMAL, 3: HYPER, 4: ANOTHER, 5: INSANE).
+    pub fn set_difficulty(&mut self, difficulty: u32) {
+        self.metadata.difficulty = Some(difficulty);
+    }
+
+    pub fn artist(&self) -> &str {
+        &self.metadata.artist
+    }
+
+    pub fn title(&self) -> &str {
+        &self.metadata.title
+    }
+
+    pub fn genre(&self) -> &str {
+        &self.metadata.genre
+    }
+
+    pub fn bpm(&self) -> f32 {
+        self.bpm
+    }
+
+    pub fn total(&self) -> f32 {
+        self.total
+    }
+
+    pub fn playlevel(&self) -> Option<u32> {
+        self.metadata.playlevel
+    }
+
+    pub fn difficulty(&self) -> Option<u32> {
+        self.metadata.difficulty
+    }
+
+    /// Pushes a row of objects to the channel. `bar_idx` 0 is written as measure #001,
+    /// leaving measure #000 as a lead-in.
     pub fn push_channel(&mut self, bar_idx: usize, channel_id: u8, bar: Vec<Option<usize>>) {
+        self.push_measure(bar_idx + 1, channel_id, bar);
+    }
+
+    fn push_measure(&mut self, measure: usize, channel_id: u8, bar: Vec<Option<usize>>) {
+        let channel = self.channels.entry(channel_id).or_insert_with(Channel::new);
+        channel.push_to_bar(measure, bar);
+    }
+
+    /// Sets the length of the bar relative to 4/4, numbered like `push_channel`.
+    pub fn set_measure_length(&mut self, bar_idx: usize, length: f64) {
+        self.measure_lengths.insert(bar_idx + 1, length);
+    }
+
+    /// Lengths of measures as `(measure, length)`, numbered like `channel`.
+    pub fn measure_lengths(&self) -> impl Iterator<Item = (usize, f64)> + '_ {
+        self.measure_lengths
+            .iter()
+            .map(|(&measure, &length)| (measure, length))
+    }
+
+    pub fn channel_ids(&self) -> impl Iterator<Item = u8> + '_ {
+        self.channels.keys().copied()
+    }
+
+    /// Pushes an object to the channel at a position in the bar, numbered like `push_channel`.
+    /// Objects are merged into rows when written.
+    pub fn push_object(
+        &mut self,
+        bar_idx: usize,
+        channel_id: u8,
+        position: Position,
+        object: usize,
+    ) {
         let channel = self.channels.entry(channel_id).or_insert_with(Channel::new);
-        channel.push_to_bar(bar_idx, bar);
+        channel.bar_mut(bar_idx + 1).push((position, object));
+    }
+
+    /// Returns the rows of the channel as `(measure, objects)` pairs, as they are written.
+    /// Rows hold the objects of a measure at the least resolution, and are split only where objects collide.
+    pub fn channel(
+        &self,
+        channel_id: u8,
+    ) -> impl Iterator<Item = (usize, Vec<Option<usize>>)> + '_ {
+        self.channels
+            .get(&channel_id)
+            .into_iter()
+            .flat_map(|channel| channel.rows())
     }
 
     pub fn set_keysound(&mut self, idx: usize, name: &str) {
-        self.keysounds.insert(idx, name.to_owned());
+        self.keysounds.insert(idx, format!("{name}.wav"));
     }
 
-    pub fn write(&self, w: &mut impl Write) -> std::io::Result<()> {
-        writeln!(w, "#PLAYER 1")?;
-        w.write(&to_shift_jis(&format!("#TITLE {}\n", self.title)))?;
-        w.write(&to_shift_jis(&format!("#GENRE {}\n", self.genre)))?;
-        w.write(&to_shift_jis(&format!("#ARTIST {}\n", self.artist)))?;
+    /// Returns the filename of the keysound, including its extension.
+    pub fn keysound(&self, idx: usize) -> Option<&str> {
+        self.keysounds.get(&idx).map(String::as_str)
+    }
+
+    pub fn keysounds(&self) -> impl Iterator<Item = (usize, &str)> {
+        self.keysounds
+            .iter()
+            .map(|(&idx, filename)| (idx, filename.as_str()))
+    }
+
+    /// Defines an extended BPM referred from `EXTENDED_BPM_CHANNEL`.
+    pub fn set_bpm_definition(&mut self, idx: usize, bpm: f32) {
+        self.bpm_definitions.insert(idx, bpm);
+    }
+
+    pub fn bpm_definition(&self, idx: usize) -> Option<f32> {
+        self.bpm_definitions.get(&idx).copied()
+    }
+
+    /// Defines a stop referred from `STOP_CHANNEL`. `length` is in 1/192 of a whole note.
+    pub fn set_stop_definition(&mut self, idx: usize, length: u32) {
+        self.stop_definitions.insert(idx, length);
+    }
+
+    pub fn stop_definition(&self, idx: usize) -> Option<u32> {
+        self.stop_definitions.get(&idx).copied()
+    }
+
+    /// Sets the keysound index which terminates a long note started by the previous object in the same lane.
+    pub fn set_lnobj(&mut self, idx: usize) {
+        self.lnobj = Some(idx);
+    }
+
+    pub fn lnobj(&self) -> Option<usize> {
+        self.lnobj
+    }
+
+    /// Sets the radix of indices. Base 62 allows up to 3843 definitions of each kind instead of 1295.
+    pub fn set_base(&mut self, base: IndexBase) {
+        self.base = base;
+    }
+
+    pub fn base(&self) -> IndexBase {
+        self.base
+    }
+
+    /// Merges keysounds defined with the same filename and renumbers keysounds from 0 without gaps.
+    /// Objects and `#LNOBJ` referring to undefined keysounds get indices after the defined ones.
+    ///
+    /// Keysounds are only identified by filename, so different files with the same sound stay apart.
+    pub fn merge_keysounds(&mut self) {
+        let mut mapping = HashMap::new();
+        let mut keysounds = BTreeMap::new();
+        let mut indices_by_filename = HashMap::new();
+        for (&idx, filename) in self.keysounds.iter() {
+            let next_idx = keysounds.len();
+            let new_idx = *indices_by_filename
+                .entry(filename.as_str())
+                .or_insert(next_idx);
+            if new_idx == next_idx {
+                keysounds.insert(new_idx, filename.clone());
+            }
+            mapping.insert(idx, new_idx);
+        }
+
+        let undefined: BTreeSet<_> = self
+            .channels
+            .iter_mut()
+            .filter(|(&channel_id, _)| refers_to_keysound(channel_id))
+            .flat_map(|(_, channel)| channel.objects_mut().map(|idx| *idx))
+            .chain(self.lnobj)
+            .filter(|idx| !mapping.contains_key(idx))
+            .collect();
+        for (i, idx) in undefined.into_iter().enumerate() {
+            mapping.insert(idx, keysounds.len() + i);
+        }
+
+        for (_, channel) in self
+            .channels
+            .iter_mut()
+            .filter(|(&channel_id, _)| refers_to_keysound(channel_id))
+        {
+            for idx in channel.objects_mut() {
+                *idx = mapping[idx];
+            }
+        }
+        self.lnobj = self.lnobj.map(|idx| mapping[&idx]);
+        self.keysounds = keysounds;
+    }
+
+    /// Merges keysounds if their indices do not fit in the current base, and switches to a larger
+    /// base up to `max_base` if they still do not fit. Fails if the indices do not fit in `max_base`.
+    pub fn fit_indices(&mut self, max_base: IndexBase) -> Result<(), BmsError> {
+        let max_index = |bms: &Self| {
+            bms.channels
+                .iter()
+                .filter(|(&channel_id, _)| refers_to_keysound(channel_id))
+                .flat_map(|(_, channel)| channel.bars.iter().flatten())
+                .map(|&(_, object)| object)
+                .chain(bms.keysounds.keys().copied())
+                .chain(bms.lnobj)
+                .max()
+                .unwrap_or(0)
+        };
+
+        if max_index(self) > self.base.max_index() {
+            self.merge_keysounds();
+        }
+        let index = max_index(self);
+        if index > self.base.max_index() && index <= max_base.max_index() {
+            self.base = max_base;
+        }
+        if index > self.base.max_index() {
+            return Err(BmsError::IndexOverflow {
+                index,
+                base: self.base,
+            });
+        }
+        Ok(())
+    }
+
+    /// Checks the limits of BMS on the headers and the channels
+    fn validate(&self) -> Result<(), BmsError> {
+        let metadata = &self.metadata;
+        for (command, _) in metadata.extra_headers.iter() {
+            if !is_header_command(command) {
+                return Err(BmsError::InvalidHeader {
+                    header: command.clone(),
+                });
+            }
+        }
+        let texts = [
+            &metadata.title,
+            &metadata.subtitle,
+            &metadata.genre,
+            &metadata.artist,
+            &metadata.subartist,
+            &metadata.stagefile,
+            &metadata.banner,
+            &metadata.backbmp,
+            &metadata.comment,
+        ];
+        if let Some(line) = metadata
+            .comment_lines
+            .iter()
+            .find(|line| line.trim_start().starts_with('#'))
+        {
+            return Err(BmsError::InvalidHeader {
+                header: line.clone(),
+            });
+        }
+        let values = metadata
+            .extra_headers
+            .iter()
+            .map(|(_, value)| value)
+            .chain(metadata.comment_lines.iter());
+        // A line break would start another header
+        if let Some(text) = texts
+            .into_iter()
+            .chain(values)
+            .find(|text| text.contains(['\n', '\r']))
+        {
+            return Err(BmsError::InvalidHeader {
+                header: text.clone(),
+            });
+        }
+
+        for (&measure, &length) in self.measure_lengths.iter() {
+            if measure > 999 {
+                return Err(BmsError::TooManyBars { measures: measure });
+            }
+            if !(length.is_finite() && length > 0.0) {
+                return Err(BmsError::InvalidMeasureLength { measure, length });
+            }
+        }
+
+        for (&channel, content) in self.channels.iter() {
+            if !(1..=99).contains(&channel) || channel == MEASURE_LENGTH_CHANNEL {
+                return Err(BmsError::InvalidChannel { channel });
+            }
+            if content.bars.len() > 1000 {
+                return Err(BmsError::TooManyBars {
+                    measures: content.bars.len() - 1,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the BMS to `w`. Nothing is written if the BMS cannot be represented.
+    pub fn write(&self, w: &mut impl Write) -> Result<(), BmsError> {
+        self.validate()?;
+
+        let mut buf = Vec::new();
+        self.write_to(&mut buf)?;
+        w.write_all(&buf)?;
+        Ok(())
+    }
+
+    fn write_to(&self, w: &mut Vec<u8>) -> Result<(), BmsError> {
+        let metadata = &self.metadata;
+
+        writeln!(w, "#PLAYER {}", metadata.player.to_bms_header())?;
+        w.write_all(&to_shift_jis(&format!("#TITLE {}\n", metadata.title))?)?;
+        write_text_header(w, "SUBTITLE", &metadata.subtitle)?;
+        write_text_header(w, "GENRE", &metadata.genre)?;
+        write_text_header(w, "ARTIST", &metadata.artist)?;
+        write_text_header(w, "SUBARTIST", &metadata.subartist)?;
         writeln!(w, "#TOTAL {:.0}", self.total)?;
         writeln!(w, "#BPM {:.2}", self.bpm)?;
-        writeln!(w, "#PLAYLEVEL 1")?;
-        writeln!(w, "#RANK 3")?;
+        if let Some(playlevel) = metadata.playlevel {
+            writeln!(w, "#PLAYLEVEL {playlevel}")?;
+        }
+        if let Some(difficulty) = metadata.difficulty {
+            writeln!(w, "#DIFFICULTY {difficulty}")?;
+        }
+        match metadata.rank {
+            JudgeRank::Rank(rank) => writeln!(w, "#RANK {rank}")?,
+            JudgeRank::DefExRank(rank) => writeln!(w, "#DEFEXRANK {rank}")?,
+        }
+        if self.base == IndexBase::Base62 {
+            writeln!(w, "#BASE 62")?;
+        }
+        write_text_header(w, "STAGEFILE", &metadata.stagefile)?;
+        write_text_header(w, "BANNER", &metadata.banner)?;
+        write_text_header(w, "BACKBMP", &metadata.backbmp)?;
+        write_text_header(w, "COMMENT", &metadata.comment)?;
+        for (command, value) in metadata.extra_headers.iter() {
+            // Headers such as #ENDIF have no value
+            let header = if value.is_empty() {
+                format!("#{command}\n")
+            } else {
+                format!("#{command} {value}\n")
+            };
+            w.write_all(&to_shift_jis(&header)?)?;
+        }
+
+        if let Some(lnobj) = self.lnobj {
+            writeln!(w, "#LNOBJ {}", write_index(lnobj, self.base)?)?;
+        }
 
-        for (&idx, name) in self.keysounds.iter() {
-            writeln!(w, "#WAV{} {}.wav", to_bms_index(idx), name)?;
+        for (&idx, filename) in self.keysounds.iter() {
+            w.write_all(&to_shift_jis(&format!(
+                "#WAV{} {}\n",
+                write_index(idx, self.base)?,
+                filename
+            ))?)?;
+        }
+
+        for (&idx, bpm) in self.bpm_definitions.iter() {
+            writeln!(w, "#BPM{} {:.2}", write_index(idx, self.base)?, bpm)?;
+        }
+
+        for (&idx, length) in self.stop_definitions.iter() {
+            writeln!(w, "#STOP{} {}", write_index(idx, self.base)?, length)?;
+        }
+
+        for (&measure, length) in self.measure_lengths.iter() {
+            writeln!(w, "#{measure:03}{MEASURE_LENGTH_CHANNEL:02}:{length}")?;
         }
 
         for (&channel_idx, channel) in self.channels.iter() {
-            channel.write(channel_idx, w)?;
+            channel.write(channel_idx, self.base, w)?;
+        }
+
+        for line in metadata.comment_lines.iter() {
+            w.write_all(&to_shift_jis(&format!("{line}\n"))?)?;
         }
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        BmsError, BmsWriter, IndexBase, Position, BGM_CHANNEL, BPM_CHANNEL, KEYBOARD_CHANNELS,
+    };
+
+    #[test]
+    fn test_index_overflow() {
+        let mut bms = BmsWriter::new();
+        bms.set_keysound(1295, "overflow");
+        assert!(bms.write(&mut Vec::new()).is_err());
+        bms.set_base(IndexBase::Base62);
+        assert!(bms.write(&mut Vec::new()).is_ok());
+
+        let mut bms = BmsWriter::new();
+        bms.push_channel(0, BPM_CHANNEL, vec![Some(256)]);
+        assert!(bms.write(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_position_out_of_measure() {
+        assert_eq!(Position::new(2, 4), Position::new(1, 2));
+        assert_eq!(Position::new(4, 4), None);
+        assert_eq!(Position::new(0, 0), None);
+    }
+
+    #[test]
+    fn test_merge_rows() {
+        let mut bms = BmsWriter::new();
+        bms.push_channel(0, BGM_CHANNEL, vec![Some(1), None, None, None]);
+        bms.push_channel(0, BGM_CHANNEL, vec![None, None, Some(2), None]);
+        bms.push_channel(0, BGM_CHANNEL, vec![Some(3), None]);
+        bms.push_object(0, BGM_CHANNEL, Position::new(1, 3).unwrap(), 4);
+        bms.push_channel(0, KEYBOARD_CHANNELS[0], vec![None; 16]);
+        bms.push_object(1, KEYBOARD_CHANNELS[0], Position::new(4, 16).unwrap(), 5);
+
+        assert_eq!(
+            bms.channel(BGM_CHANNEL).collect::<Vec<_>>(),
+            vec![
+                (1, vec![Some(1), None, Some(4), Some(2), None, None]),
+                (1, vec![Some(3)]),
+            ]
+        );
+        assert_eq!(
+            bms.channel(KEYBOARD_CHANNELS[0]).collect::<Vec<_>>(),
+            vec![(2, vec![None, Some(5), None, None])]
+        );
+
+        let mut buf = Vec::new();
+        bms.write(&mut buf).unwrap();
+        let text = String::from_utf8(buf).unwrap();
+        assert!(text.ends_with("#00101:020005030000\n#00101:04\n#00211:00060000\n"));
+    }
+
+    #[test]
+    fn test_write_errors() {
+        let mut bms = BmsWriter::new();
+        bms.push_channel(998, KEYBOARD_CHANNELS[0], vec![Some(0)]);
+        assert!(bms.write(&mut Vec::new()).is_ok());
+        bms.push_channel(999, KEYBOARD_CHANNELS[0], vec![Some(0)]);
+        let mut buf = Vec::new();
+        assert!(matches!(
+            bms.write(&mut buf),
+            Err(BmsError::TooManyBars { measures: 1000 })
+        ));
+        assert!(buf.is_empty());
+
+        let mut bms = BmsWriter::new();
+        bms.push_channel(0, 100, vec![Some(0)]);
+        assert!(matches!(
+            bms.write(&mut Vec::new()),
+            Err(BmsError::InvalidChannel { channel: 100 })
+        ));
+
+        let mut bms = BmsWriter::new();
+        bms.set_measure_length(0, 0.0);
+        assert!(matches!(
+            bms.write(&mut Vec::new()),
+            Err(BmsError::InvalidMeasureLength { measure: 1, .. })
+        ));
+        bms.set_measure_length(0, 0.75);
+        assert!(bms.write(&mut Vec::new()).is_ok());
+
+        let mut bms = BmsWriter::new();
+        bms.set_title("test 🎹");
+        assert!(matches!(
+            bms.write(&mut Vec::new()),
+            Err(BmsError::Unencodable { text }) if text == "#TITLE test 🎹"
+        ));
+        bms.set_title("テスト");
+        assert!(bms.write(&mut Vec::new()).is_ok());
+    }
+
+    #[test]
+    fn test_merge_keysounds() {
+        let mut bms = BmsWriter::new();
+        bms.set_keysound(0, "a");
+        bms.set_keysound(10, "b");
+        bms.set_keysound(2000, "a");
+        bms.set_lnobj(3000);
+        bms.push_channel(0, KEYBOARD_CHANNELS[0], vec![Some(2000), Some(10), Some(5)]);
+        bms.push_channel(0, BPM_CHANNEL, vec![Some(200)]);
+
+        let mut merged = bms.clone();
+        assert!(merged.fit_indices(IndexBase::Base36).is_ok());
+        assert_eq!(merged.base(), IndexBase::Base36);
+        assert_eq!(
+            merged.keysounds().collect::<Vec<_>>(),
+            vec![(0, "a.wav"), (1, "b.wav")]
+        );
+        assert_eq!(
+            merged.channel(KEYBOARD_CHANNELS[0]).collect::<Vec<_>>(),
+            vec![(1, vec![Some(0), Some(1), Some(2)])]
+        );
+        assert_eq!(merged.lnobj(), Some(3));
+        assert_eq!(
+            merged.channel(BPM_CHANNEL).collect::<Vec<_>>(),
+            vec![(1, vec![Some(200)])]
+        );
+
+        for i in 0..2000 {
+            bms.set_keysound(i, &format!("{i}"));
+        }
+        assert!(matches!(
+            bms.clone().fit_indices(IndexBase::Base36),
+            Err(BmsError::IndexOverflow {
+                base: IndexBase::Base36,
+                ..
+            })
+        ));
+        assert!(bms.fit_indices(IndexBase::Base62).is_ok());
+        assert_eq!(bms.base(), IndexBase::Base62);
+        assert!(bms.write(&mut Vec::new()).is_ok());
+    }
+}
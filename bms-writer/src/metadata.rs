@@ -0,0 +1,77 @@
+/// Play style written in `#PLAYER`
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum Player {
+    #[default]
+    Single,
+    Couple,
+    Double,
+    Battle,
+}
+
+impl Player {
+    pub fn from_bms_header(value: u32) -> Option<Self> {
+        match value {
+            1 => Some(Player::Single),
+            2 => Some(Player::Couple),
+            3 => Some(Player::Double),
+            4 => Some(Player::Battle),
+            _ => None,
+        }
+    }
+
+    pub fn to_bms_header(self) -> u32 {
+        match self {
+            Player::Single => 1,
+            Player::Couple => 2,
+            Player::Double => 3,
+            Player::Battle => 4,
+        }
+    }
+}
+
+/// Timing window of judgements
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum JudgeRank {
+    /// `#RANK` (0: VERY HARD, 1: HARD, 2: NORMAL, 3: EASY)
+    Rank(u32),
+    /// `#DEFEXRANK` in percentage, where 100 is as wide as `#RANK 2`
+    DefExRank(u32),
+}
+
+impl Default for JudgeRank {
+    fn default() -> Self {
+        JudgeRank::Rank(3)
+    }
+}
+
+/// Headers describing the chart. Empty texts and `None` are not written.
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct Metadata {
+    pub player: Player,
+    pub title: String,
+    pub subtitle: String,
+    pub genre: String,
+    pub artist: String,
+    pub subartist: String,
+    pub playlevel: Option<u32>,
+    /// Difficulty name shown by players (1: BEGINNER, 2: NORMAL, 3: HYPER, 4: ANOTHER, 5: INSANE)
+    pub difficulty: Option<u32>,
+    pub rank: JudgeRank,
+    /// Image shown while loading
+    pub stagefile: String,
+    pub banner: String,
+    /// Image shown behind the lanes
+    pub backbmp: String,
+    pub comment: String,
+    /// Headers written as is after the others, as `(command, value)` without `#`
+    pub extra_headers: Vec<(String, String)>,
+    /// Lines which players ignore, written at the end of the file (e.g. `*made with ...`)
+    pub comment_lines: Vec<String>,
+}
+
+impl Metadata {
+    pub fn push_header(&mut self, command: &str, value: &str) {
+        self.extra_headers
+            .push((command.to_owned(), value.to_owned()));
+    }
+}
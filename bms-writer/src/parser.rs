@@ -0,0 +1,334 @@
+use encoding_rs::SHIFT_JIS;
+
+use crate::{
+    from_bms_index, is_header_command, BmsWriter, IndexBase, JudgeRank, Player, BPM_CHANNEL,
+    MEASURE_LENGTH_CHANNEL,
+};
+
+fn parse_object(channel_id: u8, object: &[u8], base: IndexBase) -> Option<usize> {
+    let object = std::str::from_utf8(object).ok()?;
+    if channel_id == BPM_CHANNEL {
+        usize::from_str_radix(object, 16)
+            .ok()
+            .filter(|&bpm| bpm != 0)
+    } else {
+        from_bms_index(object, base)
+    }
+}
+
+/// Splits a channel line into the measure, the channel and the objects
+fn parse_address(line: &str) -> Option<(usize, u8, &str)> {
+    let (address, objects) = line.split_once(':')?;
+    if address.len() != 5 || !address.is_ascii() {
+        return None;
+    }
+
+    let measure = address[..3].parse().ok()?;
+    let channel_id = address[3..].parse().ok()?;
+    Some((measure, channel_id, objects.trim()))
+}
+
+fn parse_row(channel_id: u8, objects: &str, base: IndexBase) -> Vec<Option<usize>> {
+    objects
+        .as_bytes()
+        .chunks_exact(2)
+        .map(|object| parse_object(channel_id, object, base))
+        .collect()
+}
+
+impl BmsWriter {
+    /// Parses a Shift_JIS encoded BMS file.
+    ///
+    /// Like BMS players do, lines which cannot be interpreted are ignored. Other headers are kept
+    /// in `Metadata::extra_headers`, so that they are written back.
+    pub fn parse(input: &[u8]) -> Self {
+        let (input, _, _) = SHIFT_JIS.decode(input);
+        let mut bms = BmsWriter::new();
+        // Files without #PLAYLEVEL are told apart from ones with #PLAYLEVEL 1
+        bms.metadata_mut().playlevel = None;
+
+        // Indices anywhere in the file follow #BASE
+        let is_base_62 = input.lines().any(|line| {
+            let mut tokens = line.split_whitespace();
+            tokens
+                .next()
+                .is_some_and(|command| command.eq_ignore_ascii_case("#BASE"))
+                && tokens.next() == Some("62")
+        });
+        if is_base_62 {
+            bms.set_base(IndexBase::Base62);
+        }
+        let base = bms.base();
+
+        for line in input.lines() {
+            let Some(line) = line.trim().strip_prefix('#') else {
+                if !line.trim().is_empty() {
+                    bms.metadata_mut().comment_lines.push(line.to_owned());
+                }
+                continue;
+            };
+
+            if line.as_bytes().first().is_some_and(u8::is_ascii_digit) {
+                match parse_address(line) {
+                    Some((measure, MEASURE_LENGTH_CHANNEL, length)) => {
+                        if let Some(length) = length.parse().ok().filter(|&length| length > 0.0) {
+                            bms.measure_lengths.insert(measure, length);
+                        }
+                    }
+                    Some((measure, channel_id, objects)) => {
+                        bms.push_measure(measure, channel_id, parse_row(channel_id, objects, base));
+                    }
+                    None => {}
+                }
+                continue;
+            }
+
+            let (raw_command, value) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let command = raw_command.to_ascii_uppercase();
+            let value = value.trim();
+            // Indices in base 62 are case-sensitive
+            let index = |prefix: &str| {
+                (command.starts_with(prefix) && raw_command.len() == prefix.len() + 2)
+                    .then(|| from_bms_index(&raw_command[prefix.len()..], base))
+                    .flatten()
+            };
+
+            let metadata = bms.metadata_mut();
+            match command.as_str() {
+                "PLAYER" => {
+                    if let Some(player) = value.parse().ok().and_then(Player::from_bms_header) {
+                        metadata.player = player;
+                    }
+                }
+                "TITLE" => metadata.title = value.to_owned(),
+                "SUBTITLE" => metadata.subtitle = value.to_owned(),
+                "GENRE" => metadata.genre = value.to_owned(),
+                "ARTIST" => metadata.artist = value.to_owned(),
+                "SUBARTIST" => metadata.subartist = value.to_owned(),
+                "RANK" => {
+                    if let Ok(rank) = value.parse() {
+                        metadata.rank = JudgeRank::Rank(rank);
+                    }
+                }
+                "DEFEXRANK" => {
+                    if let Ok(rank) = value.parse() {
+                        metadata.rank = JudgeRank::DefExRank(rank);
+                    }
+                }
+                "STAGEFILE" => metadata.stagefile = value.to_owned(),
+                "BANNER" => metadata.banner = value.to_owned(),
+                "BACKBMP" => metadata.backbmp = value.to_owned(),
+                "COMMENT" => metadata.comment = value.to_owned(),
+                "BPM" => {
+                    if let Ok(bpm) = value.parse() {
+                        bms.set_bpm(bpm);
+                    }
+                }
+                "LNOBJ" => {
+                    if let Some(idx) = from_bms_index(value, base) {
+                        bms.set_lnobj(idx);
+                    }
+                }
+                "TOTAL" => {
+                    if let Ok(total) = value.parse() {
+                        bms.set_total(total);
+                    }
+                }
+                "PLAYLEVEL" => {
+                    if let Ok(playlevel) = value.parse() {
+                        bms.set_playlevel(playlevel);
+                    }
+                }
+                // Read before the other headers
+                "BASE" => {}
+                "DIFFICULTY" => {
+                    if let Ok(difficulty) = value.parse() {
+                        bms.set_difficulty(difficulty);
+                    }
+                }
+                _ => {
+                    if let Some(idx) = index("WAV") {
+                        bms.keysounds.insert(idx, value.to_owned());
+                    } else if let Some(idx) = index("BPM") {
+                        if let Ok(bpm) = value.parse() {
+                            bms.set_bpm_definition(idx, bpm);
+                        }
+                    } else if let Some(idx) = index("STOP") {
+                        if let Ok(length) = value.parse() {
+                            bms.set_stop_definition(idx, length);
+                        }
+                    } else if is_header_command(raw_command) {
+                        metadata.push_header(raw_command, value);
+                    }
+                }
+            }
+        }
+
+        bms
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        BmsWriter, IndexBase, JudgeRank, Metadata, Player, BGM_CHANNEL, BPM_CHANNEL,
+        EXTENDED_BPM_CHANNEL, KEYBOARD_CHANNELS, SCRATCH_CHANNEL, STOP_CHANNEL,
+    };
+
+    fn write_to_vec(bms: &BmsWriter) -> Vec<u8> {
+        let mut buf = Vec::new();
+        bms.write(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut bms = BmsWriter::new();
+        bms.set_title("自動生成");
+        bms.set_genre("密度: 12.34 notes/s");
+        bms.set_artist("seed: 199024");
+        bms.set_bpm(222.22);
+        bms.set_total(412.0);
+        bms.set_playlevel(12);
+        bms.set_difficulty(5);
+        bms.set_keysound(0, "s_s_C3");
+        bms.set_keysound(40, "s_dr_kick");
+        bms.push_channel(0, KEYBOARD_CHANNELS[0], vec![Some(0), None, Some(40), None]);
+        bms.push_channel(0, SCRATCH_CHANNEL, vec![Some(40)]);
+        bms.push_channel(0, BGM_CHANNEL, vec![Some(40), None]);
+        bms.push_channel(0, BGM_CHANNEL, vec![None, Some(0)]);
+        bms.push_channel(2, KEYBOARD_CHANNELS[6], vec![None, Some(0)]);
+        bms.set_bpm_definition(0, 333.33);
+        bms.set_stop_definition(1, 48);
+        bms.set_lnobj(1293);
+        bms.push_channel(1, BPM_CHANNEL, vec![Some(180), None, Some(255), None]);
+        bms.push_channel(1, EXTENDED_BPM_CHANNEL, vec![None, Some(0)]);
+        bms.push_channel(2, STOP_CHANNEL, vec![Some(1)]);
+
+        let written = write_to_vec(&bms);
+        let parsed = BmsWriter::parse(&written);
+
+        assert_eq!(parsed.title(), "自動生成");
+        assert_eq!(parsed.keysound(40), Some("s_dr_kick.wav"));
+        assert_eq!(
+            parsed.channel(BGM_CHANNEL).collect::<Vec<_>>(),
+            vec![(1, vec![Some(40), Some(0)])],
+        );
+        assert_eq!(
+            parsed.channel(BPM_CHANNEL).collect::<Vec<_>>(),
+            vec![(2, vec![Some(180), Some(255)])],
+        );
+        assert_eq!(parsed.bpm_definition(0), Some(333.33));
+        assert_eq!(parsed.stop_definition(1), Some(48));
+        assert_eq!(parsed.lnobj(), Some(1293));
+        assert_eq!(parsed.playlevel(), Some(12));
+        assert_eq!(parsed.difficulty(), Some(5));
+        assert_eq!(write_to_vec(&parsed), written);
+    }
+
+    #[test]
+    fn test_round_trip_metadata() {
+        let metadata = Metadata {
+            player: Player::Double,
+            title: "自動生成".to_owned(),
+            subtitle: "[ANOTHER]".to_owned(),
+            subartist: "obj: generator".to_owned(),
+            playlevel: Some(10),
+            rank: JudgeRank::DefExRank(120),
+            stagefile: "stage.png".to_owned(),
+            banner: "banner.png".to_owned(),
+            backbmp: "back.png".to_owned(),
+            comment: "seed: 1".to_owned(),
+            comment_lines: vec!["*made with a generator".to_owned()],
+            ..Default::default()
+        };
+        let mut bms = BmsWriter::new();
+        bms.set_metadata(metadata.clone());
+
+        let written = write_to_vec(&bms);
+        let text = String::from_utf8_lossy(&written);
+        assert!(text.starts_with("#PLAYER 3\n"));
+        assert!(text.contains("#DEFEXRANK 120\n"));
+        assert!(!text.contains("#RANK"));
+        assert!(!text.contains("#GENRE"));
+        assert_eq!(BmsWriter::parse(&written).metadata(), &metadata);
+
+        assert!(text.ends_with("#COMMENT seed: 1\n*made with a generator\n"));
+
+        bms.metadata_mut().push_header("LNTYPE", "1");
+        assert!(
+            String::from_utf8_lossy(&write_to_vec(&bms)).contains("#COMMENT seed: 1\n#LNTYPE 1\n")
+        );
+        bms.metadata_mut().push_header("00111", "01");
+        assert!(bms.write(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_round_trip_base_62() {
+        let mut bms = BmsWriter::new();
+        bms.set_base(IndexBase::Base62);
+        bms.set_keysound(34, "upper");
+        bms.set_keysound(35, "lower");
+        bms.set_keysound(3842, "last");
+        bms.set_lnobj(3841);
+        bms.push_channel(
+            0,
+            KEYBOARD_CHANNELS[0],
+            vec![Some(34), Some(35), Some(3842)],
+        );
+
+        let written = write_to_vec(&bms);
+        let text = String::from_utf8_lossy(&written);
+        assert!(text.contains("#BASE 62\n"));
+        assert!(text.contains("#WAV0a lower.wav\n"));
+        assert!(text.contains("#00111:0Z0azz\n"));
+
+        let parsed = BmsWriter::parse(&written);
+        assert_eq!(parsed.base(), IndexBase::Base62);
+        assert_eq!(parsed.keysound(34), Some("upper.wav"));
+        assert_eq!(parsed.keysound(35), Some("lower.wav"));
+        assert_eq!(parsed.lnobj(), Some(3841));
+        assert_eq!(write_to_vec(&parsed), written);
+    }
+
+    #[test]
+    fn test_parse_lenient() {
+        let input = b"*---- comment\r\n#bpm 180\r\n#WAV0z kick.ogg\r\n#WAVE x.wav\r\n#00011:01000Z\r\n#00116:0\r\n#00102:0.75\r\n#00202:x\r\n#LNTYPE 1\r\n#BMP01 bg.bmp\r\n#ENDIF\r\n";
+        let parsed = BmsWriter::parse(input);
+
+        assert_eq!(parsed.bpm(), 180.0);
+        assert_eq!(parsed.playlevel(), None);
+        assert_eq!(
+            parsed.keysounds().collect::<Vec<_>>(),
+            vec![(34, "kick.ogg")]
+        );
+        assert_eq!(
+            parsed.channel(11).collect::<Vec<_>>(),
+            vec![(0, vec![Some(0), None, Some(34)])],
+        );
+        assert_eq!(parsed.channel(16).count(), 0);
+        assert_eq!(parsed.channel_ids().collect::<Vec<_>>(), vec![11, 16]);
+        assert_eq!(
+            parsed.measure_lengths().collect::<Vec<_>>(),
+            vec![(1, 0.75)]
+        );
+        assert_eq!(
+            parsed.metadata().extra_headers,
+            [
+                ("WAVE", "x.wav"),
+                ("LNTYPE", "1"),
+                ("BMP01", "bg.bmp"),
+                ("ENDIF", "")
+            ]
+            .map(|(command, value)| (command.to_owned(), value.to_owned()))
+        );
+
+        let text = String::from_utf8(write_to_vec(&parsed)).unwrap();
+        assert!(text.contains("#LNTYPE 1\n#BMP01 bg.bmp\n#ENDIF\n"));
+        assert!(text.contains("#00102:0.75\n"));
+        assert_eq!(
+            write_to_vec(&BmsWriter::parse(text.as_bytes())),
+            text.as_bytes()
+        );
+    }
+}
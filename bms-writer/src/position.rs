@@ -0,0 +1,43 @@
+fn gcd(a: usize, b: usize) -> usize {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+/// Least common multiple, e.g. of resolutions of rows
+pub fn lcm(a: usize, b: usize) -> usize {
+    a / gcd(a, b) * b
+}
+
+/// Position of an object in a measure, as the fraction `numerator / denominator` of the measure
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct Position {
+    numerator: usize,
+    denominator: usize,
+}
+
+impl Position {
+    /// The fraction is reduced, so that equal positions compare equal.
+    ///
+    /// Returns `None` unless `numerator < denominator`, as the position is out of the measure.
+    pub fn new(numerator: usize, denominator: usize) -> Option<Self> {
+        if numerator >= denominator {
+            return None;
+        }
+        let divisor = gcd(numerator, denominator);
+        Some(Position {
+            numerator: numerator / divisor,
+            denominator: denominator / divisor,
+        })
+    }
+
+    pub fn numerator(self) -> usize {
+        self.numerator
+    }
+
+    pub fn denominator(self) -> usize {
+        self.denominator
+    }
+}
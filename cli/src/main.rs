@@ -1,20 +1,314 @@
-use clap::{error::ErrorKind, CommandFactory, Parser};
+use bms_writer::BmsWriter;
+use clap::{
+    builder::PossibleValuesParser, error::ErrorKind, parser::ValueSource, Arg, ArgMatches,
+    CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum,
+};
 use generator::{
-    bms::{chart_dp_to_bms, chart_to_bms},
-    chord::ChordDensity,
-    generate::{generate_chart, generate_chart_dp, ChartParams, NotesParams, Scatter},
-    keysound::{ChordKeySound, ChordRoot, ChordType},
+    analysis::{analyze_chart, analyze_chart_dp, ChartReport},
+    bms::{
+        chart_from_bms, chart_to_bms, write_generated_chart, ChartFormat, IndexBase, JudgeRank,
+        LongNoteMode, Metadata,
+    },
+    bmson::comment_lines_from_bmson,
+    chord::{chords_per_bar, ChordDensity},
+    config::{parse_header, GeneratorConfig, PRESET_NAMES},
+    constraint::PatternConstraint,
+    course::{chart_hashes, Course, CourseChart},
+    difficulty::{
+        estimate_chart, generate_chart_dp_with_difficulty, generate_chart_with_difficulty, Level,
+    },
+    ergonomics::{Ergonomics, Fingering},
+    generate::{
+        generate_chart, generate_chart_dp_with_schedule, generate_chart_with_model,
+        generate_chart_with_schedule, ChartParams, DpParams, DpPhrasing, LongNoteParams,
+        NotesParams, NotesSchedule, Scatter, ScratchParams,
+    },
+    key_mode::KeyMode,
+    keysound::{parse_chord, ChordKeySound},
+    markov::{chords_from_bms, MarkovModel},
+    rng::RNG,
+    table::{DifficultyTable, TableChart},
+    template::PatternFamily,
+    tempo::TempoMap,
+    transform::{flip, transform_chart, transform_chart_dp, LaneOption},
 };
+use rayon::prelude::*;
 use std::{
+    collections::HashSet,
     fs::File,
+    ops::Range,
+    path::{Path, PathBuf},
+    str::FromStr,
     time::{SystemTime, UNIX_EPOCH},
 };
 
+#[derive(Clone, Copy, ValueEnum)]
+enum Keys {
+    /// 5 keys and scratch (10 keys with --dp)
+    #[value(name = "5")]
+    Five,
+    /// 7 keys and scratch (14 keys with --dp)
+    #[value(name = "7")]
+    Seven,
+    /// 9 keys (PMS)
+    #[value(name = "9")]
+    Nine,
+}
+
+impl Keys {
+    fn to_key_mode(self) -> KeyMode {
+        match self {
+            Keys::Five => KeyMode::Beat5,
+            Keys::Seven => KeyMode::Beat7,
+            Keys::Nine => KeyMode::PopN9,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Pattern {
+    /// Stairs (e.g. 1-2-3-4)
+    Stairs,
+    /// Trills (e.g. 1-2-1-2)
+    Trill,
+    /// Scratch with keys alternating with keys alone
+    SplitChord,
+    /// Repeated two-note chords
+    ChordJack,
+    /// Single notes alternating between the hands
+    Alternating,
+}
+
+impl Pattern {
+    fn to_pattern_family(self) -> PatternFamily {
+        match self {
+            Pattern::Stairs => PatternFamily::Stairs,
+            Pattern::Trill => PatternFamily::Trill,
+            Pattern::SplitChord => PatternFamily::SplitChord,
+            Pattern::ChordJack => PatternFamily::ChordJack,
+            Pattern::Alternating => PatternFamily::AlternatingHands,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum FingeringStyle {
+    /// The left hand plays keys 1 to 4
+    #[value(name = "1048")]
+    Style1048,
+    /// The left hand plays keys 1 to 3, and the right thumb plays key 4
+    #[value(name = "2048")]
+    Style2048,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Phrasing {
+    /// Each side is generated on its own
+    Independent,
+    /// The right side plays the left side mirrored
+    Mirrored,
+    /// The sides take turns every --call-and-response-length chords
+    CallAndResponse,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum RandomOption {
+    /// Lanes are reversed
+    Mirror,
+    /// Lanes are shuffled
+    Random,
+    /// Lanes are rotated, and mirrored in half chance
+    RRandom,
+    /// Notes are placed on random lanes, avoiding jacks
+    SRandom,
+    /// Notes are placed on random lanes, avoiding lanes of the previous beat
+    HRandom,
+    /// A key of every chord is moved to the scratch
+    AllScratch,
+}
+
+impl RandomOption {
+    fn to_lane_option(self) -> LaneOption {
+        match self {
+            RandomOption::Mirror => LaneOption::Mirror,
+            RandomOption::Random => LaneOption::Random,
+            RandomOption::RRandom => LaneOption::RRandom,
+            RandomOption::SRandom => LaneOption::SRandom,
+            RandomOption::HRandom => LaneOption::HRandom,
+            RandomOption::AllScratch => LaneOption::AllScratch,
+        }
+    }
+}
+
 #[derive(Parser)]
-#[command(version, about)]
+#[command(
+    version,
+    about,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    args: Args,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Learn chord transitions from BMS files for --model
+    Train(TrainArgs),
+    /// Write a difficulty table (header.json and data.json) of BMS files
+    Table(TableArgs),
+    /// Generate charts of every combination of the given parameters in parallel
+    Batch(BatchArgs),
+    /// Generate a chart again with the settings kept in a BMS file generated by this program
+    Regenerate(RegenerateArgs),
+    /// Apply a lane option to the 1P side of a BMS file
+    Transform(TransformArgs),
+}
+
+#[derive(clap::Args)]
+struct TrainArgs {
+    /// Directory searched recursively for .bms, .bme, .bml and .pms files
+    directory: PathBuf,
+
+    /// Filename of the output model (JSON)
+    #[arg(long, short)]
+    output: PathBuf,
+
+    /// Number of previous chords the next chord depends on
+    #[arg(long, default_value_t = 2)]
+    order: usize,
+
+    /// Number of keys of the 1P side to read
+    #[arg(long, value_enum, default_value_t = Keys::Seven)]
+    keys: Keys,
+
+    /// Resolution of the grid chords are placed on, per measure
+    #[arg(long, default_value_t = 16)]
+    chords_per_bar: usize,
+}
+
+#[derive(clap::Args)]
+struct TableArgs {
+    /// Directory searched recursively for .bms, .bme, .bml and .pms files
+    directory: PathBuf,
+
+    /// Directory of the output header.json and data.json (defaults to <DIRECTORY>).
+    /// Insane charts are written to a separate table in its insane subdirectory
+    #[arg(long, short)]
+    output: Option<PathBuf>,
+
+    /// Name of the table
+    #[arg(long, default_value_t = format!("bms-generator"))]
+    name: String,
+
+    /// Symbol shown before levels
+    #[arg(long, default_value_t = format!("☆"))]
+    symbol: String,
+
+    /// Symbol shown before levels of the table of insane charts
+    #[arg(long, default_value_t = format!("★"))]
+    insane_symbol: String,
+
+    /// Level of all the charts, instead of #PLAYLEVEL and #DIFFICULTY of each chart
+    #[arg(long)]
+    level: Option<Level>,
+}
+
+#[derive(clap::Args)]
+struct BatchArgs {
+    /// Directory of the output charts and manifest.json
+    directory: PathBuf,
+
+    #[arg(long, default_value_t = 150.0)]
+    bpm: f32,
+
+    #[arg(long, default_value_t = 16)]
+    bars: usize,
+
+    /// Density of the charts as in the main command. Can be specified multiple times
+    #[arg(long, default_values_t = [format!("0,0,100,100,100")])]
+    density: Vec<String>,
+
+    /// Comma-separated subdivisions of a bar which --density refers to
+    #[arg(long, default_value_t = format!("1,2,4,8,16"))]
+    subdivisions: String,
+
+    /// Comma-separated tolerances for jacks
+    #[arg(long, value_delimiter = ',', default_values_t = [0.0])]
+    jack_tolerance: Vec<f32>,
+
+    /// Comma-separated strengths of scattering
+    #[arg(long, value_delimiter = ',', default_values_t = [0.0], allow_hyphen_values = true)]
+    scatter: Vec<f32>,
+
+    /// Decay rate of memory used for scattering
+    #[arg(long, default_value_t = 0.5)]
+    scatter_decay_rate: f32,
+
+    /// First seed (defaults to the current time)
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// Number of seeds, counted up from --seed, for each combination of the parameters
+    #[arg(long, default_value_t = 1)]
+    seeds: u64,
+
+    /// Number of keys
+    #[arg(long, value_enum, default_value_t = Keys::Seven)]
+    keys: Keys,
+
+    /// Prefix of the titles and filenames of the charts
+    #[arg(long, default_value_t = format!("batch"))]
+    title: String,
+}
+
+#[derive(clap::Args)]
+struct RegenerateArgs {
+    /// BMS file generated by this program
+    input: PathBuf,
+
+    /// Filename of the regenerated BMS
+    output: PathBuf,
+
+    /// Options which replace the ones kept in the input, given after `--` (e.g. -- --scatter=2)
+    #[arg(last = true)]
+    overrides: Vec<String>,
+}
+
+#[derive(clap::Args)]
+struct TransformArgs {
+    /// BMS file to transform
+    input: PathBuf,
+
+    /// Filename of the transformed BMS
+    output: PathBuf,
+
+    /// Lane option applied to the chart
+    #[arg(long, value_enum)]
+    lane_option: RandomOption,
+
+    /// Seed of the lane option
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// Number of keys of the input
+    #[arg(long, value_enum, default_value_t = Keys::Seven)]
+    keys: Keys,
+
+    /// Resolution of the grid in a bar, which notes are moved to
+    #[arg(long, default_value_t = 192)]
+    chords_per_bar: usize,
+}
+
+#[derive(clap::Args)]
 struct Args {
-    /// Filename of output BMS
-    filename: String,
+    /// Filename of output BMS (.bms, .bme, .pms and .bmson extensions are replaced with the one suited for --keys or --bmson)
+    #[arg(required = true)]
+    filename: Option<PathBuf>,
 
     #[arg(long, default_value_t = 150.0)]
     bpm: f32,
@@ -22,10 +316,22 @@ struct Args {
     #[arg(long, default_value_t = 16)]
     bars: usize,
 
-    /// Comma-separated density of the chart (percentage of 1/1, 1/2, 1/4, 1/8, 1/16 notes, respectively)
+    /// BPM at the end of the chart. If specified, BPM changes gradually from --bpm to this value
+    #[arg(long)]
+    end_bpm: Option<f32>,
+
+    /// Interval in bars between BPM changes made by --end-bpm
+    #[arg(long, default_value_t = 4)]
+    bpm_change_interval: usize,
+
+    /// Comma-separated density of the chart (percentage of each subdivision in --subdivisions, respectively)
     #[arg(long, default_value_t = format!("0,0,100,100,100"))]
     density: String,
 
+    /// Comma-separated subdivisions of a bar which --density refers to (e.g. 4,12 for quarter notes and triplet 8th notes)
+    #[arg(long, default_value_t = format!("1,2,4,8,16"))]
+    subdivisions: String,
+
     /// Tolerance for jacks
     /// (0 allows no jacks, 1 allows up to two consecutive notes, 0.4 allows them in 40% chance, and re-rolls otherwise.)
     #[arg(long, default_value_t = 0.0)]
@@ -41,6 +347,124 @@ struct Args {
     #[arg(long, default_value_t = 0.5)]
     scatter_decay_rate: f32,
 
+    /// Section of the chart with its own parameters, as BARS:DENSITY[:JACK_TOLERANCE[:SCATTER]]
+    /// (e.g. 8:0,0,100,100,0 then 16:0,0,100,100,100:1). Can be specified multiple times;
+    /// sections follow one after another, and the last one lasts until the end of the chart.
+    /// Omitted values are taken from --jack-tolerance and --scatter
+    #[arg(long, conflicts_with_all = ["density", "min_level", "max_level"])]
+    section: Vec<String>,
+
+    /// Change parameters gradually from the start of each --section to the start of the next one
+    #[arg(long, requires = "section")]
+    interpolate: bool,
+
+    /// Maximum length of stairs (e.g. 1-2-3-4) in notes
+    #[arg(long)]
+    max_stairs: Option<usize>,
+
+    /// Maximum length of trills (e.g. 1-2-1-2) in notes
+    #[arg(long)]
+    max_trill: Option<usize>,
+
+    /// Maximum length of denim (alternating chords, e.g. 135-246-135-246) in chords
+    #[arg(long)]
+    max_denim: Option<usize>,
+
+    /// Chord shape to forbid, as lane numbers (e.g. 1357). Can be specified multiple times
+    #[arg(long)]
+    forbid_chord: Vec<String>,
+
+    /// Lanes forbidden together with a scratch, as lane numbers (e.g. 67)
+    #[arg(long)]
+    forbid_scratch_combo: Option<String>,
+
+    /// Chance to re-roll a chord breaking --max-stairs, --max-trill, --max-denim, --forbid-chord or
+    /// --forbid-scratch-combo (1.0 forbids them, and smaller values only make them rarer)
+    #[arg(long, default_value_t = 1.0)]
+    constraint_reroll_chance: f32,
+
+    /// Comma-separated chance of a scratch in percent for each subdivision in --subdivisions.
+    /// Without this, SP charts get a scratch every 8 bars and DP charts get none
+    #[arg(long)]
+    scratch_density: Option<String>,
+
+    /// Maximum number of keys together with a scratch
+    #[arg(long, requires = "scratch_density")]
+    scratch_max_keys: Option<usize>,
+
+    /// Chance for each scratch to become a backspin (scratch long note, 0.0 to 1.0)
+    #[arg(long, default_value_t = 0.0, requires = "scratch_density")]
+    backspin_ratio: f32,
+
+    /// Minimum length of backspins in chords of the grid
+    #[arg(long, default_value_t = 4)]
+    backspin_min_length: usize,
+
+    /// Maximum length of backspins in chords of the grid
+    #[arg(long, default_value_t = 16)]
+    backspin_max_length: usize,
+
+    /// Scratch rush section as START:BARS:DENSITY, where START is the 1-based first bar and DENSITY
+    /// replaces --scratch-density. Can be specified multiple times
+    #[arg(long, requires = "scratch_density")]
+    scratch_rush: Vec<String>,
+
+    /// Fingering for 7 keys, which makes lanes awkward for it less likely to be chosen. SP only,
+    /// as each side of DP is played by one hand
+    #[arg(long, value_enum, conflicts_with = "dp")]
+    fingering: Option<FingeringStyle>,
+
+    /// Factor of the chance of a lane whose finger has just played another lane, with --fingering
+    #[arg(long, default_value_t = 0.2, requires = "fingering")]
+    same_finger_penalty: f32,
+
+    /// Number of notes of a hand in a chord (counting the scratch) above which --overload-penalty applies,
+    /// with --fingering
+    #[arg(long, default_value_t = 3, requires = "fingering")]
+    hand_capacity: usize,
+
+    /// Factor of the chance of a lane whose hand is already full, with --fingering
+    #[arg(long, default_value_t = 0.1, requires = "fingering")]
+    overload_penalty: f32,
+
+    /// Sample chords from a model made by the train subcommand instead of the density and other parameters
+    #[arg(long, conflicts_with_all = [
+        "dp", "section", "min_level", "max_level", "pattern", "density", "jack_tolerance",
+        "scatter", "scatter_decay_rate", "ln_ratio", "ln_min_length", "ln_max_length",
+        "scratch_density", "backspin_min_length", "backspin_max_length", "max_stairs",
+        "max_trill", "max_denim", "forbid_chord", "forbid_scratch_combo",
+        "constraint_reroll_chance", "fingering",
+    ])]
+    model: Option<PathBuf>,
+
+    /// Build the chart out of templates of these pattern families, comma separated.
+    /// Density only decides the rhythm, and jack tolerance, scatter, long notes and constraints are ignored
+    #[arg(long, value_enum, value_delimiter = ',', conflicts_with_all = [
+        "dp_phrasing", "call_and_response_length", "split_density", "max_hand_keys",
+        "big_chord_size",
+    ])]
+    pattern: Vec<Pattern>,
+
+    /// Chance for each note to become a long note (0.0 to 1.0)
+    #[arg(long, default_value_t = 0.0)]
+    ln_ratio: f32,
+
+    /// Minimum length of long notes in chords of the grid (16th notes by default)
+    #[arg(long, default_value_t = 2)]
+    ln_min_length: usize,
+
+    /// Maximum length of long notes in chords of the grid (16th notes by default)
+    #[arg(long, default_value_t = 8)]
+    ln_max_length: usize,
+
+    /// Write long notes using #LNOBJ instead of the long note channels
+    #[arg(long)]
+    lnobj: bool,
+
+    /// Write the chart in bmson format instead of BMS
+    #[arg(long, conflicts_with = "lnobj")]
+    bmson: bool,
+
     #[arg(long)]
     seed: Option<u64>,
 
@@ -48,9 +472,191 @@ struct Args {
     #[arg(long)]
     dp: bool,
 
+    /// How the phrases of the two sides relate to each other
+    #[arg(long, value_enum, default_value_t = Phrasing::Independent, requires = "dp")]
+    dp_phrasing: Phrasing,
+
+    /// Length of a turn of --dp-phrasing call-and-response in chords of the grid
+    #[arg(long, default_value_t = 16)]
+    call_and_response_length: usize,
+
+    /// Split --density between the two sides, so that it decides the total number of keys of both sides
+    #[arg(long, requires = "dp")]
+    split_density: bool,
+
+    /// Maximum number of keys of a side (played by one hand) at once
+    #[arg(long, requires = "dp")]
+    max_hand_keys: Option<usize>,
+
+    /// Forbid both sides from having chords of this many keys or more at the same time
+    #[arg(long, requires = "dp")]
+    big_chord_size: Option<usize>,
+
+    /// Lane option applied to the generated chart (the 1P side with --dp)
+    #[arg(long, value_enum)]
+    lane_option: Option<RandomOption>,
+
+    /// Lane option applied to the 2P side of the generated chart
+    #[arg(long, value_enum, requires = "dp")]
+    lane_option_2p: Option<RandomOption>,
+
+    /// Swap the 1P side and the 2P side of the generated chart, after the lane options
+    #[arg(long, requires = "dp")]
+    flip: bool,
+
+    /// Seed of the lane options, so that one chart gives different variants (defaults to --seed)
+    #[arg(long)]
+    lane_option_seed: Option<u64>,
+
+    /// Generate a course of this many charts with increasing density, and a beatoraja course file
+    #[arg(long, conflicts_with_all = ["model", "min_level", "max_level"])]
+    course: Option<usize>,
+
+    /// Factor of the density of the last chart of --course, changing linearly from 1 of the first chart
+    #[arg(long, default_value_t = 1.5, requires = "course")]
+    course_last_scale: f32,
+
+    /// Number of keys of a side
+    #[arg(long, value_enum, default_value_t = Keys::Seven)]
+    keys: Keys,
+
     /// Title of the BMS file
     #[arg(long, default_value_t = format!("test"))]
     title: String,
+
+    /// #SUBTITLE of the BMS file (e.g. [ANOTHER])
+    #[arg(long)]
+    subtitle: Option<String>,
+
+    /// #GENRE of the BMS file
+    #[arg(long)]
+    genre: Option<String>,
+
+    /// #ARTIST of the BMS file
+    #[arg(long)]
+    artist: Option<String>,
+
+    /// #SUBARTIST of the BMS file
+    #[arg(long)]
+    subartist: Option<String>,
+
+    /// #PLAYLEVEL of the BMS file. Estimated from the chart if omitted
+    #[arg(long)]
+    playlevel: Option<u32>,
+
+    /// #DIFFICULTY of the BMS file (1: BEGINNER, 2: NORMAL, 3: HYPER, 4: ANOTHER, 5: INSANE). Estimated from the chart if omitted
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..=5))]
+    difficulty: Option<u32>,
+
+    /// #RANK of the BMS file (0: VERY HARD, 1: HARD, 2: NORMAL, 3: EASY)
+    #[arg(long, default_value_t = 3, value_parser = clap::value_parser!(u32).range(0..=3))]
+    rank: u32,
+
+    /// #DEFEXRANK of the BMS file, the timing window in percentage of #RANK 2. Written instead of #RANK
+    #[arg(long)]
+    defexrank: Option<u32>,
+
+    /// Image shown while loading the BMS file (#STAGEFILE)
+    #[arg(long)]
+    stagefile: Option<String>,
+
+    /// #BANNER of the BMS file
+    #[arg(long)]
+    banner: Option<String>,
+
+    /// Image shown behind the lanes (#BACKBMP)
+    #[arg(long)]
+    backbmp: Option<String>,
+
+    /// #COMMENT of the BMS file. Defaults to the density and the parameters of the generation
+    #[arg(long)]
+    comment: Option<String>,
+
+    /// Additional header as COMMAND=VALUE (e.g. LNTYPE=1). Can be specified multiple times
+    #[arg(long)]
+    header: Vec<String>,
+
+    /// Comma-separated chords of the key sounds, one per bar (e.g. D,A,Bm,F#m)
+    #[arg(long, default_value_t = format!("D,A,Bm,F#m,G,D,G,A"))]
+    chord_progression: String,
+
+    /// Read settings from a TOML file. Options given on the command line take precedence
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Use the settings of a built-in preset. Options given on the command line take precedence
+    #[arg(long, conflicts_with = "config", value_parser = PossibleValuesParser::new(PRESET_NAMES))]
+    preset: Option<String>,
+
+    /// Write the settings of this run to a TOML file, which can be read by --config
+    #[arg(long)]
+    save_config: Option<PathBuf>,
+
+    /// Minimum level of the chart (e.g. 10 or ☆10 for the normal scale, ★5 or *5 for the insane scale).
+    /// With --min-level or --max-level, seeds and the density are searched until the estimated level fits
+    #[arg(long)]
+    min_level: Option<Level>,
+
+    /// Maximum level of the chart, in the same format as --min-level
+    #[arg(long)]
+    max_level: Option<Level>,
+
+    /// Print statistics of the generated chart
+    #[arg(long)]
+    report: bool,
+
+    /// Write statistics of the generated chart to the file as JSON. With --course, each stage is
+    /// written to its own file numbered like the charts (e.g. report_1.json)
+    #[arg(long)]
+    report_json: Option<PathBuf>,
+}
+
+fn output_report(report: &ChartReport, print: bool, json_filename: Option<&Path>) {
+    if print {
+        print!("{report}");
+    }
+    if let Some(json_filename) = json_filename {
+        let file = File::create(json_filename).expect("Failed to open file");
+        if serde_json::to_writer_pretty(file, report).is_err() {
+            eprintln!("統計情報の書き出しに失敗しました。");
+        }
+    }
+}
+
+/// Headers of the BMS file given by the options, except the title and the comment
+fn metadata_from_args(args: &Args) -> Metadata {
+    let Some(extra_headers) = args
+        .header
+        .iter()
+        .map(|header| parse_header(header))
+        .collect::<Option<Vec<_>>>()
+    else {
+        let mut cmd = Cli::command();
+        cmd.error(
+            ErrorKind::ValueValidation,
+            "--header must be COMMAND=VALUE (e.g. LNTYPE=1).",
+        )
+        .exit();
+    };
+
+    Metadata {
+        subtitle: args.subtitle.clone().unwrap_or_default(),
+        genre: args.genre.clone().unwrap_or_default(),
+        artist: args.artist.clone().unwrap_or_default(),
+        subartist: args.subartist.clone().unwrap_or_default(),
+        playlevel: args.playlevel,
+        difficulty: args.difficulty,
+        rank: match args.defexrank {
+            Some(rank) => JudgeRank::DefExRank(rank),
+            None => JudgeRank::Rank(args.rank),
+        },
+        stagefile: args.stagefile.clone().unwrap_or_default(),
+        banner: args.banner.clone().unwrap_or_default(),
+        backbmp: args.backbmp.clone().unwrap_or_default(),
+        comment: args.comment.clone().unwrap_or_default(),
+        extra_headers,
+        ..Default::default()
+    }
 }
 
 fn seed_from_time() -> u64 {
@@ -60,42 +666,437 @@ fn seed_from_time() -> u64 {
         .as_millis() as u64
 }
 
-fn parse_density(input: &str) -> Option<ChordDensity> {
+fn parse_subdivisions(input: &str) -> Option<Vec<usize>> {
+    let values: Vec<usize> = input
+        .split(',')
+        .map(|x| x.parse().ok())
+        .collect::<Option<_>>()?;
+    if values.is_empty() || values.contains(&0) {
+        return None;
+    }
+    Some(values)
+}
+
+fn parse_density(input: &str, subdivisions: &[usize]) -> Option<ChordDensity> {
     let values: Vec<u64> = input
         .split(',')
         .map(|x| x.parse().ok())
         .collect::<Option<_>>()?;
-    if values.len() != 5 {
+    if values.len() != subdivisions.len() {
         return None;
     }
-    Some(ChordDensity::from_power_of_two(&values))
+    let subdivisions: Vec<_> = subdivisions.iter().copied().zip(values).collect();
+    Some(ChordDensity::from_subdivisions(
+        &subdivisions,
+        chords_per_bar(&subdivisions.iter().map(|&(x, _)| x).collect::<Vec<_>>()),
+    ))
 }
 
-static CHORD_PROGRESSION: [(ChordRoot, ChordType); 8] = [
-    (ChordRoot::D, ChordType::Major),
-    (ChordRoot::A, ChordType::Major),
-    (ChordRoot::B, ChordType::Minor),
-    (ChordRoot::Fs, ChordType::Minor),
-    (ChordRoot::G, ChordType::Major),
-    (ChordRoot::D, ChordType::Major),
-    (ChordRoot::G, ChordType::Major),
-    (ChordRoot::A, ChordType::Major),
-];
+/// Parses a scratch rush of START:BARS:DENSITY into 0-based bars and density
+fn parse_scratch_rush(input: &str, subdivisions: &[usize]) -> Option<(Range<usize>, ChordDensity)> {
+    let mut values = input.splitn(3, ':');
+    let start: usize = values.next()?.parse().ok().filter(|&start| start > 0)?;
+    let bars: usize = values.next()?.parse().ok()?;
+    let density = parse_density(values.next()?, subdivisions)?;
 
-fn main() {
-    let args = Args::parse();
+    Some((start - 1..start - 1 + bars, density))
+}
+
+/// Parses lane numbers such as 1357 into lane indices
+fn parse_lanes(input: &str, lanes: usize) -> Option<Vec<u8>> {
+    let mut values: Vec<u8> = input
+        .chars()
+        .map(|c| c.to_digit(10).filter(|&d| (1..=lanes as u32).contains(&d)))
+        .map(|d| d.map(|d| d as u8 - 1))
+        .collect::<Option<_>>()?;
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_unstable();
+    values.dedup();
+    Some(values)
+}
+
+/// Parses BARS:DENSITY[:JACK_TOLERANCE[:SCATTER]] of --section, with defaults for the omitted values.
+fn parse_section(
+    input: &str,
+    subdivisions: &[usize],
+    jack_tolerance: f32,
+    scatter: f32,
+) -> Option<(usize, ChordDensity, f32, f32)> {
+    let mut values = input.split(':');
+    let bars = values.next()?.parse().ok().filter(|&bars| bars > 0)?;
+    let chord_density = parse_density(values.next()?, subdivisions)?;
+    let jack_tolerance = match values.next() {
+        Some(value) => value.parse().ok()?,
+        None => jack_tolerance,
+    };
+    let scatter = match values.next() {
+        Some(value) => value.parse().ok()?,
+        None => scatter,
+    };
+    if values.next().is_some() {
+        return None;
+    }
 
-    let Some(chord_density) = parse_density(&args.density) else {
-        let mut cmd = Args::command();
+    Some((bars, chord_density, jack_tolerance, scatter))
+}
+
+/// Number of charts tried to find one within --min-level and --max-level
+const DIFFICULTY_SEARCH_ATTEMPTS: usize = 100;
+
+/// Takes settings of `config` for the arguments not given on the command line
+fn apply_config(args: &mut Args, config: &GeneratorConfig, matches: &ArgMatches) {
+    let from_config = |id| matches.value_source(id) != Some(ValueSource::CommandLine);
+
+    if from_config("title") {
+        args.title = config.title.clone();
+    }
+    if from_config("bpm") {
+        args.bpm = config.bpm;
+    }
+    if from_config("bars") {
+        args.bars = config.bars;
+    }
+    if from_config("seed") {
+        args.seed = config.seed;
+    }
+    if from_config("keys") {
+        args.keys = match config.keys {
+            5 => Keys::Five,
+            9 => Keys::Nine,
+            _ => Keys::Seven,
+        };
+    }
+    if from_config("dp") {
+        args.dp = config.dp;
+    }
+    if from_config("subdivisions") {
+        args.subdivisions = config
+            .subdivisions
+            .iter()
+            .map(usize::to_string)
+            .collect::<Vec<_>>()
+            .join(",");
+    }
+    if from_config("density") {
+        args.density = config
+            .density
+            .iter()
+            .map(u64::to_string)
+            .collect::<Vec<_>>()
+            .join(",");
+    }
+    if from_config("jack_tolerance") {
+        args.jack_tolerance = config.jack_tolerance;
+    }
+    if from_config("scatter") {
+        args.scatter = config.scatter;
+    }
+    if from_config("scatter_decay_rate") {
+        args.scatter_decay_rate = config.scatter_decay_rate;
+    }
+    if from_config("ln_ratio") {
+        args.ln_ratio = config.ln_ratio;
+    }
+    if from_config("ln_min_length") {
+        args.ln_min_length = config.ln_min_length;
+    }
+    if from_config("ln_max_length") {
+        args.ln_max_length = config.ln_max_length;
+    }
+    if from_config("chord_progression") {
+        args.chord_progression = config.chord_progression.join(",");
+    }
+
+    let texts = [
+        ("subtitle", &mut args.subtitle, &config.subtitle),
+        ("genre", &mut args.genre, &config.genre),
+        ("artist", &mut args.artist, &config.artist),
+        ("subartist", &mut args.subartist, &config.subartist),
+        ("stagefile", &mut args.stagefile, &config.stagefile),
+        ("banner", &mut args.banner, &config.banner),
+        ("backbmp", &mut args.backbmp, &config.backbmp),
+        ("comment", &mut args.comment, &config.comment),
+    ];
+    for (id, arg, value) in texts {
+        if from_config(id) {
+            *arg = (!value.is_empty()).then(|| value.clone());
+        }
+    }
+    if from_config("playlevel") {
+        args.playlevel = config.playlevel;
+    }
+    if from_config("difficulty") {
+        args.difficulty = config.difficulty;
+    }
+    if from_config("rank") {
+        args.rank = config.rank;
+    }
+    if from_config("defexrank") {
+        args.defexrank = config.defexrank;
+    }
+    if from_config("header") {
+        args.header = config.header.clone();
+    }
+}
+
+/// Settings of the arguments which a config covers
+fn config_from_args(args: &Args) -> GeneratorConfig {
+    fn parse_values<T: FromStr>(input: &str) -> Vec<T> {
+        input.split(',').filter_map(|x| x.parse().ok()).collect()
+    }
+
+    GeneratorConfig {
+        title: args.title.clone(),
+        bpm: args.bpm,
+        bars: args.bars,
+        seed: args.seed,
+        keys: args.keys.to_key_mode().lanes() as u8,
+        dp: args.dp,
+        subdivisions: parse_values(&args.subdivisions),
+        density: parse_values(&args.density),
+        jack_tolerance: args.jack_tolerance,
+        scatter: args.scatter,
+        scatter_decay_rate: args.scatter_decay_rate,
+        ln_ratio: args.ln_ratio,
+        ln_min_length: args.ln_min_length,
+        ln_max_length: args.ln_max_length,
+        chord_progression: args
+            .chord_progression
+            .split(',')
+            .map(String::from)
+            .collect(),
+        subtitle: args.subtitle.clone().unwrap_or_default(),
+        genre: args.genre.clone().unwrap_or_default(),
+        artist: args.artist.clone().unwrap_or_default(),
+        subartist: args.subartist.clone().unwrap_or_default(),
+        playlevel: args.playlevel,
+        difficulty: args.difficulty,
+        rank: args.rank,
+        defexrank: args.defexrank,
+        stagefile: args.stagefile.clone().unwrap_or_default(),
+        banner: args.banner.clone().unwrap_or_default(),
+        backbmp: args.backbmp.clone().unwrap_or_default(),
+        comment: args.comment.clone().unwrap_or_default(),
+        header: args.header.clone(),
+    }
+}
+
+/// Options given on the command line which the config of --save-config cannot keep, so that
+/// reading the config would generate another chart
+fn unsaved_options(matches: &ArgMatches, config: &GeneratorConfig) -> Vec<String> {
+    // Options which only decide how and where the chart is written, and the ones kept separately
+    let kept = [
+        "lnobj",
+        "bmson",
+        "report",
+        "report_json",
+        "config",
+        "preset",
+        "save_config",
+        "seed",
+        "dp",
+        "header",
+    ];
+    Cli::command()
+        .get_arguments()
+        .filter(|arg| {
+            let id = arg.get_id().as_str();
+            matches.value_source(id) == Some(ValueSource::CommandLine)
+                && !kept.contains(&id)
+                && config_value(config, id).is_none()
+                // Empty texts are the same as omitted ones
+                && !matches
+                    .get_raw(id)
+                    .into_iter()
+                    .flatten()
+                    .all(|value| value.is_empty())
+        })
+        .filter_map(|arg| arg.get_long().map(|long| format!("--{long}")))
+        .collect()
+}
+
+/// Collects BMS files under `directory`
+fn find_bms_files(directory: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(directory)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            find_bms_files(&path, files)?;
+        } else if path.extension().is_some_and(|extension| {
+            ["bms", "bme", "bml", "pms"]
+                .iter()
+                .any(|bms| extension.eq_ignore_ascii_case(bms))
+        }) {
+            files.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+fn train(args: TrainArgs) {
+    if args.chords_per_bar == 0 {
+        let mut cmd = Cli::command();
         cmd.error(
             ErrorKind::ValueValidation,
-            "--density must be up to 5 comma-separated integers.",
+            "--chords-per-bar must be greater than 0.",
         )
         .exit();
+    }
+
+    let mut files = Vec::new();
+    if find_bms_files(&args.directory, &mut files).is_err() {
+        eprintln!("ディレクトリの読み込みに失敗しました。");
+        return;
+    }
+    files.sort();
+
+    let key_mode = args.keys.to_key_mode();
+    let mut model = MarkovModel::new(key_mode, args.order, args.chords_per_bar);
+    let mut charts = 0;
+    for file in files.iter() {
+        let Ok(input) = std::fs::read(file) else {
+            eprintln!("{} の読み込みに失敗しました。", file.display());
+            continue;
+        };
+        let chords = chords_from_bms(&BmsWriter::parse(&input), key_mode, args.chords_per_bar);
+        if !chords.is_empty() {
+            model.train(&chords);
+            charts += 1;
+        }
+    }
+
+    let written = File::create(&args.output)
+        .map_err(serde_json::Error::io)
+        .and_then(|file| serde_json::to_writer(file, &model));
+    if written.is_ok() {
+        println!("{charts} 個の譜面から学習しました。");
+    } else {
+        eprintln!("モデルの書き出しに失敗しました。");
+    }
+}
+
+fn table(args: TableArgs) {
+    let mut files = Vec::new();
+    if find_bms_files(&args.directory, &mut files).is_err() {
+        eprintln!("ディレクトリの読み込みに失敗しました。");
+        return;
+    }
+    files.sort();
+
+    let mut table = DifficultyTable::new(&args.name, &args.symbol);
+    let mut insane_table =
+        DifficultyTable::new(&format!("{} insane", args.name), &args.insane_symbol);
+    for file in files.iter() {
+        let Ok(input) = std::fs::read(file) else {
+            eprintln!("{} の読み込みに失敗しました。", file.display());
+            continue;
+        };
+        let bms = BmsWriter::parse(&input);
+        let level = args.level.unwrap_or_else(|| match bms.playlevel() {
+            Some(playlevel) => Level::from_bms_header(playlevel, bms.difficulty()),
+            None => {
+                // 7 keys also cover the channels of 5 keys
+                let is_pms = file
+                    .extension()
+                    .is_some_and(|extension| extension.eq_ignore_ascii_case("pms"));
+                let key_mode = if is_pms {
+                    KeyMode::PopN9
+                } else {
+                    KeyMode::Beat7
+                };
+                // The estimation depends on the timing of notes, not on the grid
+                estimate_chart(&chart_from_bms(&bms, key_mode, 192)).level
+            }
+        });
+        let chart = TableChart::new(bms.title(), bms.artist(), level, &input);
+        match level {
+            Level::Normal(_) => table.push_chart(chart),
+            Level::Insane(_) => insane_table.push_chart(chart),
+        }
+    }
+
+    let output = args.output.as_deref().unwrap_or(&args.directory);
+    let write_table = |table: &DifficultyTable, directory: &Path| {
+        std::fs::create_dir_all(directory)
+            .and_then(|()| File::create(directory.join("header.json")))
+            .map_err(serde_json::Error::io)
+            .and_then(|file| table.write_header(file, "data.json"))
+            .and_then(|_| File::create(directory.join("data.json")).map_err(serde_json::Error::io))
+            .and_then(|file| table.write_data(file))
     };
 
+    let mut written = Ok(());
+    if !table.charts().is_empty() || insane_table.charts().is_empty() {
+        written = written.and_then(|()| write_table(&table, output));
+    }
+    if !insane_table.charts().is_empty() {
+        written = written.and_then(|()| write_table(&insane_table, &output.join("insane")));
+    }
+    if written.is_ok() {
+        println!(
+            "{} 個の譜面の難易度表を書き出しました。",
+            table.charts().len() + insane_table.charts().len()
+        );
+    } else {
+        eprintln!("難易度表の書き出しに失敗しました。");
+    }
+}
+
+/// Arguments which generate a chart of the batch again, in the same form as `generator_args`
+fn batch_generator_args(
+    args: &BatchArgs,
+    name: &str,
+    density: &str,
+    jack_tolerance: f32,
+    scatter: f32,
+    seed: u64,
+) -> Vec<String> {
+    let keys = args.keys.to_possible_value().unwrap();
+    let argv = [
+        "bms-generator-cli".to_owned(),
+        name.to_owned(),
+        format!("--title={name}"),
+        format!("--bpm={}", args.bpm),
+        format!("--bars={}", args.bars),
+        format!("--subdivisions={}", args.subdivisions),
+        format!("--density={density}"),
+        format!("--jack-tolerance={jack_tolerance}"),
+        format!("--scatter={scatter}"),
+        format!("--scatter-decay-rate={}", args.scatter_decay_rate),
+        format!("--keys={}", keys.get_name()),
+    ];
+    // The options of batch are valid for the generation
+    let matches = Cli::command().get_matches_from(argv);
+    generator_args(&matches, None, seed)
+}
+
+fn batch(args: BatchArgs) {
+    let Some(subdivisions) = parse_subdivisions(&args.subdivisions) else {
+        let mut cmd = Cli::command();
+        cmd.error(
+            ErrorKind::ValueValidation,
+            "--subdivisions must be comma-separated positive integers.",
+        )
+        .exit();
+    };
+
+    let mut densities = Vec::new();
+    for density in args.density.iter() {
+        let Some(chord_density) = parse_density(density, &subdivisions) else {
+            let mut cmd = Cli::command();
+            cmd.error(
+                ErrorKind::ValueValidation,
+                format!("invalid --density: {density}"),
+            )
+            .exit();
+        };
+        densities.push((density.as_str(), chord_density));
+    }
+
     if !(0.0..=1.0).contains(&args.scatter_decay_rate) {
-        let mut cmd = Args::command();
+        let mut cmd = Cli::command();
         cmd.error(
             ErrorKind::ValueValidation,
             "--scatter-decay-rate must be between 0 and 1.",
@@ -103,80 +1104,947 @@ fn main() {
         .exit();
     };
 
-    let scatter = Scatter::new(
-        args.scatter.abs(),
-        args.scatter_decay_rate,
-        args.scatter < 0.0,
-    );
+    if std::fs::create_dir_all(&args.directory).is_err() {
+        eprintln!("ディレクトリの作成に失敗しました。");
+        return;
+    }
+
+    let key_mode = args.keys.to_key_mode();
+    let first_seed = args.seed.unwrap_or_else(seed_from_time);
+    let mut jobs = Vec::new();
+    // Repeated values would write the same file more than once
+    let mut names = HashSet::new();
+    for (density, chord_density) in densities.iter() {
+        for &jack_tolerance in args.jack_tolerance.iter() {
+            for &scatter in args.scatter.iter() {
+                for seed in (0..args.seeds).map(|i| first_seed.wrapping_add(i)) {
+                    let name = format!(
+                        "{}_d{}_j{jack_tolerance}_s{scatter}_{seed}",
+                        args.title,
+                        density.replace(',', "-")
+                    );
+                    if names.insert(name.clone()) {
+                        jobs.push((name, *density, chord_density, jack_tolerance, scatter, seed));
+                    }
+                }
+            }
+        }
+    }
+
+    let results: Vec<_> = jobs
+        .into_par_iter()
+        .map(
+            |(name, density, chord_density, jack_tolerance, scatter, seed)| {
+                let mut chart_params = ChartParams::new(args.bpm, args.bars, seed);
+                chart_params.set_chords_per_bar(chords_per_bar(&subdivisions));
+                chart_params.set_key_mode(key_mode);
+                let scatter_params =
+                    Scatter::new(scatter.abs(), args.scatter_decay_rate, scatter < 0.0);
+                let notes_params =
+                    NotesParams::new(chord_density.clone(), jack_tolerance, scatter_params);
+                let chart = generate_chart(&chart_params, &notes_params);
 
-    let seed = args.seed.unwrap_or_else(seed_from_time);
-    let chart_params = ChartParams::new(args.bpm, args.bars, seed);
-    let notes_params = NotesParams::new(chord_density, args.jack_tolerance, scatter);
+                let filename = args
+                    .directory
+                    .join(format!("{name}.{}", key_mode.extension()));
+                let metadata = |comment| Metadata {
+                    title: name.clone(),
+                    comment,
+                    comment_lines: batch_generator_args(
+                        &args,
+                        &name,
+                        density,
+                        jack_tolerance,
+                        scatter,
+                        seed,
+                    )
+                    .iter()
+                    .map(|arg| format!("{GENERATOR_ARG_PREFIX}{arg}"))
+                    .collect(),
+                    ..Default::default()
+                };
+                let chord_progression = GeneratorConfig::default().chord_progression();
+                let mut keysounds = ChordKeySound::new(chord_progression);
+                let mut buf = Vec::new();
+                let written = write_generated_chart(
+                    &mut buf,
+                    &chart,
+                    ChartFormat::Bms(LongNoteMode::Channel),
+                    Some((jack_tolerance, scatter)),
+                    seed,
+                    metadata,
+                    &mut keysounds,
+                )
+                .map_err(|err| err.to_string())
+                .and_then(|()| std::fs::write(&filename, &buf).map_err(|err| err.to_string()));
+                if let Err(err) = written {
+                    eprintln!("{} の書き出しに失敗しました: {err}", filename.display());
+                    return None;
+                }
 
-    let file = File::create(args.filename).expect("Failed to open file");
+                let (md5, sha256) = chart_hashes(&buf);
+                Some(serde_json::json!({
+                    "filename": filename.file_name().map(|name| name.to_string_lossy()),
+                    "density": density,
+                    "jack_tolerance": jack_tolerance,
+                    "scatter": scatter,
+                    "seed": seed,
+                    "level": estimate_chart(&chart).level.to_string(),
+                    "md5": md5,
+                    "sha256": sha256,
+                }))
+            },
+        )
+        .collect();
 
-    let success = if args.dp {
-        let chart = generate_chart_dp(&chart_params, &notes_params, &notes_params);
-        let notes: usize = chart
-            .bars
+    let charts: Vec<_> = results.into_iter().flatten().collect();
+    let written = File::create(args.directory.join("manifest.json"))
+        .map_err(serde_json::Error::io)
+        .and_then(|file| serde_json::to_writer_pretty(file, &charts));
+    if written.is_ok() {
+        println!("{} 個の譜面を生成しました。", charts.len());
+    } else {
+        eprintln!("マニフェストの書き出しに失敗しました。");
+    }
+}
+
+fn main() {
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    match cli.command {
+        Some(Command::Train(args)) => train(args),
+        Some(Command::Table(args)) => table(args),
+        Some(Command::Batch(args)) => batch(args),
+        Some(Command::Regenerate(args)) => regenerate(args),
+        Some(Command::Transform(args)) => transform(args),
+        None => generate(cli.args, &matches),
+    }
+}
+
+/// Prefix of the comment lines which keep the arguments of the generation in a BMS file
+const GENERATOR_ARG_PREFIX: &str = "*bms-generator ";
+
+/// Value of an option as read from the config, in the format of the command line
+fn config_value(config: &GeneratorConfig, id: &str) -> Option<String> {
+    let join = |values: Vec<String>| values.join(",");
+    let text = |value: &str| (!value.is_empty()).then(|| value.to_owned());
+    Some(match id {
+        "title" => config.title.clone(),
+        "bpm" => config.bpm.to_string(),
+        "bars" => config.bars.to_string(),
+        "keys" => config.keys.to_string(),
+        "subdivisions" => join(config.subdivisions.iter().map(usize::to_string).collect()),
+        "density" => join(config.density.iter().map(u64::to_string).collect()),
+        "jack_tolerance" => config.jack_tolerance.to_string(),
+        "scatter" => config.scatter.to_string(),
+        "scatter_decay_rate" => config.scatter_decay_rate.to_string(),
+        "ln_ratio" => config.ln_ratio.to_string(),
+        "ln_min_length" => config.ln_min_length.to_string(),
+        "ln_max_length" => config.ln_max_length.to_string(),
+        "chord_progression" => config.chord_progression.join(","),
+        "subtitle" => text(&config.subtitle)?,
+        "genre" => text(&config.genre)?,
+        "artist" => text(&config.artist)?,
+        "subartist" => text(&config.subartist)?,
+        "playlevel" => config.playlevel?.to_string(),
+        "difficulty" => config.difficulty?.to_string(),
+        "rank" => config.rank.to_string(),
+        "defexrank" => config.defexrank?.to_string(),
+        "stagefile" => text(&config.stagefile)?,
+        "banner" => text(&config.banner)?,
+        "backbmp" => text(&config.backbmp)?,
+        "comment" => text(&config.comment)?,
+        _ => return None,
+    })
+}
+
+/// Arguments which generate the same chart again, one per element. Options read from --config or
+/// --preset are written as arguments, and the seed is always written.
+fn generator_args(
+    matches: &ArgMatches,
+    config: Option<&GeneratorConfig>,
+    seed: u64,
+) -> Vec<String> {
+    // Options which do not change the chart, and the ones written separately
+    let skipped = [
+        "config",
+        "preset",
+        "save_config",
+        "report",
+        "report_json",
+        "seed",
+    ];
+    let mut generator_args = vec![format!("--seed={seed}")];
+    let command = Cli::command();
+    // Values of the config for options which conflict with the given ones are not used
+    let conflicts = |a: &Arg, b: &Arg| {
+        command
+            .get_arg_conflicts_with(a)
             .iter()
-            .flatten()
-            .flat_map(|chords| chords.iter().map(|chord| chord.lanes.len()))
-            .sum();
-        let total = f32::max(1000.0 - 1000000.0 / (1000.0 + notes as f32), 250.0);
-        let duration = 240.0 / chart.bpm * chart.bars.len() as f32;
-        let density = notes as f32 / duration;
-        let genre = format!("密度: {density:.02} notes/s");
-        let artist = format!(
-            "jacks: {:.01}, scatter: {:.01}, seed: {seed:?}",
-            args.jack_tolerance, args.scatter
+            .any(|other| other.get_id() == b.get_id())
+    };
+    let conflicts_with_given = |arg: &Arg| {
+        command.get_arguments().any(|other| {
+            matches.value_source(other.get_id().as_str()) == Some(ValueSource::CommandLine)
+                && (conflicts(arg, other) || conflicts(other, arg))
+        })
+    };
+
+    for arg in command.get_arguments() {
+        let id = arg.get_id().as_str();
+        let Some(long) = arg.get_long() else {
+            continue;
+        };
+        if skipped.contains(&id) {
+            continue;
+        }
+
+        if matches.value_source(id) != Some(ValueSource::CommandLine) {
+            if conflicts_with_given(arg) {
+                continue;
+            }
+            match config {
+                Some(config) if id == "dp" && config.dp => generator_args.push(format!("--{long}")),
+                Some(config) if id == "header" => generator_args.extend(
+                    config
+                        .header
+                        .iter()
+                        .map(|header| format!("--{long}={header}")),
+                ),
+                Some(config) => generator_args
+                    .extend(config_value(config, id).map(|value| format!("--{long}={value}"))),
+                None => {}
+            }
+        } else if arg.get_action().takes_values() {
+            for value in matches.get_raw(id).into_iter().flatten() {
+                generator_args.push(format!("--{long}={}", value.to_string_lossy()));
+            }
+        } else {
+            generator_args.push(format!("--{long}"));
+        }
+    }
+
+    generator_args
+}
+
+/// Filename of the output, with the extension suited for the keys and the format
+fn output_filename(args: &Args) -> PathBuf {
+    // Present unless a subcommand is given
+    let mut filename = args.filename.clone().unwrap();
+    let has_bms_extension = filename
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| ["bms", "bme", "pms", "bmson"].contains(&extension));
+    if has_bms_extension {
+        if args.bmson {
+            filename.set_extension("bmson");
+        } else {
+            filename.set_extension(args.keys.to_key_mode().extension());
+        }
+    }
+    filename
+}
+
+/// Filename of a stage of --course, numbered from 1 before the extension (e.g. out_1.bme)
+fn numbered_filename(filename: &Path, stage: usize) -> PathBuf {
+    let stem = filename
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let mut stage_filename = filename.with_file_name(format!("{stem}_{}", stage + 1));
+    if let Some(extension) = filename.extension() {
+        stage_filename.set_extension(extension);
+    }
+    stage_filename
+}
+
+/// Name of a long option such as `--scatter` or `--scatter=2`
+fn option_name(arg: &str) -> Option<&str> {
+    let arg = arg.strip_prefix("--")?;
+    Some(arg.split_once('=').map_or(arg, |(name, _)| name))
+}
+
+fn regenerate(args: RegenerateArgs) {
+    let Ok(input) = std::fs::read(&args.input) else {
+        eprintln!("{} の読み込みに失敗しました。", args.input.display());
+        return;
+    };
+    // bmson keeps the comment lines in its info
+    let comment_lines = comment_lines_from_bmson(&input)
+        .unwrap_or_else(|| BmsWriter::parse(&input).metadata().comment_lines.clone());
+    let mut generator_args: Vec<_> = comment_lines
+        .iter()
+        .filter_map(|line| line.strip_prefix(GENERATOR_ARG_PREFIX))
+        .map(String::from)
+        .collect();
+    if generator_args.is_empty() {
+        eprintln!(
+            "{} に生成時の設定が含まれていません。",
+            args.input.display()
         );
+        return;
+    }
 
-        let mut keysounds = ChordKeySound::new(CHORD_PROGRESSION.to_vec());
+    // The overrides replace every kept value of the same options
+    let overridden: Vec<_> = args
+        .overrides
+        .iter()
+        .filter_map(|arg| option_name(arg))
+        .collect();
+    generator_args.retain(|arg| option_name(arg).is_none_or(|name| !overridden.contains(&name)));
 
-        chart_dp_to_bms(
-            &file,
-            &chart,
-            &args.title,
-            &genre,
-            &artist,
-            total,
-            &mut keysounds,
+    let argv = ["bms-generator-cli".into(), args.output.into_os_string()]
+        .into_iter()
+        .chain(generator_args.into_iter().map(Into::into))
+        .chain(args.overrides.iter().map(Into::into));
+    let matches = Cli::command()
+        .try_get_matches_from(argv)
+        .unwrap_or_else(|err| err.exit());
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    let filename = output_filename(&cli.args);
+    let compare = args.overrides.is_empty() && cli.args.course.is_none();
+    generate(cli.args, &matches);
+
+    if compare {
+        if std::fs::read(&filename).is_ok_and(|output| output == input) {
+            println!("元の譜面と同一の譜面を再生成しました。");
+        } else {
+            eprintln!("再生成した譜面が元の譜面と一致しません。");
+        }
+    }
+}
+
+fn transform(args: TransformArgs) {
+    if args.chords_per_bar == 0 {
+        let mut cmd = Cli::command();
+        cmd.error(
+            ErrorKind::ValueValidation,
+            "--chords-per-bar must be greater than 0.",
         )
-        .is_ok()
+        .exit();
+    }
+    let key_mode = args.keys.to_key_mode();
+    if args.lane_option == RandomOption::AllScratch && !key_mode.has_scratch() {
+        let mut cmd = Cli::command();
+        cmd.error(
+            ErrorKind::ArgumentConflict,
+            "all-scratch cannot be used with --keys 9, which has no scratch.",
+        )
+        .exit();
+    }
+
+    let Ok(input) = std::fs::read(&args.input) else {
+        eprintln!("{} の読み込みに失敗しました。", args.input.display());
+        return;
+    };
+    let bms = BmsWriter::parse(&input);
+    let mut chart = chart_from_bms(&bms, key_mode, args.chords_per_bar);
+    let mut rng = RNG::new_u64(args.seed.unwrap_or_else(seed_from_time));
+    transform_chart(&mut chart, args.lane_option.to_lane_option(), &mut rng);
+
+    // The kept arguments would generate the chart before the transform
+    let mut metadata = bms.metadata().clone();
+    metadata
+        .comment_lines
+        .retain(|line| !line.starts_with(GENERATOR_ARG_PREFIX));
+    let long_note_mode = if bms.lnobj().is_some() {
+        LongNoteMode::LnObj
     } else {
-        let chart = generate_chart(&chart_params, &notes_params);
-        let notes: usize = chart
-            .bars
-            .iter()
-            .flatten()
-            .map(|chord| chord.lanes.len())
-            .sum();
-        let total = f32::max(1000.0 - 1000000.0 / (1000.0 + notes as f32), 250.0);
-        let duration = 240.0 / chart.bpm * chart.bars.len() as f32;
-        let density = notes as f32 / duration;
-        let genre = format!("密度: {density:.02} notes/s");
-        let artist = format!(
-            "jacks: {:.01}, scatter: {:.01}, seed: {seed:?}",
-            args.jack_tolerance, args.scatter
+        LongNoteMode::Channel
+    };
+    let mut keysounds = ChordKeySound::new(GeneratorConfig::default().chord_progression());
+    let mut buf = Vec::new();
+    let written = chart_to_bms(
+        &mut buf,
+        &chart,
+        &metadata,
+        bms.total(),
+        long_note_mode,
+        IndexBase::Base36,
+        &mut keysounds,
+    )
+    .map_err(|err| err.to_string())
+    .and_then(|()| std::fs::write(&args.output, &buf).map_err(|err| err.to_string()));
+    match written {
+        Ok(()) => println!("譜面を変換しました。"),
+        Err(err) => eprintln!("{} の書き出しに失敗しました: {err}", args.output.display()),
+    }
+}
+
+fn generate(mut args: Args, matches: &ArgMatches) {
+    let config = if let Some(filename) = &args.config {
+        let config = std::fs::read_to_string(filename)
+            .map_err(|err| err.to_string())
+            .and_then(|input| GeneratorConfig::from_toml(&input));
+        match config {
+            Ok(config) => Some(config),
+            Err(err) => {
+                let mut cmd = Cli::command();
+                cmd.error(
+                    ErrorKind::ValueValidation,
+                    format!("failed to read --config {}: {err}", filename.display()),
+                )
+                .exit();
+            }
+        }
+    } else {
+        args.preset.as_deref().and_then(GeneratorConfig::preset)
+    };
+    if let Some(config) = &config {
+        apply_config(&mut args, config, matches);
+    }
+
+    let Some(subdivisions) = parse_subdivisions(&args.subdivisions) else {
+        let mut cmd = Cli::command();
+        cmd.error(
+            ErrorKind::ValueValidation,
+            "--subdivisions must be comma-separated positive integers.",
+        )
+        .exit();
+    };
+
+    let Some(chord_density) = parse_density(&args.density, &subdivisions) else {
+        let mut cmd = Cli::command();
+        cmd.error(
+            ErrorKind::ValueValidation,
+            "--density must be as many comma-separated integers as --subdivisions.",
+        )
+        .exit();
+    };
+
+    let scratch_params = match &args.scratch_density {
+        Some(scratch_density) => {
+            let Some(density) = parse_density(scratch_density, &subdivisions) else {
+                let mut cmd = Cli::command();
+                cmd.error(
+                    ErrorKind::ValueValidation,
+                    "--scratch-density must be as many comma-separated integers as --subdivisions.",
+                )
+                .exit();
+            };
+            if !(0.0..=1.0).contains(&args.backspin_ratio) {
+                let mut cmd = Cli::command();
+                cmd.error(
+                    ErrorKind::ValueValidation,
+                    "--backspin-ratio must be between 0 and 1.",
+                )
+                .exit();
+            };
+            if args.backspin_min_length == 0 || args.backspin_min_length > args.backspin_max_length
+            {
+                let mut cmd = Cli::command();
+                cmd.error(
+                    ErrorKind::ValueValidation,
+                    "--backspin-min-length must be between 1 and --backspin-max-length.",
+                )
+                .exit();
+            };
+
+            let mut scratch_params = ScratchParams::new(density);
+            if let Some(max_keys) = args.scratch_max_keys {
+                scratch_params.set_max_keys(max_keys);
+            }
+            scratch_params.set_backspin(LongNoteParams::new(
+                args.backspin_ratio,
+                args.backspin_min_length,
+                args.backspin_max_length,
+            ));
+            for rush in args.scratch_rush.iter() {
+                let Some((bars, density)) = parse_scratch_rush(rush, &subdivisions) else {
+                    let mut cmd = Cli::command();
+                    cmd.error(
+                        ErrorKind::ValueValidation,
+                        format!("invalid --scratch-rush: {rush}"),
+                    )
+                    .exit();
+                };
+                scratch_params.push_rush(bars, density);
+            }
+            Some(scratch_params)
+        }
+        None => None,
+    };
+
+    if !(0.0..=1.0).contains(&args.scatter_decay_rate) {
+        let mut cmd = Cli::command();
+        cmd.error(
+            ErrorKind::ValueValidation,
+            "--scatter-decay-rate must be between 0 and 1.",
+        )
+        .exit();
+    };
+
+    if args.bpm_change_interval == 0 {
+        let mut cmd = Cli::command();
+        cmd.error(
+            ErrorKind::ValueValidation,
+            "--bpm-change-interval must be greater than 0.",
+        )
+        .exit();
+    };
+
+    if !(0.0..=1.0).contains(&args.ln_ratio) {
+        let mut cmd = Cli::command();
+        cmd.error(
+            ErrorKind::ValueValidation,
+            "--ln-ratio must be between 0 and 1.",
+        )
+        .exit();
+    };
+
+    if args.ln_min_length == 0 || args.ln_min_length > args.ln_max_length {
+        let mut cmd = Cli::command();
+        cmd.error(
+            ErrorKind::ValueValidation,
+            "--ln-min-length must be between 1 and --ln-max-length.",
+        )
+        .exit();
+    };
+
+    let key_mode = args.keys.to_key_mode();
+    let all_scratch =
+        [args.lane_option, args.lane_option_2p].contains(&Some(RandomOption::AllScratch));
+    if all_scratch && !key_mode.has_scratch() {
+        let mut cmd = Cli::command();
+        cmd.error(
+            ErrorKind::ArgumentConflict,
+            "all-scratch cannot be used with --keys 9, which has no scratch.",
+        )
+        .exit();
+    }
+
+    let Some(chord_progression) = args
+        .chord_progression
+        .split(',')
+        .map(parse_chord)
+        .collect::<Option<Vec<_>>>()
+    else {
+        let mut cmd = Cli::command();
+        cmd.error(
+            ErrorKind::ValueValidation,
+            "--chord-progression must be comma-separated chord names (e.g. D,A,Bm,F#m).",
+        )
+        .exit();
+    };
+
+    if let Some(filename) = &args.save_config {
+        let config = config_from_args(&args);
+        let unsaved = unsaved_options(matches, &config);
+        if !unsaved.is_empty() {
+            let mut cmd = Cli::command();
+            cmd.error(
+                ErrorKind::ArgumentConflict,
+                format!("--save-config cannot save {}.", unsaved.join(", ")),
+            )
+            .exit();
+        }
+        if std::fs::write(filename, config.to_toml()).is_err() {
+            eprintln!("設定の書き出しに失敗しました。");
+        }
+    }
+
+    if !(0.0..=1.0).contains(&args.constraint_reroll_chance) {
+        let mut cmd = Cli::command();
+        cmd.error(
+            ErrorKind::ValueValidation,
+            "--constraint-reroll-chance must be between 0 and 1.",
+        )
+        .exit();
+    };
+
+    let mut constraints = Vec::new();
+    if let Some(max_length) = args.max_stairs {
+        constraints.push(PatternConstraint::Stairs { max_length });
+    }
+    if let Some(max_length) = args.max_trill {
+        constraints.push(PatternConstraint::Trill { max_length });
+    }
+    if let Some(max_length) = args.max_denim {
+        constraints.push(PatternConstraint::Denim { max_length });
+    }
+    for lanes in args.forbid_chord.iter() {
+        let Some(lanes) = parse_lanes(lanes, key_mode.lanes()) else {
+            let mut cmd = Cli::command();
+            cmd.error(
+                ErrorKind::ValueValidation,
+                format!("invalid --forbid-chord: {lanes}"),
+            )
+            .exit();
+        };
+        constraints.push(PatternConstraint::ChordShape { lanes });
+    }
+    if let Some(lanes) = &args.forbid_scratch_combo {
+        let Some(lanes) = parse_lanes(lanes, key_mode.lanes()) else {
+            let mut cmd = Cli::command();
+            cmd.error(
+                ErrorKind::ValueValidation,
+                format!("invalid --forbid-scratch-combo: {lanes}"),
+            )
+            .exit();
+        };
+        constraints.push(PatternConstraint::ScratchCombo { lanes });
+    }
+
+    let ergonomics = args.fingering.map(|fingering| {
+        if key_mode != KeyMode::Beat7 {
+            let mut cmd = Cli::command();
+            cmd.error(
+                ErrorKind::ArgumentConflict,
+                "--fingering can only be used with --keys 7.",
+            )
+            .exit();
+        }
+        if !(0.0..=1.0).contains(&args.same_finger_penalty)
+            || !(0.0..=1.0).contains(&args.overload_penalty)
+        {
+            let mut cmd = Cli::command();
+            cmd.error(
+                ErrorKind::ValueValidation,
+                "--same-finger-penalty and --overload-penalty must be between 0 and 1.",
+            )
+            .exit();
+        }
+
+        let fingering = match fingering {
+            FingeringStyle::Style1048 => Fingering::style_1048(),
+            FingeringStyle::Style2048 => Fingering::style_2048(),
+        };
+        let mut ergonomics = Ergonomics::new(fingering);
+        ergonomics.set_same_finger_penalty(args.same_finger_penalty);
+        ergonomics.set_hand_capacity(args.hand_capacity, args.overload_penalty);
+        ergonomics
+    });
+
+    let model = args.model.as_ref().map(|filename| {
+        let model = std::fs::read(filename)
+            .ok()
+            .and_then(|input| serde_json::from_slice::<MarkovModel>(&input).ok());
+        let Some(model) = model else {
+            let mut cmd = Cli::command();
+            cmd.error(
+                ErrorKind::ValueValidation,
+                format!("failed to read --model: {}", filename.display()),
+            )
+            .exit();
+        };
+        if model.lanes() != key_mode.lanes() {
+            let mut cmd = Cli::command();
+            cmd.error(
+                ErrorKind::ArgumentConflict,
+                "--model was trained with a different number of keys from --keys.",
+            )
+            .exit();
+        }
+        model
+    });
+
+    if args.course.is_some_and(|stages| stages < 2) {
+        let mut cmd = Cli::command();
+        cmd.error(ErrorKind::ValueValidation, "--course must be 2 or more.")
+            .exit();
+    };
+
+    if args.call_and_response_length == 0 {
+        let mut cmd = Cli::command();
+        cmd.error(
+            ErrorKind::ValueValidation,
+            "--call-and-response-length must be greater than 0.",
+        )
+        .exit();
+    };
+
+    if args.big_chord_size == Some(0) {
+        let mut cmd = Cli::command();
+        cmd.error(
+            ErrorKind::ValueValidation,
+            "--big-chord-size must be greater than 0.",
+        )
+        .exit();
+    };
+
+    if args.dp && !key_mode.supports_dp() {
+        let mut cmd = Cli::command();
+        cmd.error(
+            ErrorKind::ArgumentConflict,
+            "--dp cannot be used with --keys 9.",
+        )
+        .exit();
+    };
+
+    let levels = match (args.min_level, args.max_level) {
+        (None, None) => None,
+        (min_level, max_level) => Some(
+            min_level.unwrap_or(Level::Normal(1))..=max_level.unwrap_or(Level::Insane(u32::MAX)),
+        ),
+    };
+
+    if levels
+        .as_ref()
+        .is_some_and(|levels| levels.start().rank() > levels.end().rank())
+    {
+        let mut cmd = Cli::command();
+        cmd.error(
+            ErrorKind::ValueValidation,
+            "--min-level must not be higher than --max-level.",
+        )
+        .exit();
+    };
+
+    let mut seed = args.seed.unwrap_or_else(seed_from_time);
+    let generator_args = generator_args(matches, config.as_ref(), seed);
+    let mut chart_params = ChartParams::new(args.bpm, args.bars, seed);
+    chart_params.set_chords_per_bar(chords_per_bar(&subdivisions));
+    chart_params.set_key_mode(key_mode);
+    let mut dp_params = DpParams::new();
+    dp_params.set_phrasing(match args.dp_phrasing {
+        Phrasing::Independent => DpPhrasing::Independent,
+        Phrasing::Mirrored => DpPhrasing::Mirrored,
+        Phrasing::CallAndResponse => DpPhrasing::CallAndResponse {
+            chords: args.call_and_response_length,
+        },
+    });
+    dp_params.set_split_density(args.split_density);
+    if let Some(max_hand_keys) = args.max_hand_keys {
+        dp_params.set_max_hand_keys(max_hand_keys);
+    }
+    if let Some(size) = args.big_chord_size {
+        dp_params.set_big_chord_size(size);
+    }
+    chart_params.set_dp_params(dp_params);
+    if let Some(end_bpm) = args.end_bpm {
+        chart_params.set_tempo_map(TempoMap::gradual(
+            args.bpm,
+            end_bpm,
+            args.bars,
+            args.bpm_change_interval,
+        ));
+    }
+    let build_notes_params = |chord_density, jack_tolerance, scatter: f32| {
+        let scatter = Scatter::new(scatter.abs(), args.scatter_decay_rate, scatter < 0.0);
+        let mut notes_params = NotesParams::new(chord_density, jack_tolerance, scatter);
+        notes_params.set_long_notes(LongNoteParams::new(
+            args.ln_ratio,
+            args.ln_min_length,
+            args.ln_max_length,
+        ));
+        for constraint in constraints.iter() {
+            notes_params.push_constraint(constraint.clone(), args.constraint_reroll_chance);
+        }
+        if let Some(scratch_params) = &scratch_params {
+            notes_params.set_scratch(scratch_params.clone());
+        }
+        if let Some(ergonomics) = &ergonomics {
+            notes_params.set_ergonomics(ergonomics.clone());
+        }
+        notes_params.set_templates(
+            args.pattern
+                .iter()
+                .map(|pattern| pattern.to_pattern_family())
+                .collect(),
         );
+        notes_params
+    };
+    let mut notes_params = build_notes_params(chord_density, args.jack_tolerance, args.scatter);
+
+    if let Some(levels) = levels {
+        let found = if args.dp {
+            generate_chart_dp_with_difficulty(
+                &chart_params,
+                &notes_params,
+                levels,
+                DIFFICULTY_SEARCH_ATTEMPTS,
+            )
+            .map(|found| (found.seed, found.density_scale, found.difficulty))
+        } else {
+            generate_chart_with_difficulty(
+                &chart_params,
+                &notes_params,
+                levels,
+                DIFFICULTY_SEARCH_ATTEMPTS,
+            )
+            .map(|found| (found.seed, found.density_scale, found.difficulty))
+        };
+        let Some((found_seed, density_scale, difficulty)) = found else {
+            eprintln!("指定された難易度の譜面が見つかりませんでした。");
+            return;
+        };
+
+        println!(
+            "推定難易度 {} の譜面が見つかりました (seed: {found_seed}, 密度の倍率: {density_scale:.02})",
+            difficulty.level
+        );
+        seed = found_seed;
+        chart_params.set_seed(seed);
+        notes_params.set_chord_density(notes_params.chord_density().scaled(density_scale));
+    }
+
+    let mut schedule = NotesSchedule::new(notes_params);
+    let mut start_bar = 0;
+    for section in args.section.iter() {
+        let Some((bars, chord_density, jack_tolerance, scatter)) =
+            parse_section(section, &subdivisions, args.jack_tolerance, args.scatter)
+        else {
+            let mut cmd = Cli::command();
+            cmd.error(
+                ErrorKind::ValueValidation,
+                format!("invalid --section: {section}"),
+            )
+            .exit();
+        };
+
+        let notes_params = build_notes_params(chord_density, jack_tolerance, scatter);
+        if start_bar == 0 {
+            schedule = NotesSchedule::new(notes_params);
+        } else {
+            schedule.push_section(start_bar, notes_params);
+        }
+        start_bar += bars;
+    }
+    schedule.set_interpolate(args.interpolate);
+
+    let format = if args.bmson {
+        ChartFormat::Bmson
+    } else if args.lnobj {
+        ChartFormat::Bms(LongNoteMode::LnObj)
+    } else {
+        ChartFormat::Bms(LongNoteMode::Channel)
+    };
+
+    let filename = output_filename(&args);
+
+    let mut metadata = metadata_from_args(&args);
+    metadata.comment_lines = generator_args
+        .iter()
+        .map(|arg| format!("{GENERATOR_ARG_PREFIX}{arg}"))
+        .collect();
+
+    // Charts sampled from --model or built from --pattern templates do not use them
+    let jack_and_scatter =
+        (model.is_none() && args.pattern.is_empty()).then_some((args.jack_tolerance, args.scatter));
 
-        let mut keysounds = ChordKeySound::new(CHORD_PROGRESSION.to_vec());
+    // Writes a chart to the file and returns its content
+    let write_chart = |chart_params: &ChartParams,
+                       schedule: &NotesSchedule,
+                       title: &str,
+                       filename: &Path,
+                       report_filename: Option<&Path>|
+     -> Result<Vec<u8>, String> {
+        let seed = chart_params.seed();
+        let mut lane_option_rng = RNG::new_u64(args.lane_option_seed.unwrap_or(seed));
+        let mut buf = Vec::new();
+        let mut keysounds = ChordKeySound::new(chord_progression.clone());
+        // The comment describes the generation unless --comment is given
+        let chart_metadata = |comment: String| {
+            let mut metadata = metadata.clone();
+            metadata.title = title.to_owned();
+            if args.comment.is_none() {
+                metadata.comment = comment;
+            }
+            metadata
+        };
 
-        chart_to_bms(
-            &file,
-            &chart,
+        let written = if args.dp {
+            let mut chart = generate_chart_dp_with_schedule(chart_params, schedule, schedule);
+            let lane_options = [args.lane_option, args.lane_option_2p]
+                .map(|option| option.map(RandomOption::to_lane_option));
+            transform_chart_dp(&mut chart, lane_options, &mut lane_option_rng);
+            if args.flip {
+                flip(&mut chart);
+            }
+            output_report(&analyze_chart_dp(&chart), args.report, report_filename);
+            write_generated_chart(
+                &mut buf,
+                &chart,
+                format,
+                jack_and_scatter,
+                seed,
+                chart_metadata,
+                &mut keysounds,
+            )
+        } else {
+            let mut chart = match &model {
+                Some(model) => generate_chart_with_model(chart_params, model),
+                None => generate_chart_with_schedule(chart_params, schedule),
+            };
+            if let Some(option) = args.lane_option {
+                transform_chart(&mut chart, option.to_lane_option(), &mut lane_option_rng);
+            }
+            output_report(&analyze_chart(&chart), args.report, report_filename);
+            write_generated_chart(
+                &mut buf,
+                &chart,
+                format,
+                jack_and_scatter,
+                seed,
+                chart_metadata,
+                &mut keysounds,
+            )
+        };
+        written
+            .map_err(|err| err.to_string())
+            .and_then(|()| std::fs::write(filename, &buf).map_err(|err| err.to_string()))?;
+        Ok(buf)
+    };
+
+    let Some(stages) = args.course else {
+        let report_filename = args.report_json.as_deref();
+        match write_chart(
+            &chart_params,
+            &schedule,
             &args.title,
-            &genre,
-            &artist,
-            total,
-            &mut keysounds,
-        )
-        .is_ok()
+            &filename,
+            report_filename,
+        ) {
+            Ok(_) => println!("BMS の生成に成功しました。"),
+            Err(err) => eprintln!("BMS の書き出しに失敗しました: {err}"),
+        }
+        return;
     };
-    if success {
-        println!("BMS の生成に成功しました。");
+
+    let stem = filename
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let mut course = Course::new(&args.title);
+    for stage in 0..stages {
+        let t = stage as f32 / (stages - 1) as f32;
+        let density_scale = 1.0 + (args.course_last_scale - 1.0) * t;
+        let mut stage_params = chart_params.clone();
+        stage_params.set_seed(seed.wrapping_add(stage as u64));
+        let title = format!("{} STAGE {}", args.title, stage + 1);
+        let stage_filename = numbered_filename(&filename, stage);
+        // Each stage has its own report
+        let report_filename = args
+            .report_json
+            .as_deref()
+            .map(|report_filename| numbered_filename(report_filename, stage));
+
+        let bytes = match write_chart(
+            &stage_params,
+            &schedule.scaled(density_scale),
+            &title,
+            &stage_filename,
+            report_filename.as_deref(),
+        ) {
+            Ok(bytes) => bytes,
+            Err(err) => {
+                eprintln!(
+                    "{} の書き出しに失敗しました: {err}",
+                    stage_filename.display()
+                );
+                return;
+            }
+        };
+        course.push_chart(CourseChart::new(&title, &bytes));
+    }
+
+    let course_filename = filename.with_file_name(format!("{stem}_course.json"));
+    let written = File::create(&course_filename)
+        .map_err(serde_json::Error::io)
+        .and_then(|file| course.write_beatoraja(file));
+    if written.is_ok() {
+        println!("{stages} 曲のコースの生成に成功しました。");
     } else {
-        eprintln!("BMS の書き出しに失敗しました。");
+        eprintln!("コースの書き出しに失敗しました。");
     }
 }
@@ -0,0 +1,410 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::generate::{Chart, ChartDp, Chord};
+use crate::tempo::TempoMap;
+
+/// Lengths of the windows, in seconds, over which the peak density is measured
+pub const PEAK_DENSITY_WINDOWS: [f32; 3] = [1.0, 2.0, 4.0];
+
+/// Minimum number of notes counted as a trill (e.g. 1-2-1-2)
+const MIN_TRILL_LENGTH: usize = 4;
+/// Minimum number of notes counted as stairs (e.g. 1-2-3-4)
+const MIN_STAIRS_LENGTH: usize = 4;
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct PeakDensity {
+    /// Length of the window in seconds
+    pub window: f32,
+    /// Notes per second in the densest window
+    pub density: f32,
+    /// Seconds from the beginning of the chart to the start of the densest window
+    pub position: f32,
+}
+
+/// Statistics of a chart. Histograms map lengths (or sizes) to the number of occurrences.
+///
+/// Patterns are detected on each side separately, skipping chords without keys.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ChartReport {
+    /// Notes including scratches
+    pub notes: usize,
+    pub scratches: usize,
+    /// Long notes including backspins
+    pub long_notes: usize,
+    /// Duration of the chart in seconds
+    pub duration: f32,
+    /// Notes per second over the whole chart
+    pub average_density: f32,
+    /// Notes per second of each bar
+    pub bar_densities: Vec<f32>,
+    pub peak_densities: Vec<PeakDensity>,
+    /// Runs of consecutive notes on the same lane, by number of notes
+    pub jacks: BTreeMap<usize, usize>,
+    /// Runs of single notes alternating between two lanes, by number of notes
+    pub trills: BTreeMap<usize, usize>,
+    /// Runs of single notes moving to the adjacent lane in one direction, by number of notes
+    pub stairs: BTreeMap<usize, usize>,
+    /// Chords by number of keys, scratches excluded
+    pub chord_sizes: BTreeMap<usize, usize>,
+    /// Notes of each lane. In DP, lanes of the 2P side follow those of the 1P side.
+    pub lane_usage: Vec<usize>,
+}
+
+/// Chord times and lanes of a side, in order
+type Side<'a> = Vec<(f32, &'a Chord)>;
+
+struct Timing<'a> {
+    bpm: f32,
+    tempo_map: &'a TempoMap,
+    bar_lengths: Vec<usize>,
+}
+
+impl Timing<'_> {
+    fn seconds_at(&self, bar_idx: usize, chord_pos: usize) -> f32 {
+        let length = self.bar_lengths.get(bar_idx).copied().unwrap_or(1).max(1);
+        self.tempo_map.seconds_at(
+            self.bpm,
+            &self.bar_lengths,
+            bar_idx,
+            chord_pos as f32 / length as f32,
+        )
+    }
+}
+
+fn count_runs(histogram: &mut BTreeMap<usize, usize>, length: usize, min_length: usize) {
+    if length >= min_length {
+        *histogram.entry(length).or_default() += 1;
+    }
+}
+
+fn count_jacks(side: &Side, lanes: usize, jacks: &mut BTreeMap<usize, usize>) {
+    let mut runs = vec![0; lanes];
+
+    for (_, chord) in side.iter().filter(|(_, chord)| !chord.lanes.is_empty()) {
+        for (lane, run) in runs.iter_mut().enumerate() {
+            if chord.lanes.contains(&(lane as u8)) {
+                *run += 1;
+            } else {
+                count_runs(jacks, *run, 2);
+                *run = 0;
+            }
+        }
+    }
+
+    for run in runs {
+        count_runs(jacks, run, 2);
+    }
+}
+
+fn count_trills_and_stairs(
+    side: &Side,
+    trills: &mut BTreeMap<usize, usize>,
+    stairs: &mut BTreeMap<usize, usize>,
+) {
+    // Sequences of single notes, split by chords
+    let keyed_chords = side.iter().filter(|(_, chord)| !chord.lanes.is_empty());
+    let mut sequences = vec![vec![]];
+    for (_, chord) in keyed_chords {
+        if let [lane] = chord.lanes[..] {
+            sequences.last_mut().unwrap().push(lane as i32);
+        } else {
+            sequences.push(vec![]);
+        }
+    }
+
+    for sequence in sequences {
+        let mut trill = 1;
+        let mut stair = 1;
+
+        for (i, &lane) in sequence.iter().enumerate().skip(1) {
+            let step = lane - sequence[i - 1];
+
+            trill = if step != 0 && (i < 2 || lane == sequence[i - 2]) {
+                trill + 1
+            } else {
+                count_runs(trills, trill, MIN_TRILL_LENGTH);
+                if step != 0 {
+                    2
+                } else {
+                    1
+                }
+            };
+
+            stair = if step.abs() == 1 && (stair == 1 || step == sequence[i - 1] - sequence[i - 2])
+            {
+                stair + 1
+            } else {
+                count_runs(stairs, stair, MIN_STAIRS_LENGTH);
+                if step.abs() == 1 {
+                    2
+                } else {
+                    1
+                }
+            };
+        }
+
+        count_runs(trills, trill, MIN_TRILL_LENGTH);
+        count_runs(stairs, stair, MIN_STAIRS_LENGTH);
+    }
+}
+
+/// Returns the highest notes per second among windows starting at each note
+fn peak_density(note_times: &[f32], window: f32) -> PeakDensity {
+    let mut peak = PeakDensity {
+        window,
+        density: 0.0,
+        position: 0.0,
+    };
+    let mut end = 0;
+
+    for (start, &time) in note_times.iter().enumerate() {
+        while end < note_times.len() && note_times[end] < time + window {
+            end += 1;
+        }
+        let density = (end - start) as f32 / window;
+        if density > peak.density {
+            peak.density = density;
+            peak.position = time;
+        }
+    }
+
+    peak
+}
+
+fn analyze(timing: Timing, lanes_per_side: usize, sides: Vec<Side>, duration: f32) -> ChartReport {
+    let mut report = ChartReport {
+        notes: 0,
+        scratches: 0,
+        long_notes: 0,
+        duration,
+        average_density: 0.0,
+        bar_densities: Vec::new(),
+        peak_densities: Vec::new(),
+        jacks: BTreeMap::new(),
+        trills: BTreeMap::new(),
+        stairs: BTreeMap::new(),
+        chord_sizes: BTreeMap::new(),
+        lane_usage: vec![0; lanes_per_side * sides.len()],
+    };
+    let mut note_times = Vec::new();
+
+    for (side_idx, side) in sides.iter().enumerate() {
+        for &(time, chord) in side {
+            let notes = chord.lanes.len() + chord.scratch as usize;
+            report.notes += notes;
+            report.scratches += chord.scratch as usize;
+            report.long_notes += chord.long_notes.len() + chord.backspin.is_some() as usize;
+            note_times.extend(std::iter::repeat_n(time, notes));
+
+            if !chord.lanes.is_empty() {
+                *report.chord_sizes.entry(chord.lanes.len()).or_default() += 1;
+            }
+            for &lane in chord.lanes.iter() {
+                report.lane_usage[side_idx * lanes_per_side + lane as usize] += 1;
+            }
+        }
+
+        count_jacks(side, lanes_per_side, &mut report.jacks);
+        count_trills_and_stairs(side, &mut report.trills, &mut report.stairs);
+    }
+
+    if duration > 0.0 {
+        report.average_density = report.notes as f32 / duration;
+    }
+
+    let bar_starts: Vec<_> = (0..=timing.bar_lengths.len())
+        .map(|bar_idx| timing.seconds_at(bar_idx, 0))
+        .collect();
+    report.bar_densities = bar_starts
+        .windows(2)
+        .map(|bar| {
+            let notes = note_times
+                .iter()
+                .filter(|&&time| bar[0] <= time && time < bar[1])
+                .count();
+            notes as f32 / (bar[1] - bar[0])
+        })
+        .collect();
+
+    note_times.sort_by(f32::total_cmp);
+    report.peak_densities = PEAK_DENSITY_WINDOWS
+        .iter()
+        .map(|&window| peak_density(&note_times, window))
+        .collect();
+
+    report
+}
+
+pub fn analyze_chart(chart: &Chart) -> ChartReport {
+    let timing = Timing {
+        bpm: chart.bpm,
+        tempo_map: &chart.tempo_map,
+        bar_lengths: chart.bar_lengths(),
+    };
+    let side = chart
+        .bars
+        .iter()
+        .enumerate()
+        .flat_map(|(bar_idx, bar)| {
+            let timing = &timing;
+            bar.iter()
+                .enumerate()
+                .map(move |(i, chord)| (timing.seconds_at(bar_idx, i), chord))
+        })
+        .collect();
+
+    analyze(timing, chart.key_mode.lanes(), vec![side], chart.duration())
+}
+
+pub fn analyze_chart_dp(chart: &ChartDp) -> ChartReport {
+    let timing = Timing {
+        bpm: chart.bpm,
+        tempo_map: &chart.tempo_map,
+        bar_lengths: chart.bar_lengths(),
+    };
+    let sides = (0..2)
+        .map(|side| {
+            chart
+                .bars
+                .iter()
+                .enumerate()
+                .flat_map(|(bar_idx, bar)| {
+                    let timing = &timing;
+                    bar.iter()
+                        .enumerate()
+                        .map(move |(i, chords)| (timing.seconds_at(bar_idx, i), &chords[side]))
+                })
+                .collect()
+        })
+        .collect();
+
+    analyze(timing, chart.key_mode.lanes(), sides, chart.duration())
+}
+
+fn fmt_histogram(
+    f: &mut fmt::Formatter,
+    name: &str,
+    histogram: &BTreeMap<usize, usize>,
+) -> fmt::Result {
+    write!(f, "{name}:")?;
+    if histogram.is_empty() {
+        write!(f, " なし")?;
+    }
+    for (length, count) in histogram {
+        write!(f, " {length}個×{count}")?;
+    }
+    writeln!(f)
+}
+
+impl fmt::Display for ChartReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "ノーツ数: {} (皿: {}, LN: {})",
+            self.notes, self.scratches, self.long_notes
+        )?;
+        writeln!(
+            f,
+            "演奏時間: {:.2} 秒, 平均密度: {:.2} notes/s",
+            self.duration, self.average_density
+        )?;
+        for peak in self.peak_densities.iter() {
+            writeln!(
+                f,
+                "最大密度 ({} 秒): {:.2} notes/s ({:.2} 秒地点)",
+                peak.window, peak.density, peak.position
+            )?;
+        }
+
+        writeln!(f, "小節ごとの密度:")?;
+        let max_density = self.bar_densities.iter().copied().fold(0.0, f32::max);
+        for (bar_idx, density) in self.bar_densities.iter().enumerate() {
+            let width = if max_density > 0.0 {
+                (density / max_density * 40.0).round() as usize
+            } else {
+                0
+            };
+            writeln!(
+                f,
+                "{:>4} {:>6.2} {}",
+                bar_idx + 1,
+                density,
+                "#".repeat(width)
+            )?;
+        }
+
+        fmt_histogram(f, "縦連", &self.jacks)?;
+        fmt_histogram(f, "トリル", &self.trills)?;
+        fmt_histogram(f, "階段", &self.stairs)?;
+        fmt_histogram(f, "同時押し", &self.chord_sizes)?;
+
+        write!(f, "レーンごとのノーツ数:")?;
+        for count in self.lane_usage.iter() {
+            write!(f, " {count}")?;
+        }
+        writeln!(f)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::analyze_chart;
+    use crate::generate::{Chart, Chord, LongNote};
+    use crate::key_mode::KeyMode;
+    use crate::tempo::TempoMap;
+    use approx::assert_relative_eq;
+
+    fn chord(lanes: &[u8]) -> Chord {
+        Chord::new(lanes.to_vec(), false)
+    }
+
+    #[test]
+    fn test_analyze_chart() {
+        let mut bar = vec![
+            Chord {
+                scratch: true,
+                long_notes: vec![LongNote { lane: 0, length: 2 }],
+                ..chord(&[0])
+            },
+            chord(&[0]),
+            chord(&[0]),
+            chord(&[1]),
+            chord(&[2]),
+            chord(&[3]),
+            chord(&[]),
+            chord(&[2]),
+            chord(&[3]),
+            chord(&[2]),
+            chord(&[3]),
+            chord(&[0, 6]),
+        ];
+        bar.resize(16, chord(&[]));
+        let chart = Chart {
+            bpm: 240.0,
+            key_mode: KeyMode::Beat7,
+            tempo_map: TempoMap::new(),
+            bars: vec![bar, vec![chord(&[]); 16]],
+        };
+
+        let report = analyze_chart(&chart);
+
+        assert_eq!(report.notes, 13);
+        assert_eq!(report.scratches, 1);
+        assert_eq!(report.long_notes, 1);
+        assert_relative_eq!(report.duration, 2.0);
+        assert_eq!(report.bar_densities, vec![13.0, 0.0]);
+        assert_relative_eq!(report.peak_densities[0].density, 13.0);
+        assert_relative_eq!(report.peak_densities[2].density, 3.25);
+        assert_eq!(report.jacks.into_iter().collect::<Vec<_>>(), vec![(3, 1)]);
+        assert_eq!(report.trills.into_iter().collect::<Vec<_>>(), vec![(6, 1)]);
+        assert_eq!(report.stairs.into_iter().collect::<Vec<_>>(), vec![(4, 1)]);
+        assert_eq!(
+            report.chord_sizes.into_iter().collect::<Vec<_>>(),
+            vec![(1, 10), (2, 1)]
+        );
+        assert_eq!(report.lane_usage, vec![4, 1, 3, 3, 0, 0, 1]);
+    }
+}
@@ -1,25 +1,305 @@
-use bms_writer::BmsWriter;
+use bms_writer::{
+    BmsError, BmsWriter, BPM_CHANNEL, EXTENDED_BPM_CHANNEL, LONG_NOTE_CHANNEL_OFFSET, STOP_CHANNEL,
+};
+pub use bms_writer::{IndexBase, JudgeRank, Metadata, Player};
 
-use crate::generate::{Chart, ChartDp, LANES};
+use crate::bmson::{chart_dp_to_bmson, chart_to_bmson};
+use crate::difficulty::{estimate_chart, estimate_chart_dp, Level};
+use crate::generate::{Chart, ChartDp, Chord, LongNote};
+use crate::key_mode::KeyMode;
 use crate::keysound::KeySound;
+use crate::tempo::TempoMap;
+use std::collections::BTreeMap;
 use std::io::Write;
 
-static LANE_MAPPING: [u8; 8] = [11, 12, 13, 14, 15, 18, 19, 16];
+/// How long notes are written
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LongNoteMode {
+    /// Start and end objects in the long note channels (51-59, 61-69)
+    Channel,
+    /// End objects in the key channels, referring to `#LNOBJ`
+    LnObj,
+}
+
+/// Format of a written chart
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ChartFormat {
+    Bms(LongNoteMode),
+    Bmson,
+}
+
+/// SP and DP charts, so that generated charts of both are written by the same code
+pub trait BmsChart {
+    fn notes(&self) -> usize;
+    fn duration(&self) -> f32;
+    fn write(
+        &self,
+        buf: impl Write,
+        format: ChartFormat,
+        metadata: &Metadata,
+        total: f32,
+        keysounds: &mut impl KeySound,
+    ) -> Result<(), BmsError>;
+}
+
+impl BmsChart for Chart {
+    fn notes(&self) -> usize {
+        Chart::notes(self)
+    }
+
+    fn duration(&self) -> f32 {
+        Chart::duration(self)
+    }
+
+    fn write(
+        &self,
+        buf: impl Write,
+        format: ChartFormat,
+        metadata: &Metadata,
+        total: f32,
+        keysounds: &mut impl KeySound,
+    ) -> Result<(), BmsError> {
+        match format {
+            ChartFormat::Bms(long_note_mode) => chart_to_bms(
+                buf,
+                self,
+                metadata,
+                total,
+                long_note_mode,
+                IndexBase::Base36,
+                keysounds,
+            ),
+            ChartFormat::Bmson => chart_to_bmson(buf, self, metadata, total, keysounds),
+        }
+    }
+}
+
+impl BmsChart for ChartDp {
+    fn notes(&self) -> usize {
+        ChartDp::notes(self)
+    }
+
+    fn duration(&self) -> f32 {
+        ChartDp::duration(self)
+    }
+
+    fn write(
+        &self,
+        buf: impl Write,
+        format: ChartFormat,
+        metadata: &Metadata,
+        total: f32,
+        keysounds: &mut impl KeySound,
+    ) -> Result<(), BmsError> {
+        match format {
+            ChartFormat::Bms(long_note_mode) => chart_dp_to_bms(
+                buf,
+                self,
+                metadata,
+                total,
+                long_note_mode,
+                IndexBase::Base36,
+                keysounds,
+            ),
+            ChartFormat::Bmson => chart_dp_to_bmson(buf, self, metadata, total, keysounds),
+        }
+    }
+}
+
+/// #TOTAL and #COMMENT of a chart of `notes` notes lasting `duration` seconds.
+/// `jack_and_scatter` is `None` for charts which do not use them, such as ones sampled from a model.
+pub fn chart_header(
+    notes: usize,
+    duration: f32,
+    jack_and_scatter: Option<(f32, f32)>,
+    seed: u64,
+) -> (f32, String) {
+    let total = f32::max(1000.0 - 1000000.0 / (1000.0 + notes as f32), 250.0);
+    let density = notes as f32 / duration;
+    let comment = match jack_and_scatter {
+        Some((jack_tolerance, scatter)) => format!(
+            "密度: {density:.02} notes/s, jacks: {jack_tolerance:.01}, scatter: {scatter:.01}, seed: {seed:?}"
+        ),
+        None => format!("密度: {density:.02} notes/s, seed: {seed:?}"),
+    };
+    (total, comment)
+}
+
+/// Writes a generated chart with #TOTAL and #COMMENT of `chart_header`. `metadata` makes the
+/// headers from the comment. Indices are written in base 36.
+pub fn write_generated_chart(
+    buf: impl Write,
+    chart: &impl BmsChart,
+    format: ChartFormat,
+    jack_and_scatter: Option<(f32, f32)>,
+    seed: u64,
+    metadata: impl FnOnce(String) -> Metadata,
+    keysounds: &mut impl KeySound,
+) -> Result<(), BmsError> {
+    let (total, comment) = chart_header(chart.notes(), chart.duration(), jack_and_scatter, seed);
+    chart.write(buf, format, &metadata(comment), total, keysounds)
+}
+
+/// Objects of key and scratch lanes, placed across bars so that long notes can end in later bars
+struct LaneObjects {
+    bar_lengths: Vec<usize>,
+    notes: Vec<Vec<Vec<Option<usize>>>>,
+    long_notes: Vec<Vec<Vec<Option<usize>>>>,
+    mode: LongNoteMode,
+    lnobj: usize,
+}
+
+impl LaneObjects {
+    fn new(bar_lengths: &[usize], lanes: usize, mode: LongNoteMode, lnobj: usize) -> Self {
+        let empty: Vec<_> = bar_lengths
+            .iter()
+            .map(|&length| vec![vec![None; length]; lanes])
+            .collect();
+
+        LaneObjects {
+            bar_lengths: bar_lengths.to_vec(),
+            notes: empty.clone(),
+            long_notes: empty,
+            mode,
+            lnobj,
+        }
+    }
+
+    fn end_position(&self, mut bar_idx: usize, mut chord_pos: usize) -> Option<(usize, usize)> {
+        while chord_pos >= *self.bar_lengths.get(bar_idx)? {
+            chord_pos -= self.bar_lengths[bar_idx];
+            bar_idx += 1;
+        }
+
+        Some((bar_idx, chord_pos))
+    }
+
+    fn push(
+        &mut self,
+        bar_idx: usize,
+        chord_pos: usize,
+        lane: usize,
+        sound_idx: usize,
+        length: Option<usize>,
+    ) {
+        let end = length.and_then(|length| self.end_position(bar_idx, chord_pos + length));
+        let Some((end_bar_idx, end_chord_pos)) = end else {
+            self.notes[bar_idx][lane][chord_pos] = Some(sound_idx);
+            return;
+        };
+
+        match self.mode {
+            LongNoteMode::Channel => {
+                self.long_notes[bar_idx][lane][chord_pos] = Some(sound_idx);
+                self.long_notes[end_bar_idx][lane][end_chord_pos] = Some(sound_idx);
+            }
+            LongNoteMode::LnObj => {
+                self.notes[bar_idx][lane][chord_pos] = Some(sound_idx);
+                self.notes[end_bar_idx][lane][end_chord_pos] = Some(self.lnobj);
+            }
+        }
+    }
+
+    fn push_channels(self, bms: &mut BmsWriter, channels: &[u8]) {
+        for (bar_idx, (notes, long_notes)) in
+            self.notes.into_iter().zip(self.long_notes).enumerate()
+        {
+            for (lane, &channel) in notes.into_iter().zip(channels) {
+                bms.push_channel(bar_idx, channel, lane);
+            }
+
+            for (lane, &channel) in long_notes.into_iter().zip(channels) {
+                if lane.iter().any(Option::is_some) {
+                    bms.push_channel(bar_idx, channel + LONG_NOTE_CHANNEL_OFFSET, lane);
+                }
+            }
+        }
+    }
+}
+
+/// Fills #PLAYLEVEL and #DIFFICULTY which are not given with the estimated level
+fn set_metadata(bms: &mut BmsWriter, metadata: &Metadata, level: Level) {
+    let mut metadata = metadata.clone();
+    metadata.playlevel.get_or_insert(level.playlevel());
+    metadata.difficulty.get_or_insert(level.difficulty());
+    bms.set_metadata(metadata);
+}
+
+fn push_tempo_map(bms: &mut BmsWriter, tempo_map: &TempoMap, bar_lengths: &[usize]) {
+    let mut rows: BTreeMap<(usize, u8), Vec<Option<usize>>> = BTreeMap::new();
+    let mut push_object = |bar: usize, chord_pos: usize, channel: u8, object: usize| {
+        let Some(&bar_length) = bar_lengths.get(bar) else {
+            return;
+        };
+        if chord_pos < bar_length {
+            rows.entry((bar, channel))
+                .or_insert_with(|| vec![None; bar_length])[chord_pos] = Some(object);
+        }
+    };
+
+    let mut extended_bpms: Vec<f32> = Vec::new();
+    for change in tempo_map.bpm_changes.iter() {
+        if change.bpm.fract() == 0.0 && (1.0..=255.0).contains(&change.bpm) {
+            push_object(
+                change.bar,
+                change.chord_pos,
+                BPM_CHANNEL,
+                change.bpm as usize,
+            );
+            continue;
+        }
+
+        let idx = extended_bpms
+            .iter()
+            .position(|&bpm| bpm == change.bpm)
+            .unwrap_or_else(|| {
+                extended_bpms.push(change.bpm);
+                extended_bpms.len() - 1
+            });
+        push_object(change.bar, change.chord_pos, EXTENDED_BPM_CHANNEL, idx);
+    }
+
+    let mut stop_lengths: Vec<u32> = Vec::new();
+    for stop in tempo_map.stops.iter() {
+        let idx = stop_lengths
+            .iter()
+            .position(|&length| length == stop.length)
+            .unwrap_or_else(|| {
+                stop_lengths.push(stop.length);
+                stop_lengths.len() - 1
+            });
+        push_object(stop.bar, stop.chord_pos, STOP_CHANNEL, idx);
+    }
+
+    for (idx, bpm) in extended_bpms.into_iter().enumerate() {
+        bms.set_bpm_definition(idx, bpm);
+    }
+
+    for (idx, length) in stop_lengths.into_iter().enumerate() {
+        bms.set_stop_definition(idx, length);
+    }
+
+    for ((bar, channel), row) in rows {
+        bms.push_channel(bar, channel, row);
+    }
+}
 
+/// Writes the chart as BMS. #PLAYLEVEL and #DIFFICULTY are estimated unless `metadata` has them.
+///
+/// `IndexBase::Base62` fits more keysounds, but not every player supports `#BASE 62`.
 pub fn chart_to_bms(
     mut buf: impl Write,
     chart: &Chart,
-    title: &str,
-    genre: &str,
-    artist: &str,
+    metadata: &Metadata,
     total: f32,
+    long_note_mode: LongNoteMode,
+    base: IndexBase,
     keysounds: &mut impl KeySound,
-) -> std::io::Result<()> {
+) -> Result<(), BmsError> {
     let mut bms = BmsWriter::new();
+    bms.set_base(base);
 
-    bms.set_title(title);
-    bms.set_genre(genre);
-    bms.set_artist(artist);
+    set_metadata(&mut bms, metadata, estimate_chart(chart).level);
     bms.set_bpm(chart.bpm);
     bms.set_total(total);
 
@@ -27,52 +307,69 @@ pub fn chart_to_bms(
         bms.set_keysound(i, source.name());
     }
 
-    for (bar_idx, bar) in chart.bars.iter().enumerate().take(999) {
-        let mut lanes = vec![vec![None; bar.len()]; LANES];
+    // The first index which no keysound uses
+    let lnobj = keysounds.sources().len();
+    if long_note_mode == LongNoteMode::LnObj {
+        if lnobj > base.max_index() {
+            return Err(BmsError::IndexOverflow { index: lnobj, base });
+        }
+        bms.set_lnobj(lnobj);
+    }
+
+    let bar_lengths: Vec<_> = chart.bar_lengths().into_iter().collect();
+    push_tempo_map(&mut bms, &chart.tempo_map, &bar_lengths);
+
+    // The scratch lane follows the keys
+    let mut channels = chart.key_mode.key_channels().to_vec();
+    channels.extend(chart.key_mode.scratch_channel());
+    let scratch_lane = chart.key_mode.lanes();
+    let mut lanes = LaneObjects::new(&bar_lengths, channels.len(), long_note_mode, lnobj);
+
+    for (bar_idx, bar) in chart.bars.iter().enumerate() {
+        keysounds.begin_bar(bar_idx, bar.len());
+
         for (i, chord) in bar.iter().enumerate() {
             for (j, lane) in chord.lanes.iter().copied().enumerate() {
-                lanes[lane as usize][i] = Some(keysounds.key_sound_idx(bar_idx, i, j));
+                let sound_idx = keysounds.key_sound_idx(bar_idx, i, j);
+                let length = chord.long_note_length(lane);
+                lanes.push(bar_idx, i, lane as usize, sound_idx, length);
             }
         }
 
-        for (lane_idx, lane) in lanes.into_iter().enumerate() {
-            bms.push_channel(bar_idx, LANE_MAPPING[lane_idx], lane);
+        if chart.key_mode.has_scratch() {
+            for (i, chord) in bar.iter().enumerate() {
+                if chord.scratch {
+                    let sound_idx = keysounds.scratch_sound_idx(bar_idx, i);
+                    lanes.push(bar_idx, i, scratch_lane, sound_idx, chord.backspin);
+                }
+            }
         }
 
-        let scratch: Vec<_> = bar
-            .iter()
-            .enumerate()
-            .map(|(i, chord)| {
-                chord
-                    .scratch
-                    .then(|| keysounds.scratch_sound_idx(bar_idx, i))
-            })
-            .collect();
-
-        bms.push_channel(bar_idx, LANE_MAPPING[7], scratch);
-
         for bgm_lane in keysounds.bgm_sound_indices(bar_idx).into_iter() {
             bms.push_channel(bar_idx, 1, bgm_lane);
         }
     }
 
+    lanes.push_channels(&mut bms, &channels);
+
     bms.write(&mut buf)
 }
 
+/// Writes the DP chart as BMS with #PLAYER 3, estimating the level like `chart_to_bms`
 pub fn chart_dp_to_bms(
     mut buf: impl Write,
     chart: &ChartDp,
-    title: &str,
-    genre: &str,
-    artist: &str,
+    metadata: &Metadata,
     total: f32,
+    long_note_mode: LongNoteMode,
+    base: IndexBase,
     keysounds: &mut impl KeySound,
-) -> std::io::Result<()> {
+) -> Result<(), BmsError> {
     let mut bms = BmsWriter::new();
+    bms.set_base(base);
 
-    bms.set_title(title);
-    bms.set_genre(genre);
-    bms.set_artist(artist);
+    set_metadata(&mut bms, metadata, estimate_chart_dp(chart).level);
+    bms.metadata_mut().player = Player::Double;
     bms.set_bpm(chart.bpm);
     bms.set_total(total);
 
@@ -80,37 +377,56 @@ pub fn chart_dp_to_bms(
         bms.set_keysound(i, source.name());
     }
 
-    for (bar_idx, bar) in chart.bars.iter().enumerate().take(999) {
-        let mut sides = [
-            vec![vec![None; bar.len()]; LANES],
-            vec![vec![None; bar.len()]; LANES],
-        ];
+    // The first index which no keysound uses
+    let lnobj = keysounds.sources().len();
+    if long_note_mode == LongNoteMode::LnObj {
+        if lnobj > base.max_index() {
+            return Err(BmsError::IndexOverflow { index: lnobj, base });
+        }
+        bms.set_lnobj(lnobj);
+    }
+
+    let bar_lengths: Vec<_> = chart.bar_lengths().into_iter().collect();
+    push_tempo_map(&mut bms, &chart.tempo_map, &bar_lengths);
+
+    // Each side has its keys followed by its scratch lane
+    let mut side_channels = chart.key_mode.key_channels().to_vec();
+    side_channels.extend(chart.key_mode.scratch_channel());
+    let lanes_per_side = chart.key_mode.lanes();
+    let channels: Vec<_> = (0..2)
+        .flat_map(|side| {
+            side_channels
+                .iter()
+                .map(move |&channel| channel + side * 10)
+        })
+        .collect();
+    let mut lanes = LaneObjects::new(&bar_lengths, channels.len(), long_note_mode, lnobj);
+
+    for (bar_idx, bar) in chart.bars.iter().enumerate() {
+        keysounds.begin_bar(bar_idx, bar.len());
+
         for (i, chords) in bar.iter().enumerate() {
             for (side, chord) in chords.iter().enumerate() {
                 for (j, lane) in chord.lanes.iter().copied().enumerate() {
-                    sides[side][lane as usize][i] = Some(keysounds.key_sound_idx(bar_idx, i, j));
+                    let sound_idx = keysounds.key_sound_idx(bar_idx, i, j);
+                    let length = chord.long_note_length(lane);
+                    let lane = side * side_channels.len() + lane as usize;
+                    lanes.push(bar_idx, i, lane, sound_idx, length);
                 }
             }
         }
 
-        for (side, lanes) in sides.into_iter().enumerate() {
-            for (lane_idx, lane) in lanes.into_iter().enumerate() {
-                bms.push_channel(bar_idx, LANE_MAPPING[lane_idx] + side as u8 * 10, lane);
-            }
-        }
-
         for side in 0..2 {
-            let scratch: Vec<_> = bar
-                .iter()
-                .enumerate()
-                .map(|(i, chords)| {
-                    chords[side]
-                        .scratch
-                        .then(|| keysounds.scratch_sound_idx(bar_idx, i))
-                })
-                .collect();
-
-            bms.push_channel(bar_idx, LANE_MAPPING[7] + side as u8 * 10, scratch);
+            if !chart.key_mode.has_scratch() {
+                break;
+            }
+            for (i, chords) in bar.iter().enumerate() {
+                if chords[side].scratch {
+                    let sound_idx = keysounds.scratch_sound_idx(bar_idx, i);
+                    let lane = side * side_channels.len() + lanes_per_side;
+                    lanes.push(bar_idx, i, lane, sound_idx, chords[side].backspin);
+                }
+            }
         }
 
         for bgm_lane in keysounds.bgm_sound_indices(bar_idx).into_iter() {
@@ -118,5 +434,225 @@ pub fn chart_dp_to_bms(
         }
     }
 
+    lanes.push_channels(&mut bms, &channels);
+
     bms.write(&mut buf)
 }
+
+/// Reads the 1P side of a parsed BMS as a chart on a grid of `chords_per_bar` chords per measure,
+/// e.g. to apply lane options to it. Objects are moved to the nearest chord of the grid, and
+/// measure 0, which `chart_to_bms` leaves empty, is skipped unless it has notes.
+/// Keysounds, BGM and measure lengths are not read.
+pub fn chart_from_bms(bms: &BmsWriter, key_mode: KeyMode, chords_per_bar: usize) -> Chart {
+    assert!(chords_per_bar > 0);
+    // Lanes of the channels, where the scratch is `None`
+    let channels: Vec<_> = key_mode
+        .key_channels()
+        .iter()
+        .enumerate()
+        .map(|(lane, &channel)| (channel, Some(lane as u8)))
+        .chain(key_mode.scratch_channel().map(|channel| (channel, None)))
+        .collect();
+    let has_notes_in_measure_0 = channels.iter().any(|&(channel, _)| {
+        [channel, channel + LONG_NOTE_CHANNEL_OFFSET]
+            .into_iter()
+            .flat_map(|channel| bms.channel(channel))
+            .any(|(measure, row)| measure == 0 && row.iter().any(Option::is_some))
+    });
+    let first_measure = if has_notes_in_measure_0 { 0 } else { 1 };
+    let objects = |channel: u8| {
+        bms.channel(channel).flat_map(move |(measure, row)| {
+            let row_length = row.len();
+            row.into_iter().enumerate().filter_map(move |(i, object)| {
+                let bar = measure.checked_sub(first_measure)?;
+                let chord_pos = (i * chords_per_bar + row_length / 2) / row_length;
+                Some((bar * chords_per_bar + chord_pos, object?))
+            })
+        })
+    };
+
+    // Lengths of long notes by the position and the lane of their starts
+    let mut notes: BTreeMap<(usize, Option<u8>), Option<usize>> = BTreeMap::new();
+    for &(channel, lane) in channels.iter() {
+        let mut lane_objects: Vec<_> = objects(channel)
+            .map(|(position, object)| (position, Some(object) == bms.lnobj()))
+            .collect();
+        lane_objects.sort_unstable();
+        let mut last_start = None;
+        for (position, ends_long_note) in lane_objects {
+            if !ends_long_note {
+                notes.insert((position, lane), None);
+                last_start = Some(position);
+            } else if let Some(start) = last_start.take().filter(|&start| start < position) {
+                notes.insert((start, lane), Some(position - start));
+            }
+        }
+
+        // Objects of long note channels alternate between starts and ends
+        let mut long_note_objects: Vec<_> = objects(channel + LONG_NOTE_CHANNEL_OFFSET)
+            .map(|(position, _)| position)
+            .collect();
+        long_note_objects.sort_unstable();
+        for pair in long_note_objects.chunks(2) {
+            let length = match *pair {
+                [start, end] if start < end => Some(end - start),
+                _ => None,
+            };
+            notes.insert((pair[0], lane), length);
+        }
+    }
+
+    let mut tempo_map = TempoMap::new();
+    let to_chord = |position: usize| (position / chords_per_bar, position % chords_per_bar);
+    for (position, bpm) in objects(BPM_CHANNEL) {
+        let (bar, chord_pos) = to_chord(position);
+        tempo_map.push_bpm_change(bar, chord_pos, bpm as f32);
+    }
+    for (position, idx) in objects(EXTENDED_BPM_CHANNEL) {
+        if let Some(bpm) = bms.bpm_definition(idx) {
+            let (bar, chord_pos) = to_chord(position);
+            tempo_map.push_bpm_change(bar, chord_pos, bpm);
+        }
+    }
+    for (position, idx) in objects(STOP_CHANNEL) {
+        if let Some(length) = bms.stop_definition(idx) {
+            let (bar, chord_pos) = to_chord(position);
+            tempo_map.push_stop(bar, chord_pos, length);
+        }
+    }
+    tempo_map
+        .bpm_changes
+        .sort_by_key(|change| (change.bar, change.chord_pos));
+    tempo_map
+        .stops
+        .sort_by_key(|stop| (stop.bar, stop.chord_pos));
+
+    let bars = notes
+        .keys()
+        .map(|&(position, _)| position / chords_per_bar + 1)
+        .max()
+        .unwrap_or(0);
+    let empty = Chord::new(Vec::new(), false);
+    let mut chords = vec![empty; bars * chords_per_bar];
+    for ((position, lane), length) in notes {
+        let chord = &mut chords[position];
+        match lane {
+            Some(lane) => {
+                chord.lanes.push(lane);
+                if let Some(length) = length {
+                    chord.long_notes.push(LongNote { lane, length });
+                }
+            }
+            None => {
+                chord.scratch = true;
+                chord.backspin = length;
+            }
+        }
+    }
+
+    Chart {
+        bpm: bms.bpm(),
+        key_mode,
+        tempo_map,
+        bars: chords
+            .chunks(chords_per_bar)
+            .map(|bar| bar.to_vec())
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{chart_from_bms, chart_to_bms, IndexBase, LongNoteMode, Metadata};
+    use crate::{
+        chord::ChordDensity,
+        config::GeneratorConfig,
+        generate::{
+            generate_chart, ChartParams, LongNoteParams, NotesParams, Scatter, ScratchParams,
+            DEFAULT_CHORDS_PER_BAR,
+        },
+        key_mode::KeyMode,
+        keysound::{ChordKeySound, KeySound},
+        tempo::TempoMap,
+    };
+    use bms_writer::BmsWriter;
+
+    #[test]
+    fn test_lnobj() {
+        let chart_params = ChartParams::new(150.0, 16, 199024);
+        let mut notes_params = NotesParams::new(
+            ChordDensity::new(vec![vec![200]]),
+            0.0,
+            Scatter::new(0.0, 0.0, false),
+        );
+        notes_params.set_long_notes(LongNoteParams::new(0.5, 1, 8));
+        let chart = generate_chart(&chart_params, &notes_params);
+        let mut keysounds = ChordKeySound::new(GeneratorConfig::default().chord_progression());
+
+        let mut buf = Vec::new();
+        chart_to_bms(
+            &mut buf,
+            &chart,
+            &Metadata::default(),
+            300.0,
+            LongNoteMode::LnObj,
+            IndexBase::Base36,
+            &mut keysounds,
+        )
+        .unwrap();
+
+        // 53 keysounds take 01 to 1H, followed by the end of long notes
+        let text = String::from_utf8_lossy(&buf);
+        assert_eq!(keysounds.sources().len(), 53);
+        assert!(text.contains("#WAV1H "));
+        assert!(!text.contains("#WAV1I "));
+        assert!(text.lines().any(|line| line == "#LNOBJ 1I"));
+        assert_eq!(BmsWriter::parse(&buf).lnobj(), Some(53));
+    }
+
+    #[test]
+    fn test_chart_from_bms() {
+        let mut chart_params = ChartParams::new(150.0, 16, 199024);
+        chart_params.set_tempo_map(TempoMap::gradual(150.0, 187.5, 16, 4));
+        let mut notes_params = NotesParams::new(
+            ChordDensity::new(vec![vec![200]]),
+            0.0,
+            Scatter::new(0.0, 0.0, false),
+        );
+        notes_params.set_long_notes(LongNoteParams::new(0.3, 1, 8));
+        let mut scratch_params = ScratchParams::new(ChordDensity::new(vec![vec![25]]));
+        scratch_params.set_backspin(LongNoteParams::new(0.5, 1, 4));
+        notes_params.set_scratch(scratch_params);
+        let mut chart = generate_chart(&chart_params, &notes_params);
+        for chord in chart.bars.iter_mut().flatten() {
+            chord.long_notes.sort_by_key(|long_note| long_note.lane);
+        }
+        let chords: Vec<_> = chart.bars.iter().flatten().collect();
+        assert!(chords.iter().any(|chord| !chord.long_notes.is_empty()));
+        assert!(chords.iter().any(|chord| chord.backspin.is_some()));
+
+        for long_note_mode in [LongNoteMode::Channel, LongNoteMode::LnObj] {
+            let mut keysounds = ChordKeySound::new(GeneratorConfig::default().chord_progression());
+            let mut buf = Vec::new();
+            chart_to_bms(
+                &mut buf,
+                &chart,
+                &Metadata::default(),
+                300.0,
+                long_note_mode,
+                IndexBase::Base36,
+                &mut keysounds,
+            )
+            .unwrap();
+
+            let parsed = chart_from_bms(
+                &BmsWriter::parse(&buf),
+                KeyMode::Beat7,
+                DEFAULT_CHORDS_PER_BAR,
+            );
+            assert_eq!(parsed.bpm, chart.bpm);
+            assert_eq!(parsed.tempo_map, chart.tempo_map);
+            assert_eq!(parsed.bars, chart.bars);
+        }
+    }
+}
@@ -0,0 +1,486 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+
+use serde::{Deserialize, Serialize};
+
+use bms_writer::{lcm, BmsError, JudgeRank, Metadata};
+
+use crate::difficulty::{estimate_chart, estimate_chart_dp, Level};
+use crate::generate::{Chart, ChartDp};
+use crate::key_mode::KeyMode;
+use crate::keysound::KeySound;
+use crate::tempo::TempoMap;
+
+const BMSON_VERSION: &str = "1.0.0";
+const BASE_PULSES_PER_BAR: usize = 960;
+
+#[derive(Serialize)]
+struct BmsonInfo {
+    title: String,
+    subtitle: String,
+    artist: String,
+    subartists: Vec<String>,
+    genre: String,
+    mode_hint: String,
+    chart_name: String,
+    level: u32,
+    init_bpm: f64,
+    judge_rank: f64,
+    total: f64,
+    back_image: String,
+    eyecatch_image: String,
+    banner_image: String,
+    resolution: u64,
+    /// Not in the bmson spec, so players ignore it like `#COMMENT` they don't show
+    #[serde(skip_serializing_if = "String::is_empty")]
+    comment: String,
+    /// Not in the bmson spec. Keeps the comment lines of BMS, such as the arguments of the generation.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    comment_lines: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct BarLine {
+    y: u64,
+}
+
+#[derive(Serialize)]
+struct BpmEvent {
+    y: u64,
+    bpm: f64,
+}
+
+#[derive(Serialize)]
+struct StopEvent {
+    y: u64,
+    duration: u64,
+}
+
+#[derive(Serialize)]
+struct BmsonNote {
+    x: u8,
+    y: u64,
+    l: u64,
+    c: bool,
+}
+
+#[derive(Serialize)]
+struct SoundChannel {
+    name: String,
+    notes: Vec<BmsonNote>,
+}
+
+#[derive(Serialize)]
+struct Bmson {
+    version: String,
+    info: BmsonInfo,
+    lines: Vec<BarLine>,
+    bpm_events: Vec<BpmEvent>,
+    stop_events: Vec<StopEvent>,
+    sound_channels: Vec<SoundChannel>,
+}
+
+/// Places notes on the pulse grid of bmson. Like BMS, the chart starts after one bar of lead-in.
+struct BmsonBuilder {
+    pulses_per_bar: u64,
+    bar_lengths: Vec<usize>,
+    sound_names: Vec<String>,
+    notes: BTreeMap<String, Vec<BmsonNote>>,
+}
+
+impl BmsonBuilder {
+    fn new(bar_lengths: Vec<usize>, sound_names: Vec<String>) -> Self {
+        let pulses_per_bar = bar_lengths
+            .iter()
+            .fold(BASE_PULSES_PER_BAR, |acc, &length| lcm(acc, length.max(1)))
+            as u64;
+        BmsonBuilder {
+            pulses_per_bar,
+            bar_lengths,
+            sound_names,
+            notes: BTreeMap::new(),
+        }
+    }
+
+    fn y(&self, bar_idx: usize, chord_pos: usize) -> u64 {
+        let bar_length = self.bar_lengths.get(bar_idx).copied().unwrap_or(1).max(1);
+        (bar_idx as u64 + 1) * self.pulses_per_bar
+            + chord_pos as u64 * self.pulses_per_bar / bar_length as u64
+    }
+
+    /// Returns the length in pulses of `chords` chords from the position.
+    fn length(&self, mut bar_idx: usize, mut chord_pos: usize, chords: usize) -> u64 {
+        let start = self.y(bar_idx, chord_pos);
+        chord_pos += chords;
+        while bar_idx < self.bar_lengths.len() && chord_pos >= self.bar_lengths[bar_idx] {
+            chord_pos -= self.bar_lengths[bar_idx];
+            bar_idx += 1;
+        }
+        self.y(bar_idx, chord_pos) - start
+    }
+
+    fn push_note(&mut self, sound_idx: usize, x: u8, y: u64, l: u64) {
+        // Sounds out of the sources are silent
+        let name = self.sound_names.get(sound_idx).cloned().unwrap_or_default();
+        self.notes
+            .entry(name)
+            .or_default()
+            .push(BmsonNote { x, y, l, c: false });
+    }
+
+    fn push_bgm(&mut self, bar_idx: usize, bgm_lane: &[Option<usize>]) {
+        for (i, sound_idx) in bgm_lane.iter().enumerate() {
+            if let Some(sound_idx) = *sound_idx {
+                let y = (bar_idx as u64 + 1) * self.pulses_per_bar
+                    + i as u64 * self.pulses_per_bar / bgm_lane.len() as u64;
+                self.push_note(sound_idx, 0, y, 0);
+            }
+        }
+    }
+
+    fn build(self, info: BmsonInfo, tempo_map: &TempoMap) -> Bmson {
+        let lines = (0..=self.bar_lengths.len() + 1)
+            .map(|bar| BarLine {
+                y: bar as u64 * self.pulses_per_bar,
+            })
+            .collect();
+        let bpm_events = tempo_map
+            .bpm_changes
+            .iter()
+            .map(|change| BpmEvent {
+                y: self.y(change.bar, change.chord_pos),
+                bpm: change.bpm as f64,
+            })
+            .collect();
+        let stop_events = tempo_map
+            .stops
+            .iter()
+            .map(|stop| StopEvent {
+                y: self.y(stop.bar, stop.chord_pos),
+                duration: stop.length as u64 * self.pulses_per_bar / 192,
+            })
+            .collect();
+        let sound_channels = self
+            .notes
+            .into_iter()
+            .map(|(name, notes)| SoundChannel { name, notes })
+            .collect();
+
+        Bmson {
+            version: BMSON_VERSION.to_owned(),
+            info: BmsonInfo {
+                resolution: self.pulses_per_bar / 4,
+                ..info
+            },
+            lines,
+            bpm_events,
+            stop_events,
+            sound_channels,
+        }
+    }
+}
+
+fn sound_names(keysounds: &impl KeySound) -> Vec<String> {
+    keysounds
+        .sources()
+        .iter()
+        .map(|source| format!("{}.wav", source.name()))
+        .collect()
+}
+
+fn mode_hint(key_mode: KeyMode, dp: bool) -> &'static str {
+    match (key_mode, dp) {
+        (KeyMode::Beat5, false) => "beat-5k",
+        (KeyMode::Beat5, true) => "beat-10k",
+        (KeyMode::Beat7, false) => "beat-7k",
+        (KeyMode::Beat7, true) => "beat-14k",
+        (KeyMode::PopN9, _) => "popn-9k",
+    }
+}
+
+/// Lane number of bmson. Beat modes place the scratch at 8 and the 2P side from 9, even with 5 keys.
+fn key_x(key_mode: KeyMode, side: usize, lane: u8) -> u8 {
+    match key_mode {
+        KeyMode::PopN9 => lane + 1,
+        KeyMode::Beat5 | KeyMode::Beat7 => side as u8 * 8 + lane + 1,
+    }
+}
+
+fn scratch_x(side: usize) -> u8 {
+    side as u8 * 8 + 8
+}
+
+/// Converts TOTAL of BMS into the percentage of bmson, which is relative to the default gauge.
+fn total_percentage(total: f32, notes: usize) -> f64 {
+    let notes = notes as f64;
+    let default_total = 7.605 * notes / (0.01 * notes + 6.5);
+    if default_total > 0.0 {
+        total as f64 / default_total * 100.0
+    } else {
+        100.0
+    }
+}
+
+/// Converts the judge rank into the percentage of bmson, where 100 is as wide as `#RANK 2`.
+fn judge_rank_percentage(rank: JudgeRank) -> f64 {
+    match rank {
+        // Widths of VERY HARD to EASY relative to NORMAL, as players treat #RANK
+        JudgeRank::Rank(rank) => [25.0, 50.0, 75.0, 100.0][rank.min(3) as usize] / 75.0 * 100.0,
+        JudgeRank::DefExRank(percentage) => percentage as f64,
+    }
+}
+
+fn chart_name(difficulty: u32) -> &'static str {
+    match difficulty {
+        1 => "BEGINNER",
+        2 => "NORMAL",
+        3 => "HYPER",
+        4 => "ANOTHER",
+        _ => "INSANE",
+    }
+}
+
+/// Level and difficulty fall back to the estimation like `#PLAYLEVEL` and `#DIFFICULTY` of BMS.
+/// Extra headers have no place in bmson and are dropped.
+fn bmson_info(
+    metadata: &Metadata,
+    mode_hint: &str,
+    level: Level,
+    bpm: f32,
+    total: f64,
+) -> BmsonInfo {
+    let subartists = if metadata.subartist.is_empty() {
+        vec![]
+    } else {
+        vec![metadata.subartist.clone()]
+    };
+    BmsonInfo {
+        title: metadata.title.clone(),
+        subtitle: metadata.subtitle.clone(),
+        artist: metadata.artist.clone(),
+        subartists,
+        genre: metadata.genre.clone(),
+        mode_hint: mode_hint.to_owned(),
+        chart_name: chart_name(metadata.difficulty.unwrap_or(level.difficulty())).to_owned(),
+        level: metadata.playlevel.unwrap_or(level.playlevel()),
+        init_bpm: bpm as f64,
+        judge_rank: judge_rank_percentage(metadata.rank),
+        total,
+        back_image: metadata.backbmp.clone(),
+        eyecatch_image: metadata.stagefile.clone(),
+        banner_image: metadata.banner.clone(),
+        resolution: 0,
+        comment: metadata.comment.clone(),
+        comment_lines: metadata.comment_lines.clone(),
+    }
+}
+
+#[derive(Deserialize)]
+struct CommentLinesInfo {
+    #[serde(default)]
+    comment_lines: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct CommentLinesBmson {
+    info: CommentLinesInfo,
+}
+
+/// Reads the comment lines written by `chart_to_bmson`. Returns `None` unless the input is bmson.
+pub fn comment_lines_from_bmson(input: &[u8]) -> Option<Vec<String>> {
+    serde_json::from_slice::<CommentLinesBmson>(input)
+        .ok()
+        .map(|bmson| bmson.info.comment_lines)
+}
+
+pub fn chart_to_bmson(
+    buf: impl Write,
+    chart: &Chart,
+    metadata: &Metadata,
+    total: f32,
+    keysounds: &mut impl KeySound,
+) -> Result<(), BmsError> {
+    let mut builder = BmsonBuilder::new(chart.bar_lengths(), sound_names(keysounds));
+    let mut notes = 0;
+
+    for (bar_idx, bar) in chart.bars.iter().enumerate() {
+        keysounds.begin_bar(bar_idx, bar.len());
+
+        for (i, chord) in bar.iter().enumerate() {
+            let y = builder.y(bar_idx, i);
+
+            for (j, lane) in chord.lanes.iter().copied().enumerate() {
+                let sound_idx = keysounds.key_sound_idx(bar_idx, i, j);
+                let l = chord
+                    .long_note_length(lane)
+                    .map_or(0, |length| builder.length(bar_idx, i, length));
+                builder.push_note(sound_idx, key_x(chart.key_mode, 0, lane), y, l);
+                notes += 1;
+            }
+        }
+
+        // Sounds are requested in the same order as chart_to_bms
+        for (i, chord) in bar.iter().enumerate() {
+            if chord.scratch {
+                let sound_idx = keysounds.scratch_sound_idx(bar_idx, i);
+                let l = chord
+                    .backspin
+                    .map_or(0, |length| builder.length(bar_idx, i, length));
+                builder.push_note(sound_idx, scratch_x(0), builder.y(bar_idx, i), l);
+                notes += 1;
+            }
+        }
+
+        for bgm_lane in keysounds.bgm_sound_indices(bar_idx) {
+            builder.push_bgm(bar_idx, &bgm_lane);
+        }
+    }
+
+    let info = bmson_info(
+        metadata,
+        mode_hint(chart.key_mode, false),
+        estimate_chart(chart).level,
+        chart.bpm,
+        total_percentage(total, notes),
+    );
+    let bmson = builder.build(info, &chart.tempo_map);
+
+    serde_json::to_writer(buf, &bmson).map_err(std::io::Error::from)?;
+
+    Ok(())
+}
+
+pub fn chart_dp_to_bmson(
+    buf: impl Write,
+    chart: &ChartDp,
+    metadata: &Metadata,
+    total: f32,
+    keysounds: &mut impl KeySound,
+) -> Result<(), BmsError> {
+    let mut builder = BmsonBuilder::new(chart.bar_lengths(), sound_names(keysounds));
+    let mut notes = 0;
+
+    for (bar_idx, bar) in chart.bars.iter().enumerate() {
+        keysounds.begin_bar(bar_idx, bar.len());
+
+        for (i, chords) in bar.iter().enumerate() {
+            let y = builder.y(bar_idx, i);
+
+            for (side, chord) in chords.iter().enumerate() {
+                for (j, lane) in chord.lanes.iter().copied().enumerate() {
+                    let sound_idx = keysounds.key_sound_idx(bar_idx, i, j);
+                    let l = chord
+                        .long_note_length(lane)
+                        .map_or(0, |length| builder.length(bar_idx, i, length));
+                    builder.push_note(sound_idx, key_x(chart.key_mode, side, lane), y, l);
+                    notes += 1;
+                }
+            }
+        }
+
+        for side in 0..2 {
+            for (i, chords) in bar.iter().enumerate() {
+                if chords[side].scratch {
+                    let sound_idx = keysounds.scratch_sound_idx(bar_idx, i);
+                    let l = chords[side]
+                        .backspin
+                        .map_or(0, |length| builder.length(bar_idx, i, length));
+                    builder.push_note(sound_idx, scratch_x(side), builder.y(bar_idx, i), l);
+                    notes += 1;
+                }
+            }
+        }
+
+        for bgm_lane in keysounds.bgm_sound_indices(bar_idx) {
+            builder.push_bgm(bar_idx, &bgm_lane);
+        }
+    }
+
+    let info = bmson_info(
+        metadata,
+        mode_hint(chart.key_mode, true),
+        estimate_chart_dp(chart).level,
+        chart.bpm,
+        total_percentage(total, notes),
+    );
+    let bmson = builder.build(info, &chart.tempo_map);
+
+    serde_json::to_writer(buf, &bmson).map_err(std::io::Error::from)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        bmson_info, comment_lines_from_bmson, judge_rank_percentage, total_percentage, BmsonBuilder,
+    };
+    use crate::difficulty::Level;
+    use approx::assert_relative_eq;
+    use bms_writer::{JudgeRank, Metadata};
+
+    #[test]
+    fn test_builder_positions() {
+        let builder = BmsonBuilder::new(vec![16, 12, 7], vec![]);
+
+        assert_eq!(builder.pulses_per_bar, 6720);
+        assert_eq!(builder.y(0, 0), 6720);
+        assert_eq!(builder.y(1, 3), 6720 * 2 + 1680);
+        assert_eq!(builder.y(2, 1), 6720 * 3 + 960);
+        assert_eq!(builder.length(0, 12, 6), 1680 + 1120);
+    }
+
+    #[test]
+    fn test_total_percentage() {
+        assert_relative_eq!(
+            total_percentage(7.605 * 1000.0 / 16.5, 1000),
+            100.0,
+            epsilon = 1e-3
+        );
+        assert_relative_eq!(total_percentage(300.0, 0), 100.0);
+    }
+
+    #[test]
+    fn test_judge_rank_percentage() {
+        assert_relative_eq!(judge_rank_percentage(JudgeRank::Rank(2)), 100.0);
+        assert_relative_eq!(judge_rank_percentage(JudgeRank::Rank(3)), 400.0 / 3.0);
+        assert_relative_eq!(judge_rank_percentage(JudgeRank::DefExRank(120)), 120.0);
+    }
+
+    #[test]
+    fn test_bmson_info_metadata() {
+        let metadata = Metadata {
+            title: "title".to_owned(),
+            subartist: "chart: someone".to_owned(),
+            difficulty: Some(4),
+            stagefile: "stage.png".to_owned(),
+            comment: "density 8.0".to_owned(),
+            ..Default::default()
+        };
+        let info = bmson_info(&metadata, "beat-7k", Level::Normal(7), 150.0, 100.0);
+
+        assert_eq!(info.title, "title");
+        assert_eq!(info.subartists, ["chart: someone"]);
+        assert_eq!(info.chart_name, "ANOTHER");
+        assert_eq!(info.level, 7);
+        assert_eq!(info.eyecatch_image, "stage.png");
+        assert_eq!(info.comment, "density 8.0");
+        assert_relative_eq!(info.judge_rank, 400.0 / 3.0);
+    }
+
+    #[test]
+    fn test_comment_lines_roundtrip() {
+        let metadata = Metadata {
+            comment_lines: vec!["*bms-generator --seed=1".to_owned()],
+            ..Default::default()
+        };
+        let info = bmson_info(&metadata, "beat-7k", Level::Normal(7), 150.0, 100.0);
+        let input = serde_json::json!({ "version": "1.0.0", "info": info }).to_string();
+
+        assert_eq!(
+            comment_lines_from_bmson(input.as_bytes()),
+            Some(vec!["*bms-generator --seed=1".to_owned()])
+        );
+        assert_eq!(comment_lines_from_bmson(b"#TITLE test"), None);
+    }
+}
@@ -1,5 +1,17 @@
+use std::cmp::Reverse;
+
+use bms_writer::lcm;
+
 use crate::rng::RNG;
 
+/// Returns the number of chords per bar needed to express all of the subdivisions,
+/// which is the least common multiple of them.
+pub fn chords_per_bar(subdivisions: &[usize]) -> usize {
+    subdivisions
+        .iter()
+        .fold(1, |acc, &subdivision| lcm(acc, subdivision))
+}
+
 #[derive(PartialEq, Debug, Clone)]
 pub struct ChordDensity {
     density_seq: Vec<Vec<u64>>,
@@ -11,14 +23,14 @@ impl ChordDensity {
     }
 
     pub fn from_power_of_two(freq: &[u64]) -> Self {
-        assert!(freq.len() > 0);
+        assert!(!freq.is_empty());
         let density_seq = (0..1 << (freq.len() - 1))
             .map(|i| {
-                freq.into_iter()
+                freq.iter()
                     .rev()
                     .cloned()
                     .enumerate()
-                    .filter(|&(j, _)| (1 << j) - 1 & i == 0)
+                    .filter(|&(j, _)| ((1 << j) - 1) & i == 0)
                     .map(|(_, x)| x)
                     .collect()
             })
@@ -27,10 +39,78 @@ impl ChordDensity {
         Self::new(density_seq)
     }
 
+    /// Builds densities from pairs of a subdivision and its density, where a subdivision `n` means
+    /// n-th notes (e.g. 4 for quarter notes, 12 for triplet 8th notes).
+    /// Every subdivision must divide `chords_per_bar`.
+    pub fn from_subdivisions(subdivisions: &[(usize, u64)], chords_per_bar: usize) -> Self {
+        assert!(
+            subdivisions
+                .iter()
+                .all(|&(subdivision, _)| subdivision > 0
+                    && chords_per_bar.is_multiple_of(subdivision))
+        );
+
+        let mut subdivisions = subdivisions.to_vec();
+        subdivisions.sort_by_key(|&(subdivision, _)| Reverse(subdivision));
+
+        let density_seq = (0..chords_per_bar)
+            .map(|i| {
+                subdivisions
+                    .iter()
+                    .filter(|&&(subdivision, _)| i.is_multiple_of(chords_per_bar / subdivision))
+                    .map(|&(_, freq)| freq)
+                    .collect()
+            })
+            .collect();
+
+        Self::new(density_seq)
+    }
+
+    /// Returns densities multiplied by `factor`, keeping the rhythm.
+    pub fn scaled(&self, factor: f32) -> Self {
+        let density_seq = self
+            .density_seq
+            .iter()
+            .map(|densities| {
+                densities
+                    .iter()
+                    .map(|&density| (density as f32 * factor).round() as u64)
+                    .collect()
+            })
+            .collect();
+
+        Self::new(density_seq)
+    }
+
+    /// Linearly interpolates densities at `t` (0.0 for `self`, 1.0 for `other`), or returns `None`
+    /// if the two have different numbers of chords.
+    pub fn interpolate(&self, other: &Self, t: f32) -> Option<Self> {
+        if self.density_seq.len() != other.density_seq.len() {
+            return None;
+        }
+
+        let density_seq = self
+            .density_seq
+            .iter()
+            .zip(other.density_seq.iter())
+            .map(|(a, b)| {
+                (0..a.len().max(b.len()))
+                    .map(|i| {
+                        let a = a.get(i).copied().unwrap_or(0) as f32;
+                        let b = b.get(i).copied().unwrap_or(0) as f32;
+                        (a + (b - a) * t).round() as u64
+                    })
+                    .collect()
+            })
+            .collect();
+
+        Some(Self::new(density_seq))
+    }
+
     pub fn generate_chord_density(&self, index: usize, rng: &mut RNG) -> u64 {
         let densities = &self.density_seq[index % self.density_seq.len()];
         densities
-            .into_iter()
+            .iter()
             .map(|d| {
                 if d % 100 > rng.next() % 100 {
                     d / 100 + 1
@@ -44,7 +124,7 @@ impl ChordDensity {
 
 #[cfg(test)]
 mod test {
-    use super::ChordDensity;
+    use super::{chords_per_bar, ChordDensity};
     use crate::rng::RNG;
 
     #[test]
@@ -74,6 +154,64 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_from_subdivisions() {
+        let subdivisions: Vec<_> = [1, 2, 4, 8, 16].into_iter().zip([1, 2, 4, 8, 16]).collect();
+        assert_eq!(
+            ChordDensity::from_subdivisions(&subdivisions, 16),
+            ChordDensity::from_power_of_two(&[1, 2, 4, 8, 16]),
+        );
+
+        let density = ChordDensity::from_subdivisions(&[(4, 100), (12, 200), (6, 300)], 12);
+        assert_eq!(
+            density.density_seq,
+            vec![
+                vec![200, 300, 100],
+                vec![200],
+                vec![200, 300],
+                vec![200, 100],
+                vec![200, 300],
+                vec![200],
+                vec![200, 300, 100],
+                vec![200],
+                vec![200, 300],
+                vec![200, 100],
+                vec![200, 300],
+                vec![200],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_interpolate() {
+        let a = ChordDensity::new(vec![vec![100, 100], vec![100]]);
+        let b = ChordDensity::new(vec![vec![300], vec![200, 100]]);
+        assert_eq!(
+            a.interpolate(&b, 0.25),
+            Some(ChordDensity::new(vec![vec![150, 75], vec![125, 25]])),
+        );
+        assert_eq!(
+            a.interpolate(&ChordDensity::new(vec![vec![100]]), 0.5),
+            None
+        );
+    }
+
+    #[test]
+    fn test_scaled() {
+        let density = ChordDensity::new(vec![vec![100, 50], vec![100]]);
+        assert_eq!(
+            density.scaled(1.5),
+            ChordDensity::new(vec![vec![150, 75], vec![150]]),
+        );
+    }
+
+    #[test]
+    fn test_chords_per_bar() {
+        assert_eq!(chords_per_bar(&[1, 2, 4, 8, 16]), 16);
+        assert_eq!(chords_per_bar(&[4, 6, 8]), 24);
+        assert_eq!(chords_per_bar(&[3]), 3);
+    }
+
     #[test]
     fn test_next_chord_count() {
         let density = ChordDensity::from_power_of_two(&[100, 100, 100, 100]);
@@ -0,0 +1,336 @@
+use serde::{Deserialize, Serialize};
+
+use crate::bms::{JudgeRank, Metadata};
+use crate::chord::{chords_per_bar, ChordDensity};
+use crate::generate::{ChartParams, LongNoteParams, NotesParams, Scatter};
+use crate::key_mode::KeyMode;
+use crate::keysound::{parse_chord, ChordRoot, ChordType};
+
+/// Names of the built-in presets
+pub const PRESET_NAMES: [&str; 4] = ["stream", "chords", "jacks", "stamina"];
+
+/// Generation settings which can be saved and shared as TOML. Omitted fields take the default values.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct GeneratorConfig {
+    pub title: String,
+    pub bpm: f32,
+    pub bars: usize,
+    /// Omitted to use a new seed for every chart
+    pub seed: Option<u64>,
+    /// Number of keys of a side (5, 7 or 9)
+    pub keys: u8,
+    pub dp: bool,
+    pub subdivisions: Vec<usize>,
+    /// Percentage of each subdivision in `subdivisions`
+    pub density: Vec<u64>,
+    pub jack_tolerance: f32,
+    pub scatter: f32,
+    pub scatter_decay_rate: f32,
+    pub ln_ratio: f32,
+    pub ln_min_length: usize,
+    pub ln_max_length: usize,
+    /// Chords of the key sounds, one per bar (e.g. `D`, `F#m`, `CM7`)
+    pub chord_progression: Vec<String>,
+    // Headers of the BMS file, where empty texts are not written
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub subtitle: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub genre: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub artist: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub subartist: String,
+    /// Omitted to estimate it from the chart
+    pub playlevel: Option<u32>,
+    /// 1: BEGINNER to 5: INSANE. Omitted to estimate it from the chart
+    pub difficulty: Option<u32>,
+    /// #RANK (0: VERY HARD to 3: EASY)
+    pub rank: u32,
+    /// #DEFEXRANK in percentage, written instead of `rank`
+    pub defexrank: Option<u32>,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub stagefile: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub banner: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub backbmp: String,
+    /// Omitted to describe the generation
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub comment: String,
+    /// Additional headers as `COMMAND=VALUE` (e.g. `LNTYPE=1`)
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub header: Vec<String>,
+}
+
+/// Parses `COMMAND=VALUE` of an additional header into the command without `#` and the value
+pub fn parse_header(input: &str) -> Option<(String, String)> {
+    let (command, value) = input.split_once('=')?;
+    let command = command.trim().trim_start_matches('#');
+    (!command.is_empty()).then(|| (command.to_owned(), value.trim().to_owned()))
+}
+
+impl Default for GeneratorConfig {
+    fn default() -> Self {
+        GeneratorConfig {
+            title: "test".to_string(),
+            bpm: 150.0,
+            bars: 16,
+            seed: None,
+            keys: 7,
+            dp: false,
+            subdivisions: vec![1, 2, 4, 8, 16],
+            density: vec![0, 0, 100, 100, 100],
+            jack_tolerance: 0.0,
+            scatter: 0.0,
+            scatter_decay_rate: 0.5,
+            ln_ratio: 0.0,
+            ln_min_length: 2,
+            ln_max_length: 8,
+            chord_progression: ["D", "A", "Bm", "F#m", "G", "D", "G", "A"]
+                .map(String::from)
+                .to_vec(),
+            subtitle: String::new(),
+            genre: String::new(),
+            artist: String::new(),
+            subartist: String::new(),
+            playlevel: None,
+            difficulty: None,
+            rank: 3,
+            defexrank: None,
+            stagefile: String::new(),
+            banner: String::new(),
+            backbmp: String::new(),
+            comment: String::new(),
+            header: Vec::new(),
+        }
+    }
+}
+
+impl GeneratorConfig {
+    /// Parses and validates a config written in TOML
+    pub fn from_toml(input: &str) -> Result<Self, String> {
+        let config: Self = toml::from_str(input).map_err(|err| err.to_string())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn to_toml(&self) -> String {
+        toml::to_string(self).expect("config should be representable in TOML")
+    }
+
+    /// One of the built-in presets named in `PRESET_NAMES`
+    pub fn preset(name: &str) -> Option<Self> {
+        let mut config = Self::default();
+        match name {
+            // Single notes of 16th notes spread over the keys
+            "stream" => {
+                config.density = vec![0, 0, 100, 100, 100];
+                config.scatter = 2.0;
+            }
+            // Chords of two or three keys on 8th notes
+            "chords" => {
+                config.density = vec![0, 0, 300, 250, 0];
+                config.scatter = 1.0;
+            }
+            // Repeated notes on the same key
+            "jacks" => {
+                config.density = vec![0, 0, 200, 200, 0];
+                config.jack_tolerance = 3.0;
+                config.scatter = -2.0;
+            }
+            // Long dense charts
+            "stamina" => {
+                config.bars = 64;
+                config.density = vec![0, 0, 100, 100, 150];
+                config.scatter = 1.0;
+            }
+            _ => return None,
+        }
+        config.title = name.to_string();
+        Some(config)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if ![5, 7, 9].contains(&self.keys) {
+            return Err("keys must be 5, 7 or 9".to_string());
+        }
+        if self.dp && !self.key_mode().supports_dp() {
+            return Err("dp cannot be used with 9 keys".to_string());
+        }
+        if self.bars == 0 || self.bpm <= 0.0 {
+            return Err("bars and bpm must be greater than 0".to_string());
+        }
+        if self.subdivisions.is_empty() || self.subdivisions.contains(&0) {
+            return Err("subdivisions must be positive integers".to_string());
+        }
+        if self.density.len() != self.subdivisions.len() {
+            return Err("density must have as many values as subdivisions".to_string());
+        }
+        if !(0.0..=1.0).contains(&self.scatter_decay_rate) || !(0.0..=1.0).contains(&self.ln_ratio)
+        {
+            return Err("scatter_decay_rate and ln_ratio must be between 0 and 1".to_string());
+        }
+        if self.ln_min_length == 0 || self.ln_min_length > self.ln_max_length {
+            return Err("ln_min_length must be between 1 and ln_max_length".to_string());
+        }
+        if self.chord_progression.is_empty() {
+            return Err("chord_progression must not be empty".to_string());
+        }
+        if let Some(name) = self
+            .chord_progression
+            .iter()
+            .find(|name| parse_chord(name).is_none())
+        {
+            return Err(format!("invalid chord: {name}"));
+        }
+        if self
+            .difficulty
+            .is_some_and(|difficulty| !(1..=5).contains(&difficulty))
+        {
+            return Err("difficulty must be between 1 and 5".to_string());
+        }
+        if self.rank > 3 {
+            return Err("rank must be between 0 and 3".to_string());
+        }
+        if let Some(header) = self
+            .header
+            .iter()
+            .find(|header| parse_header(header).is_none())
+        {
+            return Err(format!("header must be COMMAND=VALUE: {header}"));
+        }
+        Ok(())
+    }
+
+    pub fn key_mode(&self) -> KeyMode {
+        match self.keys {
+            5 => KeyMode::Beat5,
+            9 => KeyMode::PopN9,
+            _ => KeyMode::Beat7,
+        }
+    }
+
+    pub fn chart_params(&self, seed: u64) -> ChartParams {
+        let mut chart_params = ChartParams::new(self.bpm, self.bars, seed);
+        chart_params.set_chords_per_bar(chords_per_bar(&self.subdivisions));
+        chart_params.set_key_mode(self.key_mode());
+        chart_params
+    }
+
+    pub fn notes_params(&self) -> NotesParams {
+        let subdivisions: Vec<_> = self
+            .subdivisions
+            .iter()
+            .copied()
+            .zip(self.density.iter().copied())
+            .collect();
+        let chord_density =
+            ChordDensity::from_subdivisions(&subdivisions, chords_per_bar(&self.subdivisions));
+        let scatter = Scatter::new(
+            self.scatter.abs(),
+            self.scatter_decay_rate,
+            self.scatter < 0.0,
+        );
+
+        let mut notes_params = NotesParams::new(chord_density, self.jack_tolerance, scatter);
+        notes_params.set_long_notes(LongNoteParams::new(
+            self.ln_ratio,
+            self.ln_min_length,
+            self.ln_max_length,
+        ));
+        notes_params
+    }
+
+    /// Headers of a chart generated with the config. `comment` describing the generation is used
+    /// unless the config has a comment, and invalid headers are skipped.
+    pub fn metadata(&self, comment: String) -> Metadata {
+        Metadata {
+            title: self.title.clone(),
+            subtitle: self.subtitle.clone(),
+            genre: self.genre.clone(),
+            artist: self.artist.clone(),
+            subartist: self.subartist.clone(),
+            playlevel: self.playlevel,
+            difficulty: self.difficulty,
+            rank: match self.defexrank {
+                Some(rank) => JudgeRank::DefExRank(rank),
+                None => JudgeRank::Rank(self.rank),
+            },
+            stagefile: self.stagefile.clone(),
+            banner: self.banner.clone(),
+            backbmp: self.backbmp.clone(),
+            comment: if self.comment.is_empty() {
+                comment
+            } else {
+                self.comment.clone()
+            },
+            extra_headers: self
+                .header
+                .iter()
+                .filter_map(|header| parse_header(header))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Chords of `chord_progression`, skipping invalid names
+    pub fn chord_progression(&self) -> Vec<(ChordRoot, ChordType)> {
+        self.chord_progression
+            .iter()
+            .filter_map(|name| parse_chord(name))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{GeneratorConfig, PRESET_NAMES};
+    use crate::bms::JudgeRank;
+
+    #[test]
+    fn test_config() {
+        let config =
+            GeneratorConfig::from_toml("bpm = 180.0\ndensity = [0, 0, 100, 200, 0]").unwrap();
+        assert_eq!(config.bpm, 180.0);
+        assert_eq!(config.bars, GeneratorConfig::default().bars);
+        assert_eq!(GeneratorConfig::from_toml(&config.to_toml()), Ok(config));
+
+        assert!(GeneratorConfig::from_toml("density = [100]").is_err());
+        assert!(GeneratorConfig::from_toml("unknown = 1").is_err());
+        assert!(GeneratorConfig::from_toml("chord_progression = [\"X\"]").is_err());
+
+        for name in PRESET_NAMES {
+            let preset = GeneratorConfig::preset(name).unwrap();
+            assert_eq!(preset.validate(), Ok(()));
+            assert_eq!(preset.title, name);
+        }
+        assert_eq!(GeneratorConfig::preset("unknown"), None);
+    }
+
+    #[test]
+    fn test_config_metadata() {
+        let config = GeneratorConfig::from_toml(
+            "title = \"drill\"\nartist = \"me\"\ndefexrank = 80\nheader = [\"LNTYPE=1\"]",
+        )
+        .unwrap();
+        assert_eq!(
+            GeneratorConfig::from_toml(&config.to_toml()),
+            Ok(config.clone())
+        );
+        assert!(!GeneratorConfig::default().to_toml().contains("artist"));
+
+        let metadata = config.metadata("generated".to_string());
+        assert_eq!(metadata.title, "drill");
+        assert_eq!(metadata.artist, "me");
+        assert_eq!(metadata.rank, JudgeRank::DefExRank(80));
+        assert_eq!(metadata.comment, "generated");
+        assert_eq!(
+            metadata.extra_headers,
+            vec![("LNTYPE".to_string(), "1".to_string())]
+        );
+
+        assert!(GeneratorConfig::from_toml("difficulty = 6").is_err());
+        assert!(GeneratorConfig::from_toml("header = [\"LNTYPE\"]").is_err());
+    }
+}
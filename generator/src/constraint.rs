@@ -0,0 +1,135 @@
+use crate::generate::Chord;
+
+/// Pattern family which can be forbidden or limited during generation.
+/// Patterns are looked for over chords with keys, skipping empty ones.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PatternConstraint {
+    /// Limits stairs (single notes moving to the adjacent lane in one direction, e.g. 1-2-3-4)
+    /// to `max_length` notes
+    Stairs { max_length: usize },
+    /// Limits trills (single notes alternating between two lanes, e.g. 1-2-1-2) to `max_length` notes
+    Trill { max_length: usize },
+    /// Forbids the chord of exactly these lanes (e.g. `[0, 2, 4, 6]` for 1-3-5-7)
+    ChordShape { lanes: Vec<u8> },
+    /// Limits denim (two chords of several notes alternating, e.g. 135-246-135-246) to `max_length` chords
+    Denim { max_length: usize },
+    /// Forbids the lanes together with a scratch
+    ScratchCombo { lanes: Vec<u8> },
+}
+
+/// Lanes of the chords with keys, from the latest one
+fn keyed_chords<'a>(history: &'a [Chord], candidate: &'a Chord) -> impl Iterator<Item = &'a [u8]> {
+    std::iter::once(candidate)
+        .chain(history.iter().rev())
+        .map(|chord| chord.lanes.as_slice())
+        .filter(|lanes| !lanes.is_empty())
+}
+
+/// Number of the latest chords alternating between two different chords whose sizes satisfy `is_size`
+fn alternation_length<'a>(
+    mut chords: impl Iterator<Item = &'a [u8]>,
+    is_size: impl Fn(usize) -> bool,
+) -> usize {
+    let Some(a) = chords.next().filter(|a| is_size(a.len())) else {
+        return 0;
+    };
+    let Some(b) = chords.next().filter(|b| is_size(b.len()) && b != &a) else {
+        return 1;
+    };
+
+    2 + chords
+        .enumerate()
+        .take_while(|&(i, chord)| chord == if i % 2 == 0 { a } else { b })
+        .count()
+}
+
+/// Number of the latest single notes moving to the adjacent lane in one direction
+fn stairs_length<'a>(mut chords: impl Iterator<Item = &'a [u8]>) -> usize {
+    let single = |lanes: &[u8]| match *lanes {
+        [lane] => Some(lane as i32),
+        _ => None,
+    };
+    let Some(mut last) = chords.next().and_then(single) else {
+        return 0;
+    };
+    let mut length = 1;
+    let mut direction = None;
+
+    for lane in chords.map_while(single) {
+        let step = last - lane;
+        if step.abs() != 1 || direction.is_some_and(|direction| direction != step) {
+            break;
+        }
+        direction = Some(step);
+        last = lane;
+        length += 1;
+    }
+
+    length
+}
+
+impl PatternConstraint {
+    /// Returns whether adding `candidate` after `history` breaks the constraint.
+    pub fn is_violated_by(&self, history: &[Chord], candidate: &Chord) -> bool {
+        match self {
+            PatternConstraint::Stairs { max_length } => {
+                stairs_length(keyed_chords(history, candidate)) > *max_length
+            }
+            PatternConstraint::Trill { max_length } => {
+                alternation_length(keyed_chords(history, candidate), |size| size == 1) > *max_length
+            }
+            PatternConstraint::ChordShape { lanes } => candidate.lanes == *lanes,
+            PatternConstraint::Denim { max_length } => {
+                alternation_length(keyed_chords(history, candidate), |size| size >= 2) > *max_length
+            }
+            PatternConstraint::ScratchCombo { lanes } => {
+                candidate.scratch && candidate.lanes.iter().any(|lane| lanes.contains(lane))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::PatternConstraint;
+    use crate::generate::Chord;
+
+    fn chords(lanes: &[&[u8]]) -> Vec<Chord> {
+        lanes
+            .iter()
+            .map(|lanes| Chord::new(lanes.to_vec(), false))
+            .collect()
+    }
+
+    #[test]
+    fn test_is_violated_by() {
+        let history = chords(&[&[6], &[0], &[1], &[], &[2]]);
+        let stairs = PatternConstraint::Stairs { max_length: 3 };
+        assert!(stairs.is_violated_by(&history, &chords(&[&[3]])[0]));
+        assert!(!stairs.is_violated_by(&history, &chords(&[&[1]])[0]));
+        assert!(!stairs.is_violated_by(&history[..3], &chords(&[&[2]])[0]));
+
+        let history = chords(&[&[0, 2], &[3], &[4], &[3], &[4]]);
+        let trill = PatternConstraint::Trill { max_length: 4 };
+        assert!(trill.is_violated_by(&history, &chords(&[&[3]])[0]));
+        assert!(!trill.is_violated_by(&history, &chords(&[&[4]])[0]));
+        assert!(!trill.is_violated_by(&history[..4], &chords(&[&[4]])[0]));
+
+        let history = chords(&[&[0, 2, 4], &[1, 3], &[0, 2, 4]]);
+        let denim = PatternConstraint::Denim { max_length: 3 };
+        assert!(denim.is_violated_by(&history, &chords(&[&[1, 3]])[0]));
+        assert!(!denim.is_violated_by(&history, &chords(&[&[1, 5]])[0]));
+
+        let shape = PatternConstraint::ChordShape {
+            lanes: vec![0, 2, 4, 6],
+        };
+        assert!(shape.is_violated_by(&[], &chords(&[&[0, 2, 4, 6]])[0]));
+        assert!(!shape.is_violated_by(&[], &chords(&[&[0, 2, 4]])[0]));
+
+        let combo = PatternConstraint::ScratchCombo { lanes: vec![5, 6] };
+        let mut candidate = chords(&[&[0, 6]]).remove(0);
+        assert!(!combo.is_violated_by(&[], &candidate));
+        candidate.scratch = true;
+        assert!(combo.is_violated_by(&[], &candidate));
+    }
+}
@@ -0,0 +1,129 @@
+use std::io::Write;
+
+use md5::Md5;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+/// MD5 and SHA-256 of the content of a chart file in lowercase hex, by which players identify charts
+pub fn chart_hashes(bytes: &[u8]) -> (String, String) {
+    (
+        format!("{:x}", Md5::digest(bytes)),
+        format!("{:x}", Sha256::digest(bytes)),
+    )
+}
+
+/// A chart of a course, identified by the hashes of its file
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct CourseChart {
+    pub title: String,
+    pub md5: String,
+    pub sha256: String,
+}
+
+impl CourseChart {
+    /// `bytes` is the content of the chart file, as written to the disk
+    pub fn new(title: &str, bytes: &[u8]) -> Self {
+        let (md5, sha256) = chart_hashes(bytes);
+        CourseChart {
+            title: title.to_string(),
+            md5,
+            sha256,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Trophy {
+    name: &'static str,
+    missrate: f32,
+    scorerate: f32,
+}
+
+#[derive(Serialize)]
+struct CourseData<'a> {
+    name: &'a str,
+    hash: &'a [CourseChart],
+    constraint: &'a [String],
+    trophy: Vec<Trophy>,
+    release: bool,
+}
+
+/// Charts played in a row, like a dan course
+#[derive(Clone, Debug, PartialEq)]
+pub struct Course {
+    name: String,
+    charts: Vec<CourseChart>,
+    constraints: Vec<String>,
+}
+
+impl Course {
+    /// A course with the constraints of a class course (mirror is allowed, and the gauge is the one of LR2)
+    pub fn new(name: &str) -> Self {
+        Course {
+            name: name.to_string(),
+            charts: Vec::new(),
+            constraints: vec!["grade_mirror".to_string(), "gauge_lr2".to_string()],
+        }
+    }
+
+    pub fn push_chart(&mut self, chart: CourseChart) {
+        self.charts.push(chart);
+    }
+
+    /// Constraints as named by beatoraja (e.g. `grade_mirror`, `no_speed`, `gauge_lr2`)
+    pub fn set_constraints(&mut self, constraints: Vec<String>) {
+        self.constraints = constraints;
+    }
+
+    pub fn charts(&self) -> &[CourseChart] {
+        &self.charts
+    }
+
+    /// Writes the course in the JSON format of the course folder of beatoraja
+    pub fn write_beatoraja(&self, buf: impl Write) -> serde_json::Result<()> {
+        let trophy = |name, missrate, scorerate| Trophy {
+            name,
+            missrate,
+            scorerate,
+        };
+        let course = CourseData {
+            name: &self.name,
+            hash: &self.charts,
+            constraint: &self.constraints,
+            trophy: vec![
+                trophy("bronzemedal", 7.5, 55.0),
+                trophy("silvermedal", 5.0, 70.0),
+                trophy("goldmedal", 2.5, 85.0),
+            ],
+            release: true,
+        };
+        serde_json::to_writer_pretty(buf, &[course])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Course, CourseChart};
+
+    #[test]
+    fn test_write_beatoraja() {
+        let chart = CourseChart::new("test", b"");
+        assert_eq!(chart.md5, "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(
+            chart.sha256,
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+
+        let mut course = Course::new("course");
+        course.push_chart(chart.clone());
+        course.push_chart(chart);
+        let mut buf = Vec::new();
+        course.write_beatoraja(&mut buf).unwrap();
+
+        let json: serde_json::Value = serde_json::from_slice(&buf).unwrap();
+        assert_eq!(json[0]["name"], "course");
+        assert_eq!(json[0]["hash"].as_array().unwrap().len(), 2);
+        assert_eq!(json[0]["hash"][1]["title"], "test");
+        assert_eq!(json[0]["constraint"][0], "grade_mirror");
+    }
+}
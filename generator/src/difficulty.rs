@@ -0,0 +1,431 @@
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+use crate::generate::{
+    generate_chart, generate_chart_dp, Chart, ChartDp, ChartParams, Chord, NotesParams,
+};
+use crate::tempo::TempoMap;
+
+/// Seconds in which the strain decays to 1/e
+const STRAIN_DECAY: f32 = 1.0;
+/// Weight of the n-th hardest bar is `PEAK_WEIGHT` to the n-th power
+const PEAK_WEIGHT: f32 = 0.9;
+/// Notes pressed together by a hand are easier than the same number of notes in a row
+const CHORD_EXPONENT: f32 = 0.85;
+const SCRATCH_WEIGHT: f32 = 1.2;
+/// A note in the same lane as the previous chord gets the full bonus when it follows within `JACK_INTERVAL` seconds
+const JACK_BONUS: f32 = 0.5;
+const JACK_INTERVAL: f32 = 0.15;
+/// A hand gets the full bonus when it hits again within `HAND_INTERVAL` seconds, which alternating hands avoid
+const HAND_BONUS: f32 = 0.2;
+const HAND_INTERVAL: f32 = 0.15;
+/// Strain, in notes per second, of ☆12. Above this, levels are on the insane scale.
+const NORMAL_MAX_STRAIN: f32 = 12.0;
+/// Insane levels per note per second of strain
+const INSANE_LEVELS_PER_STRAIN: f32 = 0.8;
+
+/// Level on the normal scale (☆1 to ☆12) or the insane scale (★1 to ★25 and beyond)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Level {
+    Normal(u32),
+    Insane(u32),
+}
+
+impl Level {
+    fn from_strain(strain: f32) -> Self {
+        if strain < NORMAL_MAX_STRAIN + 0.5 {
+            Level::Normal((strain.round() as u32).clamp(1, 12))
+        } else {
+            let level = ((strain - NORMAL_MAX_STRAIN) * INSANE_LEVELS_PER_STRAIN).round() as u32;
+            Level::Insane(level.max(1))
+        }
+    }
+
+    /// Position on the combined scale, where ★1 follows ☆12
+    pub fn rank(&self) -> u32 {
+        match *self {
+            Level::Normal(level) => level,
+            Level::Insane(level) => level.saturating_add(12),
+        }
+    }
+
+    /// Reads the level back from #PLAYLEVEL and #DIFFICULTY
+    pub fn from_bms_header(playlevel: u32, difficulty: Option<u32>) -> Self {
+        match difficulty {
+            Some(5) => Level::Insane(playlevel),
+            _ => Level::Normal(playlevel),
+        }
+    }
+
+    /// Value for #PLAYLEVEL
+    pub fn playlevel(&self) -> u32 {
+        match *self {
+            Level::Normal(level) | Level::Insane(level) => level,
+        }
+    }
+
+    /// Value for #DIFFICULTY (2: NORMAL, 3: HYPER, 4: ANOTHER, 5: INSANE)
+    pub fn difficulty(&self) -> u32 {
+        match *self {
+            Level::Normal(0..=5) => 2,
+            Level::Normal(6..=9) => 3,
+            Level::Normal(_) => 4,
+            Level::Insane(_) => 5,
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Level::Normal(level) => write!(f, "☆{level}"),
+            Level::Insane(level) => write!(f, "★{level}"),
+        }
+    }
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    /// Parses `☆12` or `12` as a normal level, and `★5` or `*5` as an insane level.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (insane, level) = if let Some(level) = s.strip_prefix(['★', '*']) {
+            (true, level)
+        } else {
+            (false, s.strip_prefix('☆').unwrap_or(s))
+        };
+        let level: u32 = level.parse().map_err(|_| format!("invalid level: {s}"))?;
+
+        match (insane, level) {
+            (true, 1..) => Ok(Level::Insane(level)),
+            (false, 1..=12) => Ok(Level::Normal(level)),
+            _ => Err(format!("level out of range: {s}")),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Difficulty {
+    /// Weighted strain of the hardest bars, comparable to notes per second
+    pub strain: f32,
+    pub level: Level,
+}
+
+/// A key pressed in a chord. Keys of the same lane have the same id.
+struct Key {
+    hand: usize,
+    id: usize,
+    scratch: bool,
+}
+
+struct StrainModel {
+    strain: f32,
+    last_time: f32,
+    last_key_hits: Vec<Option<f32>>,
+    previous_keys: Vec<usize>,
+    last_hand_hits: [Option<f32>; 2],
+}
+
+impl StrainModel {
+    fn new(keys: usize) -> Self {
+        StrainModel {
+            strain: 0.0,
+            last_time: 0.0,
+            last_key_hits: vec![None; keys],
+            previous_keys: Vec::new(),
+            last_hand_hits: [None; 2],
+        }
+    }
+
+    fn bonus(last_hit: Option<f32>, time: f32, bonus: f32, interval: f32) -> f32 {
+        last_hit.map_or(0.0, |last_hit| {
+            bonus * (interval / (time - last_hit).max(0.001)).min(1.0)
+        })
+    }
+
+    /// Adds the chord and returns the strain after it
+    fn push(&mut self, time: f32, keys: &[Key]) -> f32 {
+        self.strain *= (-(time - self.last_time) / STRAIN_DECAY).exp();
+        self.last_time = time;
+
+        for hand in 0..2 {
+            let weight: f32 = keys
+                .iter()
+                .filter(|key| key.hand == hand)
+                .map(|key| if key.scratch { SCRATCH_WEIGHT } else { 1.0 })
+                .sum();
+            if weight == 0.0 {
+                continue;
+            }
+
+            let hand_bonus =
+                Self::bonus(self.last_hand_hits[hand], time, HAND_BONUS, HAND_INTERVAL);
+            self.strain += weight.powf(CHORD_EXPONENT) * (1.0 + hand_bonus);
+            self.last_hand_hits[hand] = Some(time);
+        }
+
+        for key in keys {
+            if self.previous_keys.contains(&key.id) {
+                self.strain +=
+                    Self::bonus(self.last_key_hits[key.id], time, JACK_BONUS, JACK_INTERVAL);
+            }
+            self.last_key_hits[key.id] = Some(time);
+        }
+        self.previous_keys = keys.iter().map(|key| key.id).collect();
+
+        self.strain
+    }
+}
+
+/// Estimates the difficulty from chords given as `(bar_idx, chord_pos, keys)`.
+fn estimate(
+    bpm: f32,
+    tempo_map: &TempoMap,
+    bar_lengths: &[usize],
+    keys: usize,
+    chords: impl Iterator<Item = (usize, usize, Vec<Key>)>,
+) -> Difficulty {
+    let mut model = StrainModel::new(keys);
+    let mut bar_peaks = vec![0.0; bar_lengths.len()];
+
+    for (bar_idx, chord_pos, keys) in chords {
+        if keys.is_empty() {
+            continue;
+        }
+
+        let fraction = chord_pos as f32 / bar_lengths[bar_idx] as f32;
+        let time = tempo_map.seconds_at(bpm, bar_lengths, bar_idx, fraction);
+        let strain = model.push(time, &keys);
+        bar_peaks[bar_idx] = f32::max(bar_peaks[bar_idx], strain);
+    }
+
+    bar_peaks.sort_by(|a, b| b.total_cmp(a));
+    let (weighted_sum, weight_sum) = bar_peaks
+        .iter()
+        .zip(std::iter::successors(Some(1.0), |weight| {
+            Some(weight * PEAK_WEIGHT)
+        }))
+        .fold((0.0, 0.0), |(sum, weights), (peak, weight)| {
+            (sum + peak * weight, weights + weight)
+        });
+    let strain = if weight_sum > 0.0 {
+        weighted_sum / weight_sum / STRAIN_DECAY
+    } else {
+        0.0
+    };
+
+    Difficulty {
+        strain,
+        level: Level::from_strain(strain),
+    }
+}
+
+fn chord_keys(chord: &Chord, lanes: usize, side: Option<usize>) -> Vec<Key> {
+    // In SP, the left hand plays the scratch and the left half of the keys
+    let hand_of = |lane: usize| side.unwrap_or(if lane < lanes / 2 { 0 } else { 1 });
+    let offset = side.unwrap_or(0) * (lanes + 1);
+
+    let mut keys: Vec<_> = chord
+        .lanes
+        .iter()
+        .map(|&lane| Key {
+            hand: hand_of(lane as usize),
+            id: offset + lane as usize,
+            scratch: false,
+        })
+        .collect();
+    if chord.scratch {
+        keys.push(Key {
+            hand: side.unwrap_or(0),
+            id: offset + lanes,
+            scratch: true,
+        });
+    }
+
+    keys
+}
+
+pub fn estimate_chart(chart: &Chart) -> Difficulty {
+    let lanes = chart.key_mode.lanes();
+    let chords = chart.bars.iter().enumerate().flat_map(|(bar_idx, bar)| {
+        bar.iter()
+            .enumerate()
+            .map(move |(i, chord)| (bar_idx, i, chord_keys(chord, lanes, None)))
+    });
+
+    estimate(
+        chart.bpm,
+        &chart.tempo_map,
+        &chart.bar_lengths(),
+        lanes + 1,
+        chords,
+    )
+}
+
+/// Estimates the difficulty of a DP chart, where each hand plays a side.
+pub fn estimate_chart_dp(chart: &ChartDp) -> Difficulty {
+    let lanes = chart.key_mode.lanes();
+    let chords = chart.bars.iter().enumerate().flat_map(|(bar_idx, bar)| {
+        bar.iter().enumerate().map(move |(i, chords)| {
+            let keys = (0..2)
+                .flat_map(|side| chord_keys(&chords[side], lanes, Some(side)))
+                .collect();
+            (bar_idx, i, keys)
+        })
+    });
+
+    estimate(
+        chart.bpm,
+        &chart.tempo_map,
+        &chart.bar_lengths(),
+        (lanes + 1) * 2,
+        chords,
+    )
+}
+
+/// A chart found by a search for a difficulty, with the parameters used to generate it
+pub struct TargetedChart<T> {
+    pub chart: T,
+    pub difficulty: Difficulty,
+    pub seed: u64,
+    /// Factor applied to the chord density of the given `NotesParams`
+    pub density_scale: f32,
+}
+
+/// Tries successive seeds from that of `chart_params`, scaling the chord density up or down
+/// by bisection until the level lands in `levels`.
+fn search<T>(
+    chart_params: &ChartParams,
+    notes_params: &NotesParams,
+    levels: RangeInclusive<Level>,
+    attempts: usize,
+    generate: impl Fn(&ChartParams, &NotesParams) -> T,
+    estimate: impl Fn(&T) -> Difficulty,
+) -> Option<TargetedChart<T>> {
+    let (min_rank, max_rank) = (levels.start().rank(), levels.end().rank());
+    let mut chart_params = chart_params.clone();
+    let mut scaled_notes_params = notes_params.clone();
+    let mut scale = 1.0;
+    let mut lower_scale = 0.0;
+    let mut upper_scale: Option<f32> = None;
+
+    for attempt in 0..attempts {
+        if attempt > 0 {
+            chart_params.set_seed(chart_params.seed().wrapping_add(1));
+        }
+        scaled_notes_params.set_chord_density(notes_params.chord_density().scaled(scale));
+
+        let chart = generate(&chart_params, &scaled_notes_params);
+        let difficulty = estimate(&chart);
+        let rank = difficulty.level.rank();
+
+        if rank < min_rank {
+            lower_scale = scale;
+            scale = upper_scale.map_or(scale * 2.0, |upper_scale| (scale + upper_scale) / 2.0);
+        } else if rank > max_rank {
+            upper_scale = Some(scale);
+            scale = (lower_scale + scale) / 2.0;
+        } else {
+            return Some(TargetedChart {
+                chart,
+                difficulty,
+                seed: chart_params.seed(),
+                density_scale: scale,
+            });
+        }
+    }
+
+    None
+}
+
+/// Generates a chart whose level is within `levels`, or returns `None` after `attempts` tries.
+pub fn generate_chart_with_difficulty(
+    chart_params: &ChartParams,
+    notes_params: &NotesParams,
+    levels: RangeInclusive<Level>,
+    attempts: usize,
+) -> Option<TargetedChart<Chart>> {
+    search(
+        chart_params,
+        notes_params,
+        levels,
+        attempts,
+        generate_chart,
+        estimate_chart,
+    )
+}
+
+/// Generates a DP chart whose level is within `levels`, or returns `None` after `attempts` tries.
+/// Both sides use `notes_params`.
+pub fn generate_chart_dp_with_difficulty(
+    chart_params: &ChartParams,
+    notes_params: &NotesParams,
+    levels: RangeInclusive<Level>,
+    attempts: usize,
+) -> Option<TargetedChart<ChartDp>> {
+    search(
+        chart_params,
+        notes_params,
+        levels,
+        attempts,
+        |chart_params, notes_params| generate_chart_dp(chart_params, notes_params, notes_params),
+        estimate_chart_dp,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::{estimate_chart, generate_chart_with_difficulty, Level};
+    use crate::chord::ChordDensity;
+    use crate::generate::{generate_chart, ChartParams, NotesParams, Scatter};
+
+    fn notes_params(density: u64) -> NotesParams {
+        NotesParams::new(
+            ChordDensity::new(vec![vec![density]]),
+            0.0,
+            Scatter::new(0.0, 0.0, false),
+        )
+    }
+
+    #[test]
+    fn test_level() {
+        assert_eq!("12".parse(), Ok(Level::Normal(12)));
+        assert_eq!("☆3".parse(), Ok(Level::Normal(3)));
+        assert_eq!("★25".parse(), Ok(Level::Insane(25)));
+        assert_eq!(Level::from_bms_header(7, Some(5)), Level::Insane(7));
+        assert_eq!(Level::from_bms_header(7, None), Level::Normal(7));
+        assert_eq!("*5".parse(), Ok(Level::Insane(5)));
+        assert!("13".parse::<Level>().is_err());
+        assert!("★0".parse::<Level>().is_err());
+        assert!(Level::Normal(12).rank() < Level::Insane(1).rank());
+        assert_eq!(Level::Insane(7).to_string(), "★7");
+    }
+
+    #[test]
+    fn test_estimate_chart() {
+        let chart_params = ChartParams::new(150.0, 16, 199024);
+
+        let easy = estimate_chart(&generate_chart(&chart_params, &notes_params(50)));
+        let stream = estimate_chart(&generate_chart(&chart_params, &notes_params(100)));
+        let chords = estimate_chart(&generate_chart(&chart_params, &notes_params(300)));
+
+        assert!(easy.strain < stream.strain);
+        assert!(stream.strain < chords.strain);
+        assert!(matches!(easy.level, Level::Normal(_)));
+        assert!(matches!(chords.level, Level::Insane(_)));
+    }
+
+    #[test]
+    fn test_generate_chart_with_difficulty() {
+        let chart_params = ChartParams::new(150.0, 16, 199024);
+        let levels = Level::Insane(5)..=Level::Insane(7);
+
+        let result =
+            generate_chart_with_difficulty(&chart_params, &notes_params(100), levels, 100).unwrap();
+
+        assert!((17..=19).contains(&result.difficulty.level.rank()));
+        assert!(result.density_scale > 1.0);
+        assert_eq!(estimate_chart(&result.chart), result.difficulty);
+    }
+}
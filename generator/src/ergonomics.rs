@@ -0,0 +1,172 @@
+use crate::generate::Chord;
+
+/// Number of the latest chords looked back for the previous use of a finger
+pub const SAME_FINGER_WINDOW: usize = 4;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Hand {
+    Left,
+    Right,
+}
+
+/// Hand and finger playing each key, where fingers are numbered from the thumb (0) to the little finger (4)
+#[derive(Clone, PartialEq, Debug)]
+pub struct Fingering {
+    fingers: Vec<(Hand, u8)>,
+    scratch_hand: Hand,
+}
+
+impl Fingering {
+    /// `fingers` lists the hand and finger of each key from left to right
+    pub fn new(fingers: Vec<(Hand, u8)>, scratch_hand: Hand) -> Self {
+        assert!(fingers.iter().all(|&(_, finger)| finger < 5));
+        Fingering {
+            fingers,
+            scratch_hand,
+        }
+    }
+
+    /// 1048 style for 7 keys: the left hand plays the scratch and keys 1 to 4 with the ring finger to the thumb,
+    /// and the right hand plays keys 5 to 7 with the index finger to the ring finger
+    pub fn style_1048() -> Self {
+        use Hand::*;
+        Self::new(
+            vec![
+                (Left, 3),
+                (Left, 2),
+                (Left, 1),
+                (Left, 0),
+                (Right, 1),
+                (Right, 2),
+                (Right, 3),
+            ],
+            Left,
+        )
+    }
+
+    /// 2048 style for 7 keys: the left hand plays the scratch and keys 1 to 3 with the middle finger to the thumb,
+    /// and the right hand plays keys 4 to 7 with the thumb to the ring finger
+    pub fn style_2048() -> Self {
+        use Hand::*;
+        Self::new(
+            vec![
+                (Left, 2),
+                (Left, 1),
+                (Left, 0),
+                (Right, 0),
+                (Right, 1),
+                (Right, 2),
+                (Right, 3),
+            ],
+            Left,
+        )
+    }
+
+    fn finger(&self, lane: u8) -> Option<(Hand, u8)> {
+        self.fingers.get(lane as usize).copied()
+    }
+}
+
+/// Cost model of hands, which makes awkward lanes less likely to be chosen
+#[derive(Clone, PartialEq, Debug)]
+pub struct Ergonomics {
+    fingering: Fingering,
+    same_finger_penalty: f32,
+    hand_capacity: usize,
+    overload_penalty: f32,
+}
+
+impl Ergonomics {
+    pub fn new(fingering: Fingering) -> Self {
+        Ergonomics {
+            fingering,
+            same_finger_penalty: 0.2,
+            hand_capacity: 3,
+            overload_penalty: 0.1,
+        }
+    }
+
+    /// Multiplies the weight of a lane whose finger recently played another lane by `penalty`
+    pub fn set_same_finger_penalty(&mut self, penalty: f32) {
+        self.same_finger_penalty = penalty;
+    }
+
+    /// Multiplies the weight of a lane by `penalty` when its hand already plays `capacity` notes
+    /// in the chord, counting the scratch
+    pub fn set_hand_capacity(&mut self, capacity: usize, penalty: f32) {
+        self.hand_capacity = capacity;
+        self.overload_penalty = penalty;
+    }
+
+    /// Returns the factor of the weight of `lane` after `history`, when `selected` lanes and
+    /// `scratch` are already in the chord.
+    pub fn weight_factor(
+        &self,
+        lane: u8,
+        history: &[Chord],
+        selected: &[u8],
+        scratch: bool,
+    ) -> f32 {
+        let Some((hand, finger)) = self.fingering.finger(lane) else {
+            return 1.0;
+        };
+        let mut factor = 1.0;
+
+        let previous = history
+            .iter()
+            .rev()
+            .take(SAME_FINGER_WINDOW)
+            .find(|chord| !chord.lanes.is_empty());
+        if let Some(previous) = previous {
+            let same_finger = previous.lanes.iter().any(|&other| {
+                other != lane && self.fingering.finger(other) == Some((hand, finger))
+            });
+            if same_finger {
+                factor *= self.same_finger_penalty;
+            }
+        }
+
+        let load = selected
+            .iter()
+            .filter(|&&other| self.fingering.finger(other).map(|(hand, _)| hand) == Some(hand))
+            .count()
+            + (scratch && self.fingering.scratch_hand == hand) as usize;
+        if load >= self.hand_capacity {
+            factor *= self.overload_penalty;
+        }
+
+        factor
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Ergonomics, Fingering, Hand};
+    use crate::generate::Chord;
+    use approx::assert_relative_eq;
+
+    #[test]
+    fn test_weight_factor() {
+        let fingering = Fingering::style_2048();
+        assert_eq!(fingering.finger(3), Some((Hand::Right, 0)));
+        let ergonomics =
+            Ergonomics::new(Fingering::new(fingering.fingers[..4].to_vec(), Hand::Left));
+
+        let history = vec![Chord::new(vec![3], false)];
+        assert_relative_eq!(ergonomics.weight_factor(3, &history, &[], false), 1.0);
+        assert_relative_eq!(ergonomics.weight_factor(2, &history, &[], false), 1.0);
+
+        assert_relative_eq!(ergonomics.weight_factor(0, &[], &[1], true), 1.0);
+        assert_relative_eq!(ergonomics.weight_factor(0, &[], &[1, 2], true), 0.1);
+        assert_relative_eq!(ergonomics.weight_factor(3, &[], &[1, 2], true), 1.0);
+        assert_relative_eq!(ergonomics.weight_factor(7, &[], &[1, 2], true), 1.0);
+
+        let ergonomics = Ergonomics::new(Fingering::new(
+            vec![(Hand::Left, 1), (Hand::Left, 1), (Hand::Right, 1)],
+            Hand::Left,
+        ));
+        let history = vec![Chord::new(vec![0], false)];
+        assert_relative_eq!(ergonomics.weight_factor(1, &history, &[], false), 0.2);
+        assert_relative_eq!(ergonomics.weight_factor(2, &history, &[], false), 1.0);
+    }
+}
@@ -1,9 +1,18 @@
 use crate::chord::ChordDensity;
+use crate::constraint::PatternConstraint;
+use crate::ergonomics::{Ergonomics, SAME_FINGER_WINDOW};
+use crate::key_mode::KeyMode;
+use crate::markov::MarkovModel;
 use crate::rng::RNG;
+use crate::template::PatternFamily;
+use crate::tempo::TempoMap;
+use std::ops::Range;
 
-pub const CHORDS_PER_BAR: usize = 16;
-pub const LANES: usize = 7;
+pub const DEFAULT_CHORDS_PER_BAR: usize = 16;
+/// Maximum number of rerolls of a chord breaking pattern constraints, after which the chord is kept
+const MAX_CONSTRAINT_REROLLS: usize = 16;
 
+#[derive(Clone)]
 pub struct Scatter {
     strength: f32,
     decay: f32,
@@ -20,23 +29,210 @@ impl Scatter {
     }
 }
 
+/// How the phrases of the two DP sides relate to each other
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DpPhrasing {
+    /// Each side is generated on its own
+    Independent,
+    /// The right side plays the left side mirrored
+    Mirrored,
+    /// The sides take turns every `chords` chords of the grid, starting from the left side
+    CallAndResponse { chords: usize },
+}
+
+/// Relationship between the two sides of DP charts. Bars built from templates are generated for
+/// each side independently, ignoring these.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DpParams {
+    phrasing: DpPhrasing,
+    split_density: bool,
+    max_hand_keys: Option<usize>,
+    big_chord_size: Option<usize>,
+}
+
+impl DpParams {
+    pub fn new() -> Self {
+        DpParams {
+            phrasing: DpPhrasing::Independent,
+            split_density: false,
+            max_hand_keys: None,
+            big_chord_size: None,
+        }
+    }
+
+    pub fn set_phrasing(&mut self, phrasing: DpPhrasing) {
+        if let DpPhrasing::CallAndResponse { chords } = phrasing {
+            assert!(chords > 0);
+        }
+        self.phrasing = phrasing;
+    }
+
+    /// If enabled, the chord density of the left side decides the total number of keys of both sides,
+    /// which are split randomly between them. The chord density of the right side is not used.
+    pub fn set_split_density(&mut self, split_density: bool) {
+        self.split_density = split_density;
+    }
+
+    /// Limits the number of keys of a side, which is played by one hand, at once
+    pub fn set_max_hand_keys(&mut self, max_hand_keys: usize) {
+        self.max_hand_keys = Some(max_hand_keys);
+    }
+
+    /// Forbids both sides from having chords of `size` keys or more at the same time
+    pub fn set_big_chord_size(&mut self, size: usize) {
+        self.big_chord_size = Some(size);
+    }
+}
+
+impl Default for DpParams {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct ChartParams {
     bpm: f32,
     bars: usize,
     seed: u64,
+    chords_per_bar: usize,
+    key_mode: KeyMode,
+    tempo_map: TempoMap,
+    dp_params: DpParams,
 }
 
 impl ChartParams {
     pub fn new(bpm: f32, bars: usize, seed: u64) -> Self {
-        ChartParams { bpm, bars, seed }
+        ChartParams {
+            bpm,
+            bars,
+            seed,
+            chords_per_bar: DEFAULT_CHORDS_PER_BAR,
+            key_mode: KeyMode::Beat7,
+            tempo_map: TempoMap::new(),
+            dp_params: DpParams::new(),
+        }
+    }
+
+    /// Sets the resolution of the rhythmic grid. Chord densities should have the same length.
+    pub fn set_chords_per_bar(&mut self, chords_per_bar: usize) {
+        assert!(chords_per_bar > 0);
+        self.chords_per_bar = chords_per_bar;
     }
+
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn set_key_mode(&mut self, key_mode: KeyMode) {
+        self.key_mode = key_mode;
+    }
+
+    pub fn set_tempo_map(&mut self, tempo_map: TempoMap) {
+        self.tempo_map = tempo_map;
+    }
+
+    /// Sets how the sides of DP charts are related. Not used for SP charts.
+    pub fn set_dp_params(&mut self, dp_params: DpParams) {
+        self.dp_params = dp_params;
+    }
+}
+
+#[derive(Clone)]
+pub struct LongNoteParams {
+    ratio: f32,
+    min_length: usize,
+    max_length: usize,
 }
 
+impl LongNoteParams {
+    /// `ratio` is the chance for each note to become a long note, and the length is chosen
+    /// uniformly from `min_length..=max_length` chords.
+    pub fn new(ratio: f32, min_length: usize, max_length: usize) -> Self {
+        assert!(0 < min_length && min_length <= max_length);
+        LongNoteParams {
+            ratio,
+            min_length,
+            max_length,
+        }
+    }
+
+    fn disabled() -> Self {
+        LongNoteParams {
+            ratio: 0.0,
+            min_length: 1,
+            max_length: 1,
+        }
+    }
+
+    fn generate_length(&self, rng: &mut RNG) -> Option<usize> {
+        // Disabled long notes do not touch the RNG, so that charts without them keep their seeds
+        if self.ratio <= 0.0 || self.ratio <= rng.next_f32() {
+            return None;
+        }
+
+        let range = (self.max_length - self.min_length + 1) as u64;
+        Some(self.min_length + (rng.next() % range) as usize)
+    }
+}
+
+/// How scratches are generated
+#[derive(Clone)]
+pub struct ScratchParams {
+    density: ChordDensity,
+    max_keys: Option<usize>,
+    backspin: LongNoteParams,
+    rushes: Vec<(Range<usize>, ChordDensity)>,
+}
+
+impl ScratchParams {
+    /// `density` is the chance of a scratch in percent for each chord of the grid, like chord densities
+    pub fn new(density: ChordDensity) -> Self {
+        ScratchParams {
+            density,
+            max_keys: None,
+            backspin: LongNoteParams::disabled(),
+            rushes: Vec::new(),
+        }
+    }
+
+    /// Limits the number of keys in a chord with a scratch
+    pub fn set_max_keys(&mut self, max_keys: usize) {
+        self.max_keys = Some(max_keys);
+    }
+
+    /// Sets the chance and the lengths of scratch long notes (backspins)
+    pub fn set_backspin(&mut self, backspin: LongNoteParams) {
+        self.backspin = backspin;
+    }
+
+    /// Uses `density` instead in `bars`, for scratch rushes
+    pub fn push_rush(&mut self, bars: Range<usize>, density: ChordDensity) {
+        self.rushes.push((bars, density));
+    }
+
+    fn density_at(&self, bar_idx: usize) -> &ChordDensity {
+        self.rushes
+            .iter()
+            .find(|(bars, _)| bars.contains(&bar_idx))
+            .map_or(&self.density, |(_, density)| density)
+    }
+}
+
+#[derive(Clone)]
 pub struct NotesParams {
     chord_density: ChordDensity,
     jack_tolerance: f32,
     scatter: Scatter,
+    long_notes: LongNoteParams,
+    constraints: Vec<(PatternConstraint, f32)>,
+    templates: Vec<PatternFamily>,
+    scratch: Option<ScratchParams>,
+    ergonomics: Option<Ergonomics>,
 }
 
 impl NotesParams {
@@ -45,52 +241,294 @@ impl NotesParams {
             chord_density,
             jack_tolerance,
             scatter,
+            long_notes: LongNoteParams::disabled(),
+            constraints: Vec::new(),
+            templates: Vec::new(),
+            scratch: None,
+            ergonomics: None,
+        }
+    }
+
+    pub fn set_long_notes(&mut self, long_notes: LongNoteParams) {
+        self.long_notes = long_notes;
+    }
+
+    /// Adds a constraint on patterns. A chord breaking it is rerolled in `reroll_chance`,
+    /// so 1.0 forbids the pattern and smaller values make it rarer.
+    pub fn push_constraint(&mut self, constraint: PatternConstraint, reroll_chance: f32) {
+        self.constraints.push((constraint, reroll_chance));
+    }
+
+    /// Builds bars out of templates of the pattern families instead of rolling each chord.
+    /// The chord density only decides which positions of the grid have notes, and jack tolerance,
+    /// scatter, long notes and constraints are not used. An empty list disables templates.
+    pub fn set_templates(&mut self, templates: Vec<PatternFamily>) {
+        self.templates = templates;
+    }
+
+    /// Generates scratches with `scratch` on both SP and DP. Without it, SP charts get a scratch
+    /// on the first chord of every 8 bars and DP charts get none.
+    pub fn set_scratch(&mut self, scratch: ScratchParams) {
+        self.scratch = Some(scratch);
+    }
+
+    /// Makes lanes which are awkward for the hands less likely to be chosen
+    pub fn set_ergonomics(&mut self, ergonomics: Ergonomics) {
+        self.ergonomics = Some(ergonomics);
+    }
+
+    pub fn set_chord_density(&mut self, chord_density: ChordDensity) {
+        self.chord_density = chord_density;
+    }
+
+    pub fn chord_density(&self) -> &ChordDensity {
+        &self.chord_density
+    }
+
+    /// Interpolates numeric parameters at `t` (0.0 for `self`, 1.0 for `other`).
+    /// Chord densities with different rhythms, the lengths of long notes and constraints are taken from `self`.
+    fn interpolate(&self, other: &Self, t: f32) -> Self {
+        let chord_density = self
+            .chord_density
+            .interpolate(&other.chord_density, t)
+            .unwrap_or_else(|| self.chord_density.clone());
+
+        let signed_strength = |scatter: &Scatter| {
+            if scatter.inverted {
+                -scatter.strength
+            } else {
+                scatter.strength
+            }
+        };
+        let strength = lerp(
+            signed_strength(&self.scatter),
+            signed_strength(&other.scatter),
+            t,
+        );
+        let scatter = Scatter::new(
+            strength.abs(),
+            lerp(self.scatter.decay, other.scatter.decay, t),
+            strength < 0.0,
+        );
+
+        NotesParams {
+            chord_density,
+            jack_tolerance: lerp(self.jack_tolerance, other.jack_tolerance, t),
+            scatter,
+            long_notes: LongNoteParams {
+                ratio: lerp(self.long_notes.ratio, other.long_notes.ratio, t),
+                ..self.long_notes.clone()
+            },
+            constraints: self.constraints.clone(),
+            templates: self.templates.clone(),
+            scratch: self.scratch.clone(),
+            ergonomics: self.ergonomics.clone(),
         }
     }
 }
 
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+/// `NotesParams` which change over the chart. Each section lasts from its start bar until the next one.
+#[derive(Clone)]
+pub struct NotesSchedule {
+    sections: Vec<(usize, NotesParams)>,
+    interpolate: bool,
+}
+
+impl NotesSchedule {
+    pub fn new(notes_params: NotesParams) -> Self {
+        NotesSchedule {
+            sections: vec![(0, notes_params)],
+            interpolate: false,
+        }
+    }
+
+    /// Adds a section starting at `start_bar`, which must be after the start of the last section.
+    pub fn push_section(&mut self, start_bar: usize, notes_params: NotesParams) {
+        assert!(self.sections.last().unwrap().0 < start_bar);
+        self.sections.push((start_bar, notes_params));
+    }
+
+    /// If enabled, parameters change linearly from the start of each section to the start of the next one
+    /// instead of changing at once.
+    pub fn set_interpolate(&mut self, interpolate: bool) {
+        self.interpolate = interpolate;
+    }
+
+    /// Multiplies the chord density of every section by `factor`
+    pub fn scaled(&self, factor: f32) -> Self {
+        let mut schedule = self.clone();
+        for (_, notes_params) in schedule.sections.iter_mut() {
+            notes_params.set_chord_density(notes_params.chord_density().scaled(factor));
+        }
+        schedule
+    }
+
+    pub fn notes_params_at(&self, bar_idx: usize) -> NotesParams {
+        let idx = self
+            .sections
+            .iter()
+            .rposition(|&(start_bar, _)| start_bar <= bar_idx)
+            .unwrap_or(0);
+        let (start_bar, notes_params) = &self.sections[idx];
+
+        match self.sections.get(idx + 1) {
+            Some((next_start_bar, next_notes_params)) if self.interpolate => {
+                let t = (bar_idx - start_bar) as f32 / (next_start_bar - start_bar) as f32;
+                notes_params.interpolate(next_notes_params, t)
+            }
+            _ => notes_params.clone(),
+        }
+    }
+
+    fn bars(&self, bars: usize) -> Vec<NotesParams> {
+        (0..bars)
+            .map(|bar_idx| self.notes_params_at(bar_idx))
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LongNote {
+    pub lane: u8,
+    pub length: usize, // by chords
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct Chord {
     pub lanes: Vec<u8>,
     pub scratch: bool,
+    /// Lanes in `lanes` which start long notes
+    pub long_notes: Vec<LongNote>,
+    /// Length of the scratch long note (backspin) by chords, if `scratch` starts one
+    pub backspin: Option<usize>,
 }
 
 impl Chord {
-    fn new(lanes: Vec<u8>, scratch: bool) -> Self {
-        Chord { lanes, scratch }
+    pub(crate) fn new(lanes: Vec<u8>, scratch: bool) -> Self {
+        Chord {
+            lanes,
+            scratch,
+            long_notes: Vec::new(),
+            backspin: None,
+        }
     }
 
     fn contains(&self, lane: u8) -> bool {
         self.lanes.contains(&lane)
     }
+
+    /// Returns the chord with lanes reversed, as played by the other side in mirrored DP phrasing
+    fn mirrored(&self, lanes: usize) -> Self {
+        let mirror = |lane: u8| lanes as u8 - 1 - lane;
+        let mut mirrored_lanes: Vec<_> = self.lanes.iter().map(|&lane| mirror(lane)).collect();
+        mirrored_lanes.sort_unstable();
+        Chord {
+            lanes: mirrored_lanes,
+            scratch: self.scratch,
+            long_notes: self
+                .long_notes
+                .iter()
+                .map(|long_note| LongNote {
+                    lane: mirror(long_note.lane),
+                    length: long_note.length,
+                })
+                .collect(),
+            backspin: self.backspin,
+        }
+    }
+
+    pub fn long_note_length(&self, lane: u8) -> Option<usize> {
+        self.long_notes
+            .iter()
+            .find(|long_note| long_note.lane == lane)
+            .map(|long_note| long_note.length)
+    }
 }
 
 pub struct Chart {
     pub bpm: f32,
+    pub key_mode: KeyMode,
+    pub tempo_map: TempoMap,
     pub bars: Vec<Vec<Chord>>,
 }
 
 impl Chart {
-    fn new(bpm: f32) -> Self {
+    fn new(chart_params: &ChartParams) -> Self {
         Chart {
-            bpm,
+            bpm: chart_params.bpm,
+            key_mode: chart_params.key_mode,
+            tempo_map: chart_params.tempo_map.clone(),
             bars: Vec::new(),
         }
     }
+
+    pub fn bar_lengths(&self) -> Vec<usize> {
+        self.bars.iter().map(|bar| bar.len()).collect()
+    }
+
+    /// Duration of the chart in seconds, taking BPM changes and stops into account.
+    pub fn duration(&self) -> f32 {
+        self.tempo_map
+            .seconds_at(self.bpm, &self.bar_lengths(), self.bars.len(), 0.0)
+    }
+
+    /// Number of notes on the keys, not counting scratches
+    pub fn notes(&self) -> usize {
+        self.bars
+            .iter()
+            .flatten()
+            .map(|chord| chord.lanes.len())
+            .sum()
+    }
 }
 
 pub struct ChartDp {
     pub bpm: f32,
+    pub key_mode: KeyMode,
+    pub tempo_map: TempoMap,
     pub bars: Vec<Vec<[Chord; 2]>>,
 }
 
 impl ChartDp {
-    fn new(bpm: f32) -> Self {
+    fn new(chart_params: &ChartParams) -> Self {
         ChartDp {
-            bpm,
+            bpm: chart_params.bpm,
+            key_mode: chart_params.key_mode,
+            tempo_map: chart_params.tempo_map.clone(),
             bars: Vec::new(),
         }
     }
+
+    pub fn bar_lengths(&self) -> Vec<usize> {
+        self.bars.iter().map(|bar| bar.len()).collect()
+    }
+
+    /// Duration of the chart in seconds, taking BPM changes and stops into account.
+    pub fn duration(&self) -> f32 {
+        self.tempo_map
+            .seconds_at(self.bpm, &self.bar_lengths(), self.bars.len(), 0.0)
+    }
+
+    /// Number of notes on the keys of both sides, not counting scratches
+    pub fn notes(&self) -> usize {
+        self.bars
+            .iter()
+            .flatten()
+            .flat_map(|chords| chords.iter().map(|chord| chord.lanes.len()))
+            .sum()
+    }
+}
+
+/// Ergonomic cost of the chord being generated
+#[derive(PartialEq, Debug, Clone)]
+struct ChordErgonomics {
+    ergonomics: Ergonomics,
+    history: Vec<Chord>,
+    scratch: bool,
 }
 
 #[derive(PartialEq, Debug, Clone)]
@@ -98,16 +536,48 @@ struct NoteRandomizer {
     weight: Vec<f32>,
     priorities: Vec<usize>,
     reroll_chances: Vec<f32>,
+    held: Vec<bool>,
+    ergonomics: Option<ChordErgonomics>,
 }
 
 impl NoteRandomizer {
-    fn new(priorities: Vec<usize>, reroll_chances: Vec<f32>, weight: Vec<f32>) -> Self {
-        assert!(priorities.len() == LANES);
-        assert!(reroll_chances.len() == LANES);
+    fn new(
+        priorities: Vec<usize>,
+        reroll_chances: Vec<f32>,
+        weight: Vec<f32>,
+        held: Vec<bool>,
+    ) -> Self {
+        assert!(priorities.len() == weight.len());
+        assert!(reroll_chances.len() == weight.len());
+        assert!(held.len() == weight.len());
         NoteRandomizer {
             weight,
             priorities,
             reroll_chances,
+            held,
+            ergonomics: None,
+        }
+    }
+
+    /// Applies the ergonomic cost of lanes, where `history` is the chords before
+    fn set_ergonomics(&mut self, ergonomics: &Ergonomics, history: &[Chord], scratch: bool) {
+        self.ergonomics = Some(ChordErgonomics {
+            ergonomics: ergonomics.clone(),
+            history: history.to_vec(),
+            scratch,
+        });
+    }
+
+    fn lane_weight(&self, lane: u8, selected: &[u8]) -> f32 {
+        let weight = self.weight[lane as usize];
+        match &self.ergonomics {
+            Some(chord) => {
+                weight
+                    * chord
+                        .ergonomics
+                        .weight_factor(lane, &chord.history, selected, chord.scratch)
+            }
+            None => weight,
         }
     }
 
@@ -133,7 +603,13 @@ impl NoteRandomizer {
             })
             .collect();
 
-        NoteRandomizer::new(priorities, reroll_chances, context.bias_to_weight())
+        let held = context
+            .held_chords
+            .iter()
+            .map(|&chords| chords > 0)
+            .collect();
+
+        NoteRandomizer::new(priorities, reroll_chances, context.bias_to_weight(), held)
     }
 
     fn generate(&mut self, mut count: usize, rng: &mut RNG) -> Vec<u8> {
@@ -141,6 +617,10 @@ impl NoteRandomizer {
         let mut selected_notes: Vec<u8> = Vec::new();
 
         for (i, priority) in self.priorities.iter().copied().enumerate() {
+            if self.held[i] {
+                continue;
+            }
+
             if lanes_by_priority.len() < priority + 1 {
                 lanes_by_priority.resize(priority + 1, Vec::new());
             }
@@ -158,14 +638,18 @@ impl NoteRandomizer {
                     break 'outer;
                 }
 
-                let weight_sum: f32 = lanes.iter().map(|&lane| self.weight[lane as usize]).sum();
+                let weights: Vec<f32> = lanes
+                    .iter()
+                    .map(|&lane| self.lane_weight(lane, &selected_notes))
+                    .collect();
+                let weight_sum: f32 = weights.iter().sum();
                 let rng_next = rng.next_f32() * weight_sum;
 
-                let (idx, _) = lanes
+                let (idx, _) = weights
                     .iter()
                     .enumerate()
-                    .scan(0.0, |weight_acc, (i, &lane)| {
-                        *weight_acc += self.weight[lane as usize];
+                    .scan(0.0, |weight_acc, (i, &weight)| {
+                        *weight_acc += weight;
                         Some((i, *weight_acc))
                     })
                     .find(|&(_, weight_acc)| rng_next < weight_acc)
@@ -193,9 +677,16 @@ impl NoteRandomizer {
 
 struct GenerateContext<'a> {
     generated_chords: Vec<Chord>,
+    chords_per_bar: usize,
+    total_chords: usize,
     rng: RNG,
     ongoing_jacks: Vec<usize>,
+    held_chords: Vec<usize>,
+    held_scratch_chords: usize,
+    has_scratch: bool,
     bias: Vec<f32>,
+    /// Chords left in the current template, in reverse order
+    template_chords: Vec<Chord>,
     notes_params: &'a NotesParams,
 }
 
@@ -203,16 +694,87 @@ impl<'a> GenerateContext<'a> {
     fn new(chart_params: &ChartParams, notes_params: &'a NotesParams) -> Self {
         GenerateContext {
             generated_chords: Vec::new(),
+            chords_per_bar: chart_params.chords_per_bar,
+            total_chords: chart_params.bars * chart_params.chords_per_bar,
             rng: RNG::new_u64(chart_params.seed),
-            ongoing_jacks: vec![0; LANES],
-            bias: vec![0.0; LANES],
+            ongoing_jacks: vec![0; chart_params.key_mode.lanes()],
+            held_chords: vec![0; chart_params.key_mode.lanes()],
+            held_scratch_chords: 0,
+            has_scratch: chart_params.key_mode.has_scratch(),
+            bias: vec![0.0; chart_params.key_mode.lanes()],
+            template_chords: Vec::new(),
             notes_params,
         }
     }
 
+    /// Rolls the number of keys of the chord from the chord density
+    fn roll_count(&mut self, chord_idx: usize) -> usize {
+        self.notes_params
+            .chord_density
+            .generate_chord_density(chord_idx, &mut self.rng) as usize
+    }
+
+    /// Chords after the current one, within which long notes must end
+    fn chords_left(&self) -> usize {
+        self.total_chords
+            .saturating_sub(self.generated_chords.len() + 1)
+    }
+
+    fn generate_long_notes(&mut self, lanes: &[u8]) -> Vec<LongNote> {
+        let chords_left = self.chords_left();
+
+        lanes
+            .iter()
+            .filter_map(|&lane| {
+                let length = self
+                    .notes_params
+                    .long_notes
+                    .generate_length(&mut self.rng)?;
+                let length = length.min(chords_left);
+                (length > 0).then_some(LongNote { lane, length })
+            })
+            .collect()
+    }
+
+    /// Rolls whether the chord has a scratch with `ScratchParams`, and the length of its backspin
+    fn generate_scratch(
+        &mut self,
+        scratch_params: &ScratchParams,
+        bar_idx: usize,
+        chord_idx: usize,
+    ) -> (bool, Option<usize>) {
+        if !self.has_scratch || self.held_scratch_chords > 0 {
+            return (false, None);
+        }
+        let density = scratch_params.density_at(bar_idx);
+        if density.generate_chord_density(chord_idx, &mut self.rng) == 0 {
+            return (false, None);
+        }
+
+        let chords_left = self.chords_left();
+        let backspin = scratch_params
+            .backspin
+            .generate_length(&mut self.rng)
+            .map(|length| length.min(chords_left))
+            .filter(|&length| length > 0);
+        (true, backspin)
+    }
+
     fn push_chord(&mut self, chord: Chord) {
+        for held_chords in self.held_chords.iter_mut() {
+            *held_chords = held_chords.saturating_sub(1);
+        }
+        self.held_scratch_chords = self.held_scratch_chords.saturating_sub(1);
+        if let Some(length) = chord.backspin {
+            self.held_scratch_chords = length;
+        }
+
+        for long_note in chord.long_notes.iter() {
+            self.held_chords[long_note.lane as usize] = long_note.length;
+        }
+
         for (i, bias) in self.bias.iter_mut().enumerate() {
-            *bias = *bias * self.notes_params.scatter.decay;
+            *bias *= self.notes_params.scatter.decay;
 
             if chord.contains(i as u8) {
                 *bias += self.notes_params.scatter.strength;
@@ -229,6 +791,38 @@ impl<'a> GenerateContext<'a> {
         self.generated_chords.push(chord);
     }
 
+    /// Returns whether the chord should be rerolled, rolling the chance of each constraint it breaks.
+    fn breaks_constraints(&mut self, candidate: &Chord) -> bool {
+        let notes_params = self.notes_params;
+        notes_params
+            .constraints
+            .iter()
+            .any(|(constraint, reroll_chance)| {
+                constraint.is_violated_by(&self.generated_chords, candidate)
+                    && *reroll_chance > self.rng.next_f32()
+            })
+    }
+
+    /// Takes the next chord of the current template, starting a template of a random family if needed.
+    /// Lanes held by long notes are left out.
+    fn next_template_chord(&mut self) -> Chord {
+        let templates = &self.notes_params.templates;
+        if self.template_chords.is_empty() {
+            let family = templates[(self.rng.next() % templates.len() as u64) as usize];
+            self.template_chords = family.generate(self.bias.len(), &mut self.rng);
+            self.template_chords.reverse();
+        }
+
+        let Some(mut chord) = self.template_chords.pop() else {
+            return Chord::new(Vec::new(), false);
+        };
+        chord
+            .lanes
+            .retain(|&lane| self.held_chords[lane as usize] == 0);
+        chord.scratch &= self.has_scratch && self.held_scratch_chords == 0;
+        chord
+    }
+
     fn bias_to_weight(&self) -> Vec<f32> {
         let min_bias = self.bias.iter().copied().fold(f32::MAX, f32::min);
         self.bias
@@ -244,14 +838,149 @@ impl<'a> GenerateContext<'a> {
     }
 }
 
-fn generate_bar(bar_idx: usize, context: &mut GenerateContext, has_scratch: bool) -> Vec<Chord> {
+/// `legacy_scratch` enables the scratch on the first chord of every 8 bars when `NotesParams` has no `ScratchParams`.
+fn generate_bar(bar_idx: usize, context: &mut GenerateContext, legacy_scratch: bool) -> Vec<Chord> {
+    if !context.notes_params.templates.is_empty() {
+        return generate_template_bar(context);
+    }
+
+    (0..context.chords_per_bar)
+        .map(|i| {
+            let count = context.roll_count(i);
+            generate_chord(bar_idx, i, count, context, legacy_scratch)
+        })
+        .collect()
+}
+
+/// Generates a chord with `count` keys, fewer if a scratch limits them
+fn generate_chord(
+    bar_idx: usize,
+    i: usize,
+    mut count: usize,
+    context: &mut GenerateContext,
+    legacy_scratch: bool,
+) -> Chord {
+    let notes_params = context.notes_params;
+    let (scratch, backspin) = match &notes_params.scratch {
+        Some(scratch_params) => {
+            let (scratch, backspin) = context.generate_scratch(scratch_params, bar_idx, i);
+            if let (true, Some(max_keys)) = (scratch, scratch_params.max_keys) {
+                count = count.min(max_keys);
+            }
+            (scratch, backspin)
+        }
+        None => (bar_idx.is_multiple_of(8) && i == 0 && legacy_scratch, None),
+    };
+    let randomizer = |context: &GenerateContext| {
+        let mut randomizer = NoteRandomizer::from_context(context);
+        if let Some(ergonomics) = &notes_params.ergonomics {
+            let history_start = context
+                .generated_chords
+                .len()
+                .saturating_sub(SAME_FINGER_WINDOW);
+            randomizer.set_ergonomics(
+                ergonomics,
+                &context.generated_chords[history_start..],
+                scratch,
+            );
+        }
+        randomizer
+    };
+    let mut notes = randomizer(context).generate(count, &mut context.rng);
+    for _ in 0..MAX_CONSTRAINT_REROLLS {
+        if !context.breaks_constraints(&Chord::new(notes.clone(), scratch)) {
+            break;
+        }
+        notes = randomizer(context).generate(count, &mut context.rng);
+    }
+    let long_notes = context.generate_long_notes(&notes);
+    let chord = Chord {
+        long_notes,
+        backspin,
+        ..Chord::new(notes, scratch)
+    };
+    context.push_chord(chord.clone());
+    chord
+}
+
+/// Generates a bar of both sides, coordinated by `DpParams`
+fn generate_bar_dp(
+    bar_idx: usize,
+    contexts: &mut [GenerateContext; 2],
+    dp_params: &DpParams,
+) -> Vec<[Chord; 2]> {
+    let [left, right] = contexts;
+    if !left.notes_params.templates.is_empty() || !right.notes_params.templates.is_empty() {
+        let bar_left = generate_bar(bar_idx, left, false);
+        let bar_right = generate_bar(bar_idx, right, false);
+        return bar_left
+            .into_iter()
+            .zip(bar_right)
+            .map(|(a, b)| [a, b])
+            .collect();
+    }
+
+    let lanes = left.bias.len();
+    (0..left.chords_per_bar)
+        .map(|i| {
+            let mut counts = [left.roll_count(i), right.roll_count(i)];
+
+            if dp_params.split_density {
+                let total = counts[0];
+                counts = [0, 0];
+                for _ in 0..total {
+                    let side = (left.rng.next_f32() < 0.5) as usize;
+                    if counts[side] < lanes {
+                        counts[side] += 1;
+                    } else {
+                        counts[1 - side] += 1;
+                    }
+                }
+            }
+
+            if let DpPhrasing::CallAndResponse { chords } = dp_params.phrasing {
+                let resting_side = 1 - ((bar_idx * left.chords_per_bar + i) / chords) % 2;
+                counts[1 - resting_side] += counts[resting_side];
+                counts[resting_side] = 0;
+            }
+
+            if let Some(max_hand_keys) = dp_params.max_hand_keys {
+                for count in counts.iter_mut() {
+                    *count = (*count).min(max_hand_keys);
+                }
+            }
+
+            if let Some(size) = dp_params.big_chord_size {
+                if counts.iter().all(|&count| count >= size) {
+                    let smaller = (counts[1] < counts[0]) as usize;
+                    counts[smaller] = size - 1;
+                }
+            }
+
+            let chord_left = generate_chord(bar_idx, i, counts[0], left, false);
+            let chord_right = match dp_params.phrasing {
+                DpPhrasing::Mirrored => {
+                    let chord = chord_left.mirrored(lanes);
+                    right.push_chord(chord.clone());
+                    chord
+                }
+                _ => generate_chord(bar_idx, i, counts[1], right, false),
+            };
+            [chord_left, chord_right]
+        })
+        .collect()
+}
+
+fn generate_template_bar(context: &mut GenerateContext) -> Vec<Chord> {
     let chord_density = &context.notes_params.chord_density;
-    (0..CHORDS_PER_BAR)
+    (0..context.chords_per_bar)
         .map(|i| {
             let count = chord_density.generate_chord_density(i, &mut context.rng);
-            let mut randomizer = NoteRandomizer::from_context(context);
-            let notes = randomizer.generate(count as usize, &mut context.rng);
-            let chord = Chord::new(notes, bar_idx % 8 == 0 && i == 0 && has_scratch);
+            let chord = if count == 0 {
+                Chord::new(Vec::new(), false)
+            } else {
+                context.next_template_chord()
+            };
             context.push_chord(chord.clone());
             chord
         })
@@ -259,38 +988,106 @@ fn generate_bar(bar_idx: usize, context: &mut GenerateContext, has_scratch: bool
 }
 
 pub fn generate_chart(chart_params: &ChartParams, notes_params: &NotesParams) -> Chart {
-    let mut context = GenerateContext::new(chart_params, notes_params);
-    let mut chart = Chart::new(chart_params.bpm);
+    generate_chart_with_schedule(chart_params, &NotesSchedule::new(notes_params.clone()))
+}
 
-    for bar_idx in 0..chart_params.bars {
-        let bar = generate_bar(bar_idx, &mut context, true);
+pub fn generate_chart_with_schedule(chart_params: &ChartParams, schedule: &NotesSchedule) -> Chart {
+    let notes_params = schedule.bars(chart_params.bars);
+    let Some(first_notes_params) = notes_params.first() else {
+        return Chart::new(chart_params);
+    };
+    let mut context = GenerateContext::new(chart_params, first_notes_params);
+    let mut chart = Chart::new(chart_params);
+    let legacy_scratch = chart_params.key_mode.has_scratch();
+
+    for (bar_idx, notes_params) in notes_params.iter().enumerate() {
+        context.notes_params = notes_params;
+        let bar = generate_bar(bar_idx, &mut context, legacy_scratch);
         chart.bars.push(bar);
     }
 
     chart
 }
 
+/// Generates a chart by sampling chords from a model learned from BMS charts instead of rolling them.
+/// Gaps of the model are converted to the grid of `chart_params`, and lanes the key mode lacks are dropped.
+pub fn generate_chart_with_model(chart_params: &ChartParams, model: &MarkovModel) -> Chart {
+    let mut chart = Chart::new(chart_params);
+    let mut rng = RNG::new_u64(chart_params.seed);
+    let total_chords = chart_params.bars * chart_params.chords_per_bar;
+    let lanes = chart_params.key_mode.lanes();
+    let has_scratch = chart_params.key_mode.has_scratch();
+
+    let mut chords = vec![Chord::new(Vec::new(), false); total_chords];
+    let mut history = Vec::new();
+    let mut position = 0;
+    while let Some(model_chord) = model.sample(&history, &mut rng) {
+        if !history.is_empty() {
+            let gap = (model_chord.gap * chart_params.chords_per_bar) as f32
+                / model.chords_per_bar() as f32;
+            position += (gap.round() as usize).max(1);
+        }
+        let Some(chord) = chords.get_mut(position) else {
+            break;
+        };
+
+        let chord_lanes = model_chord
+            .lanes
+            .iter()
+            .copied()
+            .filter(|&lane| (lane as usize) < lanes)
+            .collect();
+        *chord = Chord::new(chord_lanes, model_chord.scratch && has_scratch);
+        history.push(model_chord);
+    }
+
+    chart.bars = chords
+        .chunks(chart_params.chords_per_bar)
+        .map(|bar| bar.to_vec())
+        .collect();
+    chart
+}
+
 pub fn generate_chart_dp(
     chart_params: &ChartParams,
     notes_params_left: &NotesParams,
     notes_params_right: &NotesParams,
 ) -> ChartDp {
-    let mut context_left = GenerateContext::new(chart_params, notes_params_left);
+    generate_chart_dp_with_schedule(
+        chart_params,
+        &NotesSchedule::new(notes_params_left.clone()),
+        &NotesSchedule::new(notes_params_right.clone()),
+    )
+}
+
+pub fn generate_chart_dp_with_schedule(
+    chart_params: &ChartParams,
+    schedule_left: &NotesSchedule,
+    schedule_right: &NotesSchedule,
+) -> ChartDp {
+    assert!(chart_params.key_mode.supports_dp());
+
+    let notes_params_left = schedule_left.bars(chart_params.bars);
+    let notes_params_right = schedule_right.bars(chart_params.bars);
+    let (Some(first_notes_params_left), Some(first_notes_params_right)) =
+        (notes_params_left.first(), notes_params_right.first())
+    else {
+        return ChartDp::new(chart_params);
+    };
+
+    let context_left = GenerateContext::new(chart_params, first_notes_params_left);
     let right_chart_params = ChartParams {
         seed: !chart_params.seed,
         ..chart_params.clone()
     };
-    let mut context_right = GenerateContext::new(&right_chart_params, notes_params_right);
-    let mut chart = ChartDp::new(chart_params.bpm);
+    let context_right = GenerateContext::new(&right_chart_params, first_notes_params_right);
+    let mut contexts = [context_left, context_right];
+    let mut chart = ChartDp::new(chart_params);
 
     for bar_idx in 0..chart_params.bars {
-        let bar_left = generate_bar(bar_idx, &mut context_left, false);
-        let bar_right = generate_bar(bar_idx, &mut context_right, false);
-        let bar = bar_left
-            .into_iter()
-            .zip(bar_right.into_iter())
-            .map(|(a, b)| [a, b])
-            .collect();
+        contexts[0].notes_params = &notes_params_left[bar_idx];
+        contexts[1].notes_params = &notes_params_right[bar_idx];
+        let bar = generate_bar_dp(bar_idx, &mut contexts, &chart_params.dp_params);
         chart.bars.push(bar);
     }
 
@@ -301,8 +1098,17 @@ pub fn generate_chart_dp(
 mod test {
     use super::{generate_chart, ChartParams, GenerateContext, NoteRandomizer};
     use crate::{
+        analysis::analyze_chart,
         chord::ChordDensity,
-        generate::{Chord, NotesParams, Scatter, CHORDS_PER_BAR, LANES},
+        constraint::PatternConstraint,
+        generate::{
+            generate_chart_dp, generate_chart_with_model, generate_chart_with_schedule, Chord,
+            DpParams, DpPhrasing, LongNoteParams, NotesParams, NotesSchedule, Scatter,
+            ScratchParams, DEFAULT_CHORDS_PER_BAR,
+        },
+        key_mode::KeyMode,
+        markov::{MarkovModel, ModelChord},
+        template::PatternFamily,
     };
     use approx::assert_relative_eq;
 
@@ -385,8 +1191,9 @@ mod test {
             randomizer,
             NoteRandomizer::new(
                 vec![1, 0, 1, 0, 1, 0, 1],
-                vec![0.0; LANES],
-                vec![1.0; LANES]
+                vec![0.0; 7],
+                vec![1.0; 7],
+                vec![false; 7],
             )
         );
 
@@ -416,7 +1223,7 @@ mod test {
         assert_relative_eq!(chart_params.bpm, chart.bpm);
 
         for bar in chart.bars.iter() {
-            assert_eq!(bar.len(), CHORDS_PER_BAR);
+            assert_eq!(bar.len(), DEFAULT_CHORDS_PER_BAR);
         }
 
         let flatten_chart: Vec<_> = chart.bars.into_iter().flatten().collect();
@@ -441,4 +1248,385 @@ mod test {
             }
         }
     }
+
+    #[test]
+    fn test_generate_chart_key_modes() {
+        let notes_params = NotesParams::new(
+            ChordDensity::new(vec![vec![200]]),
+            0.0,
+            Scatter::new(0.0, 0.0, false),
+        );
+
+        for (key_mode, lanes) in [(KeyMode::Beat5, 5), (KeyMode::PopN9, 9)] {
+            let mut chart_params = ChartParams::new(222.22, 16, 199024);
+            chart_params.set_key_mode(key_mode);
+
+            let chart = generate_chart(&chart_params, &notes_params);
+            let flatten_chart: Vec<_> = chart.bars.into_iter().flatten().collect();
+
+            assert!(flatten_chart
+                .iter()
+                .all(|chord| chord.lanes.iter().all(|&lane| (lane as usize) < lanes)));
+            assert!(flatten_chart
+                .iter()
+                .any(|chord| chord.lanes.contains(&(lanes as u8 - 1))));
+            assert_eq!(
+                flatten_chart.iter().any(|chord| chord.scratch),
+                key_mode.has_scratch()
+            );
+
+            for window in flatten_chart.windows(2) {
+                assert!(window[0].lanes.iter().all(|&x| !window[1].contains(x)));
+            }
+        }
+    }
+
+    #[test]
+    fn test_generate_chart_triplets() {
+        let mut chart_params = ChartParams::new(222.22, 16, 199024);
+        chart_params.set_chords_per_bar(12);
+        let notes_params = NotesParams::new(
+            ChordDensity::from_subdivisions(&[(3, 100), (12, 100)], 12),
+            0.0,
+            Scatter::new(0.0, 0.0, false),
+        );
+
+        let chart = generate_chart(&chart_params, &notes_params);
+
+        for bar in chart.bars.iter() {
+            assert_eq!(bar.len(), 12);
+
+            for (i, chord) in bar.iter().enumerate() {
+                let expected = if i % 4 == 0 { 2 } else { 1 };
+                assert_eq!(chord.lanes.len(), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_generate_long_notes() {
+        let chart_params = ChartParams::new(222.22, 64, 199024);
+        let mut notes_params = NotesParams::new(
+            ChordDensity::new(vec![vec![200]]),
+            0.0,
+            Scatter::new(0.0, 0.0, false),
+        );
+        notes_params.set_long_notes(LongNoteParams::new(0.5, 1, 8));
+
+        let chart = generate_chart(&chart_params, &notes_params);
+        let flatten_chart: Vec<_> = chart.bars.into_iter().flatten().collect();
+
+        assert!(flatten_chart
+            .iter()
+            .any(|chord| !chord.long_notes.is_empty()));
+
+        for (i, chord) in flatten_chart.iter().enumerate() {
+            for long_note in chord.long_notes.iter() {
+                assert!(chord.contains(long_note.lane));
+                assert!(i + long_note.length < flatten_chart.len());
+
+                for held_chord in &flatten_chart[i + 1..=i + long_note.length] {
+                    assert!(!held_chord.contains(long_note.lane));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_notes_schedule() {
+        let notes_params = |density: u64, jack_tolerance: f32, scatter: f32| {
+            NotesParams::new(
+                ChordDensity::new(vec![vec![density]]),
+                jack_tolerance,
+                Scatter::new(scatter.abs(), 0.5, scatter < 0.0),
+            )
+        };
+        let mut schedule = NotesSchedule::new(notes_params(100, 0.0, 2.0));
+        schedule.push_section(4, notes_params(300, 1.0, -2.0));
+        schedule.push_section(8, notes_params(100, 0.0, 0.0));
+
+        let at = schedule.notes_params_at(6);
+        assert_eq!(at.chord_density, ChordDensity::new(vec![vec![300]]));
+        assert_relative_eq!(at.jack_tolerance, 1.0);
+
+        schedule.set_interpolate(true);
+        let at = schedule.notes_params_at(1);
+        assert_eq!(at.chord_density, ChordDensity::new(vec![vec![150]]));
+        assert_relative_eq!(at.jack_tolerance, 0.25);
+        assert_relative_eq!(at.scatter.strength, 1.0);
+        assert!(!at.scatter.inverted);
+        assert!(schedule.notes_params_at(3).scatter.inverted);
+        assert_eq!(
+            schedule.notes_params_at(5).chord_density,
+            ChordDensity::new(vec![vec![250]])
+        );
+        assert_eq!(
+            schedule.notes_params_at(20).chord_density,
+            ChordDensity::new(vec![vec![100]])
+        );
+
+        let chart_params = ChartParams::new(150.0, 12, 199024);
+        schedule.set_interpolate(false);
+        let chart = generate_chart_with_schedule(&chart_params, &schedule);
+        let notes: Vec<usize> = chart
+            .bars
+            .iter()
+            .map(|bar| bar.iter().map(|chord| chord.lanes.len()).sum())
+            .collect();
+
+        assert_eq!(notes, [vec![16; 4], vec![48; 4], vec![16; 4]].concat());
+    }
+
+    #[test]
+    fn test_generate_chart_constraints() {
+        let chart_params = ChartParams::new(150.0, 64, 199024);
+        let mut notes_params = NotesParams::new(
+            ChordDensity::new(vec![vec![100]]),
+            0.0,
+            Scatter::new(0.0, 0.0, false),
+        );
+
+        let report = analyze_chart(&generate_chart(&chart_params, &notes_params));
+        assert!(!report.trills.is_empty());
+        assert!(!report.stairs.is_empty());
+
+        notes_params.push_constraint(PatternConstraint::Trill { max_length: 3 }, 1.0);
+        notes_params.push_constraint(PatternConstraint::Stairs { max_length: 3 }, 1.0);
+
+        let report = analyze_chart(&generate_chart(&chart_params, &notes_params));
+        assert!(report.trills.is_empty());
+        assert!(report.stairs.is_empty());
+
+        let mut notes_params = NotesParams::new(
+            ChordDensity::new(vec![vec![400]]),
+            7.0,
+            Scatter::new(0.0, 0.0, false),
+        );
+        let shape = vec![0, 2, 4, 6];
+        notes_params.push_constraint(
+            PatternConstraint::ChordShape {
+                lanes: shape.clone(),
+            },
+            1.0,
+        );
+
+        let chart = generate_chart(&chart_params, &notes_params);
+        assert!(chart
+            .bars
+            .iter()
+            .flatten()
+            .all(|chord| chord.lanes != shape));
+    }
+
+    #[test]
+    fn test_generate_chart_scratch() {
+        let chart_params = ChartParams::new(150.0, 32, 199024);
+        let mut notes_params = NotesParams::new(
+            ChordDensity::new(vec![vec![300]]),
+            0.0,
+            Scatter::new(0.0, 0.0, false),
+        );
+        let mut scratch_params = ScratchParams::new(ChordDensity::new(vec![vec![25]]));
+        scratch_params.set_max_keys(1);
+        scratch_params.set_backspin(LongNoteParams::new(0.5, 1, 4));
+        notes_params.set_scratch(scratch_params);
+
+        let chords: Vec<_> = generate_chart(&chart_params, &notes_params)
+            .bars
+            .into_iter()
+            .flatten()
+            .collect();
+        assert!(chords.iter().any(|chord| chord.backspin.is_some()));
+        for (i, chord) in chords.iter().enumerate() {
+            if chord.scratch {
+                assert!(chord.lanes.len() <= 1);
+            } else {
+                assert_eq!(chord.lanes.len(), 3);
+            }
+            if let Some(length) = chord.backspin {
+                assert!(chords[i + 1..=i + length]
+                    .iter()
+                    .all(|chord| !chord.scratch));
+            }
+        }
+
+        let mut scratch_params = ScratchParams::new(ChordDensity::new(vec![vec![0]]));
+        scratch_params.push_rush(4..8, ChordDensity::new(vec![vec![100]]));
+        notes_params.set_scratch(scratch_params);
+
+        let chart = generate_chart_dp(&chart_params, &notes_params, &notes_params);
+        for (bar_idx, bar) in chart.bars.iter().enumerate() {
+            let rush = (4..8).contains(&bar_idx);
+            assert!(bar.iter().flatten().all(|chord| chord.scratch == rush));
+        }
+    }
+
+    #[test]
+    fn test_generate_chart_dp_params() {
+        let mut chart_params = ChartParams::new(150.0, 16, 199024);
+        let notes_params = NotesParams::new(
+            ChordDensity::new(vec![vec![300]]),
+            0.0,
+            Scatter::new(0.0, 0.0, false),
+        );
+        let generate = |chart_params: &ChartParams| -> Vec<[Chord; 2]> {
+            generate_chart_dp(chart_params, &notes_params, &notes_params)
+                .bars
+                .into_iter()
+                .flatten()
+                .collect()
+        };
+
+        let mut dp_params = DpParams::new();
+        dp_params.set_phrasing(DpPhrasing::Mirrored);
+        chart_params.set_dp_params(dp_params);
+        for [left, right] in generate(&chart_params) {
+            let mirrored: Vec<_> = left.lanes.iter().map(|&lane| 6 - lane).rev().collect();
+            assert_eq!(right.lanes, mirrored);
+        }
+
+        let mut dp_params = DpParams::new();
+        dp_params.set_phrasing(DpPhrasing::CallAndResponse { chords: 4 });
+        dp_params.set_split_density(true);
+        chart_params.set_dp_params(dp_params);
+        for (i, [left, right]) in generate(&chart_params).into_iter().enumerate() {
+            let (playing, resting) = if (i / 4) % 2 == 0 {
+                (left, right)
+            } else {
+                (right, left)
+            };
+            assert_eq!(playing.lanes.len(), 3);
+            assert!(resting.lanes.is_empty());
+        }
+
+        let mut dp_params = DpParams::new();
+        dp_params.set_split_density(true);
+        dp_params.set_max_hand_keys(2);
+        chart_params.set_dp_params(dp_params);
+        let chords = generate(&chart_params);
+        assert!(chords
+            .iter()
+            .all(|[left, right]| left.lanes.len() <= 2 && right.lanes.len() <= 2));
+        assert!(chords
+            .iter()
+            .any(|[left, right]| left.lanes.len() + right.lanes.len() == 3));
+
+        let mut dp_params = DpParams::new();
+        dp_params.set_big_chord_size(3);
+        chart_params.set_dp_params(dp_params);
+        for [left, right] in generate(&chart_params) {
+            assert!(left.lanes.len() < 3 || right.lanes.len() < 3);
+        }
+    }
+
+    #[test]
+    fn test_generate_chart_with_model() {
+        let chord = |lane| ModelChord {
+            gap: 2,
+            lanes: vec![lane],
+            scratch: false,
+        };
+        let chords: Vec<_> = [0, 1, 2, 1].repeat(8).into_iter().map(chord).collect();
+        let mut model = MarkovModel::new(KeyMode::Beat7, 1, 8);
+        model.train(&chords);
+
+        let chart_params = ChartParams::new(150.0, 4, 199024);
+        let chart = generate_chart_with_model(&chart_params, &model);
+        assert_eq!(chart.bars.len(), 4);
+        for (i, chord) in chart.bars.iter().flatten().enumerate() {
+            if i % 4 == 0 {
+                assert_eq!(chord.lanes.len(), 1);
+            } else {
+                assert!(chord.lanes.is_empty());
+            }
+        }
+        assert!(chart
+            .bars
+            .iter()
+            .flatten()
+            .all(|chord| chord.lanes.iter().all(|&lane| lane <= 2)));
+    }
+
+    #[test]
+    fn test_generate_chart_templates() {
+        let chart_params = ChartParams::new(150.0, 32, 199024);
+        let mut notes_params = NotesParams::new(
+            ChordDensity::new(vec![vec![0], vec![100]]),
+            0.0,
+            Scatter::new(0.0, 0.0, false),
+        );
+        notes_params.set_templates(vec![PatternFamily::Trill]);
+
+        let chart = generate_chart(&chart_params, &notes_params);
+        assert!(chart
+            .bars
+            .iter()
+            .flatten()
+            .step_by(2)
+            .all(|chord| chord.lanes.is_empty()));
+        let chords: Vec<_> = chart.bars.iter().flatten().skip(1).step_by(2).collect();
+        assert_eq!(chords.len(), 32 * DEFAULT_CHORDS_PER_BAR / 2);
+        assert!(chords.chunks(4).all(|template| {
+            template[0].lanes.len() == 1
+                && template[0] == template[2]
+                && template[1] == template[3]
+                && template[0] != template[1]
+        }));
+    }
+
+    #[test]
+    fn test_seed_without_long_notes() {
+        // Charts without long notes must stay the same as before long notes were added
+        let chart_params = ChartParams::new(150.0, 2, 42);
+        let notes_params = NotesParams::new(
+            ChordDensity::from_power_of_two(&[0, 0, 100, 100, 100]),
+            0.0,
+            Scatter::new(0.0, 0.5, false),
+        );
+
+        let chart = generate_chart(&chart_params, &notes_params);
+        let lanes: Vec<_> = chart
+            .bars
+            .iter()
+            .flatten()
+            .map(|chord| chord.lanes.clone())
+            .collect();
+        assert_eq!(
+            lanes,
+            [
+                vec![1, 4, 6],
+                vec![3],
+                vec![1, 5],
+                vec![6],
+                vec![0, 1, 2],
+                vec![4],
+                vec![1, 2],
+                vec![6],
+                vec![0, 3, 5],
+                vec![6],
+                vec![3, 5],
+                vec![2],
+                vec![3, 4, 5],
+                vec![6],
+                vec![0, 1],
+                vec![2],
+                vec![3, 4, 6],
+                vec![1],
+                vec![2, 5],
+                vec![3],
+                vec![1, 2, 6],
+                vec![0],
+                vec![3, 6],
+                vec![1],
+                vec![2, 3, 4],
+                vec![1],
+                vec![5, 6],
+                vec![2],
+                vec![3, 4, 5],
+                vec![6],
+                vec![2, 5],
+                vec![0],
+            ]
+        );
+    }
 }
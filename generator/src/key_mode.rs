@@ -0,0 +1,50 @@
+/// Key layout of a side
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum KeyMode {
+    /// 5 keys and scratch (10 keys in DP)
+    Beat5,
+    /// 7 keys and scratch (14 keys in DP)
+    Beat7,
+    /// 9 keys without scratch (PMS)
+    PopN9,
+}
+
+impl KeyMode {
+    pub fn lanes(&self) -> usize {
+        self.key_channels().len()
+    }
+
+    pub fn has_scratch(&self) -> bool {
+        self.scratch_channel().is_some()
+    }
+
+    pub fn supports_dp(&self) -> bool {
+        *self != KeyMode::PopN9
+    }
+
+    /// BMS channels of the keys of the 1P side, from left to right.
+    /// Channels of the 2P side are obtained by adding 10.
+    pub fn key_channels(&self) -> &'static [u8] {
+        match self {
+            KeyMode::Beat5 => &[11, 12, 13, 14, 15],
+            KeyMode::Beat7 => &[11, 12, 13, 14, 15, 18, 19],
+            KeyMode::PopN9 => &[11, 12, 13, 14, 15, 22, 23, 24, 25],
+        }
+    }
+
+    pub fn scratch_channel(&self) -> Option<u8> {
+        match self {
+            KeyMode::Beat5 | KeyMode::Beat7 => Some(16),
+            KeyMode::PopN9 => None,
+        }
+    }
+
+    /// File extension conventionally used for the mode
+    pub fn extension(&self) -> &'static str {
+        match self {
+            KeyMode::Beat5 => "bms",
+            KeyMode::Beat7 => "bme",
+            KeyMode::PopN9 => "pms",
+        }
+    }
+}
@@ -3,11 +3,14 @@ mod sequence;
 
 use keysound_gen::KeySoundSource;
 
-pub use chord::{ChordKeySound, ChordRoot, ChordType};
+pub use chord::{parse_chord, ChordKeySound, ChordRoot, ChordType};
 pub use sequence::{Note, Oscillator, SequenceKeySound};
 
 pub trait KeySound {
     fn sources(&self) -> &[KeySoundSource];
+    /// Called before any sound of the bar is requested, as bars can have different numbers of chords.
+    /// Implementations must not panic if it was skipped.
+    fn begin_bar(&mut self, _bar_idx: usize, _chords: usize) {}
     fn key_sound_idx(&mut self, bar_idx: usize, chord_pos: usize, chord_idx: usize) -> usize;
     fn scratch_sound_idx(&mut self, bar_idx: usize, chord_pos: usize) -> usize;
     fn bgm_sound_indices(&mut self, bar_idx: usize) -> Vec<Vec<Option<usize>>>;
@@ -65,6 +65,55 @@ impl ChordType {
     }
 }
 
+/// Parses a chord name such as `D`, `F#m`, `Bb7`, `CM7` or `AmM7`
+pub fn parse_chord(name: &str) -> Option<(ChordRoot, ChordType)> {
+    let mut chars = name.chars();
+    let natural = match chars.next()? {
+        'C' => 0,
+        'D' => 2,
+        'E' => 4,
+        'F' => 5,
+        'G' => 7,
+        'A' => 9,
+        'B' => 11,
+        _ => return None,
+    };
+    let rest = chars.as_str();
+    let (index, suffix) = if let Some(suffix) = rest.strip_prefix('#') {
+        (natural + 1, suffix)
+    } else if let Some(suffix) = rest.strip_prefix('b') {
+        (natural + 11, suffix)
+    } else {
+        (natural, rest)
+    };
+
+    let root = match index % 12 {
+        0 => ChordRoot::C,
+        1 => ChordRoot::Cs,
+        2 => ChordRoot::D,
+        3 => ChordRoot::Ds,
+        4 => ChordRoot::E,
+        5 => ChordRoot::F,
+        6 => ChordRoot::Fs,
+        7 => ChordRoot::G,
+        8 => ChordRoot::Gs,
+        9 => ChordRoot::A,
+        10 => ChordRoot::As,
+        _ => ChordRoot::B,
+    };
+    let chord_type = match suffix {
+        "" => ChordType::Major,
+        "m" => ChordType::Minor,
+        "7" => ChordType::Major7,
+        "m7" => ChordType::Minor7,
+        "M7" => ChordType::MajorMajor7,
+        "mM7" => ChordType::MinorMajor7,
+        _ => return None,
+    };
+
+    Some((root, chord_type))
+}
+
 pub struct ChordKeySound {
     chords: Vec<(ChordRoot, ChordType)>,
     sources: Vec<KeySoundSource>,
@@ -139,7 +188,7 @@ impl KeySound for ChordKeySound {
 
 #[cfg(test)]
 mod test {
-    use crate::keysound::{ChordRoot, ChordType};
+    use crate::keysound::{parse_chord, ChordRoot, ChordType};
 
     #[test]
     fn test_chord() {
@@ -167,4 +216,17 @@ mod test {
             assert!([9, 0, 4, 8].contains(&(index % 12)));
         }
     }
+
+    #[test]
+    fn test_parse_chord() {
+        assert_eq!(parse_chord("D"), Some((ChordRoot::D, ChordType::Major)));
+        assert_eq!(parse_chord("F#m"), Some((ChordRoot::Fs, ChordType::Minor)));
+        assert_eq!(parse_chord("Cb7"), Some((ChordRoot::B, ChordType::Major7)));
+        assert_eq!(
+            parse_chord("AmM7"),
+            Some((ChordRoot::A, ChordType::MinorMajor7))
+        );
+        assert_eq!(parse_chord("H"), None);
+        assert_eq!(parse_chord("Dsus4"), None);
+    }
 }
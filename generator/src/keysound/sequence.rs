@@ -1,10 +1,9 @@
 use keysound_gen::{synth::Envelope, KeySoundSource, SoundSource};
 use std::collections::HashMap;
-use std::f32::{consts::PI, NAN};
+use std::f32::consts::PI;
 use std::hash::Hash;
 
 use super::KeySound;
-use crate::generate::CHORDS_PER_BAR;
 
 #[derive(Clone, Copy, PartialEq, Debug, Hash)]
 pub enum Oscillator {
@@ -62,7 +61,7 @@ impl Hash for Note {
 
 impl Note {
     pub fn new(osc: Oscillator, note: i32, length: usize, volume: f32) -> Note {
-        assert!(volume != NAN);
+        assert!(!volume.is_nan());
         Note {
             osc,
             note,
@@ -92,6 +91,7 @@ pub struct SequenceKeySound {
     keysound_indices: Vec<Vec<usize>>,
     keysounds: Vec<KeySoundSource>,
     used_keysounds: Vec<Vec<usize>>,
+    bar_offsets: Vec<usize>,
 }
 
 fn silence_keysound() -> KeySoundSource {
@@ -135,12 +135,29 @@ impl SequenceKeySound {
             keysound_indices,
             keysounds,
             used_keysounds: Vec::new(),
+            bar_offsets: Vec::new(),
         }
     }
 
     pub fn keysounds(&self) -> &[KeySoundSource] {
         &self.keysounds
     }
+
+    /// Tracks bars up to `bar_idx` and at least `chords` chords of it, even without `begin_bar`
+    fn grow_bars(&mut self, bar_idx: usize, chords: usize) {
+        while self.used_keysounds.len() <= bar_idx {
+            let offset = match (self.bar_offsets.last(), self.used_keysounds.last()) {
+                (Some(offset), Some(used_keysounds)) => offset + used_keysounds.len(),
+                _ => 0,
+            };
+            self.bar_offsets.push(offset);
+            self.used_keysounds.push(Vec::new());
+        }
+        let used_keysounds = &mut self.used_keysounds[bar_idx];
+        if used_keysounds.len() < chords {
+            used_keysounds.resize(chords, 0);
+        }
+    }
 }
 
 impl KeySound for SequenceKeySound {
@@ -148,39 +165,46 @@ impl KeySound for SequenceKeySound {
         &self.keysounds
     }
 
+    fn begin_bar(&mut self, bar_idx: usize, chords: usize) {
+        self.bar_offsets.truncate(bar_idx);
+        self.used_keysounds.truncate(bar_idx);
+        self.grow_bars(bar_idx, chords);
+    }
+
     fn key_sound_idx(&mut self, bar_idx: usize, chord_pos: usize, chord_idx: usize) -> usize {
-        if self.used_keysounds.len() < bar_idx + 1 {
-            self.used_keysounds
-                .resize(bar_idx + 1, vec![0; CHORDS_PER_BAR]);
-        }
+        self.grow_bars(bar_idx, chord_pos + 1);
         self.used_keysounds[bar_idx][chord_pos] += 1;
-        self.keysound_indices[(bar_idx * CHORDS_PER_BAR + chord_pos) % self.keysound_indices.len()]
+        let offset = self.bar_offsets[bar_idx];
+        self.keysound_indices[(offset + chord_pos) % self.keysound_indices.len()]
             .get(chord_idx)
             .copied()
-            .unwrap_or(self.keysounds.len()) // silence
+            .unwrap_or(0) // silence
     }
 
     fn scratch_sound_idx(&mut self, _bar_idx: usize, _chord_pos: usize) -> usize {
-        self.keysounds.len()
+        0 // silence
     }
 
     fn bgm_sound_indices(&mut self, bar_idx: usize) -> Vec<Vec<Option<usize>>> {
+        self.grow_bars(bar_idx, 0);
         let silence = 0;
+        let chords = self.used_keysounds[bar_idx].len();
 
         let unused_keysounds: Vec<_> = self
             .keysound_indices
             .iter()
-            .skip((bar_idx * CHORDS_PER_BAR) % self.keysound_indices.len())
-            .take(CHORDS_PER_BAR)
+            .cycle()
+            .skip(self.bar_offsets[bar_idx] % self.keysound_indices.len())
+            .take(chords)
             .zip(self.used_keysounds[bar_idx].iter().copied())
             .map(|(keysounds, used_keysounds)| {
                 &keysounds[usize::min(used_keysounds, keysounds.len())..]
             })
             .collect();
-        let bgm_channels = unused_keysounds.iter().map(|x| x.len()).max().unwrap();
+        let bgm_channels = unused_keysounds.iter().map(|x| x.len()).max().unwrap_or(0);
         let mut bgm_channels: Vec<_> = (0..bgm_channels)
             .map(|i| {
-                (0..CHORDS_PER_BAR)
+                (0..chords)
                     .map(|j| unused_keysounds[j].get(i).copied())
                     .collect()
             })
@@ -193,3 +217,37 @@ impl KeySound for SequenceKeySound {
         bgm_channels
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::keysound::{KeySound, Note, Oscillator, SequenceKeySound};
+    use keysound_gen::synth::Envelope;
+
+    #[test]
+    fn test_without_begin_bar() {
+        let notes = [
+            vec![Note::new(Oscillator::Sine, 0, 1, 1.0)],
+            vec![Note::new(Oscillator::Saw, 3, 1, 1.0)],
+        ];
+        let envelope = Envelope::new(0.0, 0.0, 1.0, 0.0);
+        let mut keysounds = SequenceKeySound::new(&notes, 150.0, &envelope);
+        let mut skipped = SequenceKeySound::new(&notes, 150.0, &envelope);
+
+        for bar_idx in 0..3 {
+            keysounds.begin_bar(bar_idx, 3);
+            for chord_pos in 0..3 {
+                assert_eq!(
+                    skipped.key_sound_idx(bar_idx, chord_pos, 0),
+                    keysounds.key_sound_idx(bar_idx, chord_pos, 0)
+                );
+            }
+            assert_eq!(
+                skipped.bgm_sound_indices(bar_idx),
+                keysounds.bgm_sound_indices(bar_idx)
+            );
+        }
+        assert!(SequenceKeySound::new(&notes, 150.0, &envelope)
+            .bgm_sound_indices(2)
+            .is_empty());
+    }
+}
@@ -1,5 +1,18 @@
+pub mod analysis;
 pub mod bms;
+pub mod bmson;
 pub mod chord;
+pub mod config;
+pub mod constraint;
+pub mod course;
+pub mod difficulty;
+pub mod ergonomics;
 pub mod generate;
+pub mod key_mode;
 pub mod keysound;
+pub mod markov;
 pub mod rng;
+pub mod table;
+pub mod template;
+pub mod tempo;
+pub mod transform;
@@ -0,0 +1,240 @@
+use bms_writer::{BmsWriter, LONG_NOTE_CHANNEL_OFFSET};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::key_mode::KeyMode;
+use crate::rng::RNG;
+
+/// A chord of the model, with the gap from the previous chord
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ModelChord {
+    /// Gap from the previous chord in chords of the grid of the model
+    pub gap: usize,
+    pub lanes: Vec<u8>,
+    pub scratch: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+struct Transition {
+    history: Vec<ModelChord>,
+    next: Vec<(ModelChord, u32)>,
+}
+
+/// N-gram model of chord transitions learned from BMS charts
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MarkovModel {
+    order: usize,
+    lanes: usize,
+    chords_per_bar: usize,
+    transitions: Vec<Transition>,
+}
+
+/// Reads the chords of the 1P side of a parsed BMS, on a grid of `chords_per_bar` chords per measure.
+/// The position of the first chord is returned as its gap. Long notes are counted by their starts.
+pub fn chords_from_bms(
+    bms: &BmsWriter,
+    key_mode: KeyMode,
+    chords_per_bar: usize,
+) -> Vec<ModelChord> {
+    let position = |measure: usize, i: usize, row_length: usize| {
+        measure * chords_per_bar + (i * chords_per_bar + row_length / 2) / row_length
+    };
+    let mut chords: BTreeMap<usize, (Vec<u8>, bool)> = BTreeMap::new();
+    let mut push = |position: usize, lane: Option<u8>| {
+        let (lanes, scratch) = chords.entry(position).or_default();
+        match lane {
+            Some(lane) if !lanes.contains(&lane) => lanes.push(lane),
+            Some(_) => {}
+            None => *scratch = true,
+        }
+    };
+
+    let channels = key_mode
+        .key_channels()
+        .iter()
+        .enumerate()
+        .map(|(lane, &channel)| (channel, Some(lane as u8)))
+        .chain(key_mode.scratch_channel().map(|channel| (channel, None)));
+
+    for (channel, lane) in channels {
+        for (measure, row) in bms.channel(channel) {
+            for (i, object) in row.iter().enumerate() {
+                match object {
+                    Some(idx) if Some(*idx) != bms.lnobj() => {
+                        push(position(measure, i, row.len()), lane);
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        // Objects of long note channels alternate between starts and ends
+        let mut long_note_objects: Vec<_> = bms
+            .channel(channel + LONG_NOTE_CHANNEL_OFFSET)
+            .flat_map(|(measure, row)| {
+                let row_length = row.len();
+                row.into_iter()
+                    .enumerate()
+                    .filter(|(_, object)| object.is_some())
+                    .map(move |(i, _)| position(measure, i, row_length))
+            })
+            .collect();
+        long_note_objects.sort_unstable();
+        for &position in long_note_objects.iter().step_by(2) {
+            push(position, lane);
+        }
+    }
+
+    let mut last_position = 0;
+    chords
+        .into_iter()
+        .map(|(position, (mut lanes, scratch))| {
+            lanes.sort_unstable();
+            let gap = position - last_position;
+            last_position = position;
+            ModelChord {
+                gap,
+                lanes,
+                scratch,
+            }
+        })
+        .collect()
+}
+
+impl MarkovModel {
+    /// A model which predicts a chord from the `order` chords before it
+    pub fn new(key_mode: KeyMode, order: usize, chords_per_bar: usize) -> Self {
+        assert!(chords_per_bar > 0);
+        MarkovModel {
+            order,
+            lanes: key_mode.lanes(),
+            chords_per_bar,
+            transitions: Vec::new(),
+        }
+    }
+
+    pub fn chords_per_bar(&self) -> usize {
+        self.chords_per_bar
+    }
+
+    pub fn lanes(&self) -> usize {
+        self.lanes
+    }
+
+    /// Learns the transitions of a chart. Histories shorter than the order are also learned,
+    /// so that sampling can back off to them.
+    pub fn train(&mut self, chords: &[ModelChord]) {
+        let mut counts: BTreeMap<Vec<ModelChord>, BTreeMap<ModelChord, u32>> = self
+            .transitions
+            .drain(..)
+            .map(|transition| (transition.history, transition.next.into_iter().collect()))
+            .collect();
+
+        // The first chord has no meaningful gap
+        for (i, chord) in chords.iter().enumerate().skip(1) {
+            for length in 0..=self.order.min(i - 1) {
+                let history = chords[i - length..i].to_vec();
+                *counts
+                    .entry(history)
+                    .or_default()
+                    .entry(chord.clone())
+                    .or_default() += 1;
+            }
+        }
+
+        self.transitions = counts
+            .into_iter()
+            .map(|(history, next)| Transition {
+                history,
+                next: next.into_iter().collect(),
+            })
+            .collect();
+    }
+
+    /// Samples the next chord after `history`, from the longest history which has been learned.
+    /// Returns `None` if the model has learned nothing.
+    pub fn sample(&self, history: &[ModelChord], rng: &mut RNG) -> Option<ModelChord> {
+        let next = (0..=self.order.min(history.len()))
+            .rev()
+            .find_map(|length| {
+                let history = &history[history.len() - length..];
+                self.transitions
+                    .binary_search_by(|transition| transition.history.as_slice().cmp(history))
+                    .ok()
+                    .map(|idx| &self.transitions[idx].next)
+            })?;
+
+        let total: u32 = next.iter().map(|(_, count)| count).sum();
+        let mut value = (rng.next() % total as u64) as u32;
+        next.iter()
+            .find(|(_, count)| {
+                if value < *count {
+                    return true;
+                }
+                value -= count;
+                false
+            })
+            .map(|(chord, _)| chord.clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{chords_from_bms, MarkovModel, ModelChord};
+    use crate::key_mode::KeyMode;
+    use crate::rng::RNG;
+    use bms_writer::BmsWriter;
+
+    #[test]
+    fn test_chords_from_bms() {
+        let mut bms = BmsWriter::new();
+        bms.push_channel(0, 11, vec![Some(1), None, Some(1), None]);
+        bms.push_channel(0, 19, vec![None, Some(2), None, None]);
+        bms.push_channel(0, 16, vec![Some(3), None]);
+        bms.push_channel(1, 51, vec![Some(4), None, Some(4), None]);
+
+        let chords = chords_from_bms(&bms, KeyMode::Beat7, 8);
+        let chord = |gap, lanes: &[u8], scratch| ModelChord {
+            gap,
+            lanes: lanes.to_vec(),
+            scratch,
+        };
+        assert_eq!(
+            chords,
+            vec![
+                chord(8, &[0], true),
+                chord(2, &[6], false),
+                chord(2, &[0], false),
+                chord(4, &[0], false),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sample() {
+        let chord = |lane| ModelChord {
+            gap: 2,
+            lanes: vec![lane],
+            scratch: false,
+        };
+        let chords: Vec<_> = [0, 1, 0, 1, 0, 1, 2].into_iter().map(chord).collect();
+        let mut model = MarkovModel::new(KeyMode::Beat7, 2, 16);
+        model.train(&chords);
+
+        let mut rng = RNG::new_u64(199024);
+        for _ in 0..100 {
+            assert_eq!(
+                model.sample(&[chord(1), chord(0)], &mut rng),
+                Some(chord(1))
+            );
+            assert_eq!(model.sample(&[chord(2)], &mut rng).map(|c| c.gap), Some(2));
+        }
+
+        let json = serde_json::to_string(&model).unwrap();
+        assert_eq!(serde_json::from_str::<MarkovModel>(&json).unwrap(), model);
+        assert_eq!(
+            MarkovModel::new(KeyMode::Beat7, 2, 16).sample(&[], &mut rng),
+            None
+        );
+    }
+}
@@ -11,6 +11,7 @@ impl RNG {
         RNG([0xC0BEBEEF, seed])
     }
 
+    #[allow(clippy::should_implement_trait)]
     pub fn next(&mut self) -> u64 {
         let [s0, mut s1] = self.0;
         let result = s0.wrapping_add(s1);
@@ -0,0 +1,123 @@
+use std::io::Write;
+
+use serde::Serialize;
+
+use crate::course::chart_hashes;
+use crate::difficulty::Level;
+
+/// A chart of a difficulty table, identified by the hashes of its file
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct TableChart {
+    pub title: String,
+    pub artist: String,
+    /// Level as a bare number, shown after the symbol of the table (e.g. `12` of `☆12`)
+    pub level: String,
+    pub md5: String,
+    pub sha256: String,
+    #[serde(skip)]
+    rank: u32,
+}
+
+impl TableChart {
+    /// `bytes` is the content of the chart file, as written to the disk
+    pub fn new(title: &str, artist: &str, level: Level, bytes: &[u8]) -> Self {
+        let (md5, sha256) = chart_hashes(bytes);
+        TableChart {
+            title: title.to_string(),
+            artist: artist.to_string(),
+            level: level.playlevel().to_string(),
+            md5,
+            sha256,
+            rank: level.rank(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct TableHeader<'a> {
+    name: &'a str,
+    symbol: &'a str,
+    data_url: &'a str,
+    level_order: Vec<&'a str>,
+}
+
+/// Difficulty table, which players load from header.json and data.json.
+/// Levels of a table are on one scale, so normal and insane charts go to separate tables.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DifficultyTable {
+    name: String,
+    symbol: String,
+    charts: Vec<TableChart>,
+}
+
+impl DifficultyTable {
+    pub fn new(name: &str, symbol: &str) -> Self {
+        DifficultyTable {
+            name: name.to_string(),
+            symbol: symbol.to_string(),
+            charts: Vec::new(),
+        }
+    }
+
+    pub fn push_chart(&mut self, chart: TableChart) {
+        self.charts.push(chart);
+    }
+
+    pub fn charts(&self) -> &[TableChart] {
+        &self.charts
+    }
+
+    /// Writes header.json, which refers to the data at `data_url` relative to the header.
+    /// Levels are ordered from the easiest.
+    pub fn write_header(&self, buf: impl Write, data_url: &str) -> serde_json::Result<()> {
+        let mut levels: Vec<_> = self
+            .charts
+            .iter()
+            .map(|chart| (chart.rank, chart.level.as_str()))
+            .collect();
+        levels.sort_unstable();
+        levels.dedup();
+
+        let header = TableHeader {
+            name: &self.name,
+            symbol: &self.symbol,
+            data_url,
+            level_order: levels.into_iter().map(|(_, level)| level).collect(),
+        };
+        serde_json::to_writer_pretty(buf, &header)
+    }
+
+    /// Writes data.json, the list of the charts
+    pub fn write_data(&self, buf: impl Write) -> serde_json::Result<()> {
+        serde_json::to_writer_pretty(buf, &self.charts)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{DifficultyTable, TableChart};
+    use crate::difficulty::Level;
+
+    #[test]
+    fn test_write_table() {
+        let mut table = DifficultyTable::new("drills", "▽");
+        table.push_chart(TableChart::new("a", "", Level::Normal(2), b"a"));
+        table.push_chart(TableChart::new("b", "", Level::Normal(12), b"b"));
+        table.push_chart(TableChart::new("c", "", Level::Normal(2), b"c"));
+
+        let mut buf = Vec::new();
+        table.write_header(&mut buf, "data.json").unwrap();
+        let header: serde_json::Value = serde_json::from_slice(&buf).unwrap();
+        assert_eq!(header["symbol"], "▽");
+        assert_eq!(header["data_url"], "data.json");
+        assert_eq!(header["level_order"], serde_json::json!(["2", "12"]));
+
+        let mut buf = Vec::new();
+        table.write_data(&mut buf).unwrap();
+        let data: serde_json::Value = serde_json::from_slice(&buf).unwrap();
+        assert_eq!(data.as_array().unwrap().len(), 3);
+        assert_eq!(data[1]["level"], "12");
+        assert_eq!(data[0]["md5"], "0cc175b9c0f1b6a831c399e269772661");
+        assert!(data[0].get("rank").is_none());
+    }
+}
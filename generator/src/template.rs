@@ -0,0 +1,197 @@
+use crate::generate::Chord;
+use crate::rng::RNG;
+
+/// Family of patterns built from templates, for drilling a specific pattern
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PatternFamily {
+    /// Single notes moving to the adjacent lane (e.g. 1-2-3-4)
+    Stairs,
+    /// Single notes alternating between two lanes (e.g. 1-2-1-2)
+    Trill,
+    /// Scratch together with keys alternating with keys alone (皿+鍵盤)
+    SplitChord,
+    /// Two-note chords repeated on the same lanes
+    ChordJack,
+    /// Single notes alternating between the left and right hands
+    AlternatingHands,
+}
+
+/// A chord of a template, with lanes relative to the leftmost lane of the template
+type TemplateChord = (&'static [u8], bool);
+
+const STAIRS: &[&[TemplateChord]] = &[
+    &[(&[0], false), (&[1], false), (&[2], false), (&[3], false)],
+    &[
+        (&[0], false),
+        (&[1], false),
+        (&[2], false),
+        (&[3], false),
+        (&[4], false),
+        (&[3], false),
+        (&[2], false),
+        (&[1], false),
+    ],
+];
+
+const TRILL: &[&[TemplateChord]] = &[
+    &[(&[0], false), (&[1], false), (&[0], false), (&[1], false)],
+    &[(&[0], false), (&[2], false), (&[0], false), (&[2], false)],
+];
+
+const SPLIT_CHORD: &[&[TemplateChord]] = &[
+    &[
+        (&[0], true),
+        (&[1, 2], false),
+        (&[0], true),
+        (&[1, 2], false),
+    ],
+    &[
+        (&[1], true),
+        (&[0, 2], false),
+        (&[1], true),
+        (&[0, 2], false),
+    ],
+    &[
+        (&[0, 2], true),
+        (&[1], false),
+        (&[0, 2], true),
+        (&[1], false),
+    ],
+];
+
+const CHORD_JACK: &[&[TemplateChord]] = &[
+    &[
+        (&[0, 1], false),
+        (&[0, 1], false),
+        (&[0, 1], false),
+        (&[0, 1], false),
+    ],
+    &[
+        (&[0, 2], false),
+        (&[0, 2], false),
+        (&[0, 2], false),
+        (&[0, 2], false),
+    ],
+    &[
+        (&[0, 3], false),
+        (&[0, 3], false),
+        (&[0, 3], false),
+        (&[0, 3], false),
+    ],
+];
+
+impl PatternFamily {
+    /// Generates the chords of a template of the family, randomly transposed and mirrored within `lanes`.
+    /// Templates wider than `lanes` are not used.
+    pub fn generate(&self, lanes: usize, rng: &mut RNG) -> Vec<Chord> {
+        let templates = match self {
+            PatternFamily::Stairs => STAIRS,
+            PatternFamily::Trill => TRILL,
+            PatternFamily::SplitChord => SPLIT_CHORD,
+            PatternFamily::ChordJack => CHORD_JACK,
+            PatternFamily::AlternatingHands => return alternating_hands(lanes, rng),
+        };
+        let width = |template: &[TemplateChord]| {
+            template
+                .iter()
+                .flat_map(|(lanes, _)| lanes.iter())
+                .max()
+                .map_or(0, |&lane| lane as usize + 1)
+        };
+        let templates: Vec<_> = templates
+            .iter()
+            .filter(|template| width(template) <= lanes)
+            .collect();
+        if templates.is_empty() {
+            return Vec::new();
+        }
+
+        let template = templates[(rng.next() % templates.len() as u64) as usize];
+        let offset = (rng.next() % (lanes - width(template) + 1) as u64) as u8;
+        let mirror = rng.next_f32() < 0.5;
+
+        template
+            .iter()
+            .map(|&(relative_lanes, scratch)| {
+                let mut lanes: Vec<u8> = relative_lanes
+                    .iter()
+                    .map(|&lane| {
+                        let lane = lane + offset;
+                        if mirror {
+                            lanes as u8 - 1 - lane
+                        } else {
+                            lane
+                        }
+                    })
+                    .collect();
+                lanes.sort_unstable();
+                Chord::new(lanes, scratch)
+            })
+            .collect()
+    }
+}
+
+/// Four single notes alternating between the hands, starting from a random one.
+/// The same lane is not repeated within a hand.
+fn alternating_hands(lanes: usize, rng: &mut RNG) -> Vec<Chord> {
+    if lanes < 4 {
+        return Vec::new();
+    }
+
+    let half = lanes / 2;
+    let hands = [0..half, half..lanes];
+    let first_hand = (rng.next() % 2) as usize;
+    let mut last_lanes = [None, None];
+
+    (0..4)
+        .map(|i| {
+            let hand = (first_hand + i) % 2;
+            let range = &hands[hand];
+            let mut lane = range.start + (rng.next() % range.len() as u64) as usize;
+            if last_lanes[hand] == Some(lane) {
+                lane = range.start + (lane - range.start + 1) % range.len();
+            }
+            last_lanes[hand] = Some(lane);
+            Chord::new(vec![lane as u8], false)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::PatternFamily;
+    use crate::rng::RNG;
+
+    #[test]
+    fn test_generate() {
+        let mut rng = RNG::new_u64(2024);
+        for _ in 0..32 {
+            let chords = PatternFamily::Trill.generate(7, &mut rng);
+            assert_eq!(chords.len(), 4);
+            assert_eq!(chords[0], chords[2]);
+            assert_eq!(chords[1], chords[3]);
+            assert!(chords
+                .iter()
+                .all(|chord| chord.lanes.len() == 1 && chord.lanes[0] < 7));
+
+            let chords = PatternFamily::Stairs.generate(5, &mut rng);
+            assert!(chords
+                .windows(2)
+                .all(|w| w[0].lanes[0].abs_diff(w[1].lanes[0]) == 1));
+
+            let chords = PatternFamily::SplitChord.generate(7, &mut rng);
+            assert!(chords[0].scratch && !chords[1].scratch);
+
+            let chords = PatternFamily::ChordJack.generate(7, &mut rng);
+            assert!(chords.iter().all(|chord| chord == &chords[0]));
+            assert_eq!(chords[0].lanes.len(), 2);
+
+            let chords = PatternFamily::AlternatingHands.generate(7, &mut rng);
+            let left = |lane: u8| lane < 3;
+            assert!(chords
+                .windows(2)
+                .all(|w| left(w[0].lanes[0]) != left(w[1].lanes[0])));
+        }
+        assert!(PatternFamily::Stairs.generate(3, &mut rng).is_empty());
+    }
+}
@@ -0,0 +1,147 @@
+#[derive(Clone, Debug, PartialEq)]
+pub struct BpmChange {
+    pub bar: usize,
+    pub chord_pos: usize,
+    pub bpm: f32,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Stop {
+    pub bar: usize,
+    pub chord_pos: usize,
+    pub length: u32, // by 1/192 of a bar
+}
+
+/// BPM changes and stops of a chart. Positions are chord positions within the bar they belong to.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TempoMap {
+    pub bpm_changes: Vec<BpmChange>,
+    pub stops: Vec<Stop>,
+}
+
+impl TempoMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Changes BPM linearly from `start_bpm` at the first bar to `end_bpm` at the last step,
+    /// every `interval` bars.
+    pub fn gradual(start_bpm: f32, end_bpm: f32, bars: usize, interval: usize) -> Self {
+        assert!(interval > 0);
+        let steps = bars.div_ceil(interval);
+        let mut tempo_map = Self::new();
+
+        for step in 1..steps {
+            let bpm = start_bpm + (end_bpm - start_bpm) * step as f32 / (steps - 1) as f32;
+            tempo_map.push_bpm_change(step * interval, 0, bpm);
+        }
+
+        tempo_map
+    }
+
+    pub fn push_bpm_change(&mut self, bar: usize, chord_pos: usize, bpm: f32) {
+        self.bpm_changes.push(BpmChange {
+            bar,
+            chord_pos,
+            bpm,
+        });
+    }
+
+    pub fn push_stop(&mut self, bar: usize, chord_pos: usize, length: u32) {
+        self.stops.push(Stop {
+            bar,
+            chord_pos,
+            length,
+        });
+    }
+
+    /// Returns the elapsed seconds from the beginning of the chart to the given position.
+    /// `bar_lengths` is the number of chords of each bar, used to locate the events.
+    pub fn seconds_at(
+        &self,
+        initial_bpm: f32,
+        bar_lengths: &[usize],
+        bar: usize,
+        fraction: f32,
+    ) -> f32 {
+        let to_bars = |bar: usize, chord_pos: usize| {
+            let length = bar_lengths.get(bar).copied().unwrap_or(1).max(1);
+            bar as f32 + chord_pos as f32 / length as f32
+        };
+        let target = bar as f32 + fraction;
+
+        let mut bpm_changes: Vec<_> = self
+            .bpm_changes
+            .iter()
+            .map(|change| (to_bars(change.bar, change.chord_pos), change.bpm))
+            .filter(|&(position, _)| position < target)
+            .collect();
+        bpm_changes.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        let bpm_at = |position: f32| {
+            bpm_changes
+                .iter()
+                .rev()
+                .find(|&&(change_position, _)| change_position <= position)
+                .map_or(initial_bpm, |&(_, bpm)| bpm)
+        };
+
+        let mut seconds = 0.0;
+        let mut position = 0.0;
+        let mut bpm = initial_bpm;
+
+        for &(change_position, change_bpm) in bpm_changes.iter() {
+            seconds += (change_position - position) * 240.0 / bpm;
+            position = change_position;
+            bpm = change_bpm;
+        }
+        seconds += (target - position) * 240.0 / bpm;
+
+        for stop in self.stops.iter() {
+            let position = to_bars(stop.bar, stop.chord_pos);
+            if position < target {
+                seconds += stop.length as f32 / 192.0 * 240.0 / bpm_at(position);
+            }
+        }
+
+        seconds
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::TempoMap;
+    use approx::assert_relative_eq;
+
+    #[test]
+    fn test_gradual() {
+        let tempo_map = TempoMap::gradual(150.0, 180.0, 16, 4);
+        let bpm_changes: Vec<_> = tempo_map
+            .bpm_changes
+            .iter()
+            .map(|change| (change.bar, change.bpm))
+            .collect();
+
+        assert_eq!(bpm_changes, vec![(4, 160.0), (8, 170.0), (12, 180.0)]);
+        assert!(TempoMap::gradual(150.0, 180.0, 4, 4).bpm_changes.is_empty());
+    }
+
+    #[test]
+    fn test_seconds_at() {
+        let bar_lengths = [16; 4];
+        let mut tempo_map = TempoMap::new();
+
+        assert_relative_eq!(tempo_map.seconds_at(120.0, &bar_lengths, 4, 0.0), 8.0);
+
+        tempo_map.push_bpm_change(2, 0, 240.0);
+        assert_relative_eq!(tempo_map.seconds_at(120.0, &bar_lengths, 2, 0.0), 4.0);
+        assert_relative_eq!(tempo_map.seconds_at(120.0, &bar_lengths, 4, 0.0), 6.0);
+
+        tempo_map.push_stop(1, 8, 96);
+        assert_relative_eq!(tempo_map.seconds_at(120.0, &bar_lengths, 1, 0.5), 3.0);
+        assert_relative_eq!(tempo_map.seconds_at(120.0, &bar_lengths, 1, 0.75), 4.5);
+
+        tempo_map.push_stop(3, 0, 192);
+        assert_relative_eq!(tempo_map.seconds_at(120.0, &bar_lengths, 4, 0.0), 8.0);
+    }
+}
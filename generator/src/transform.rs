@@ -0,0 +1,329 @@
+use crate::generate::{Chart, ChartDp, Chord, LongNote};
+use crate::rng::RNG;
+
+/// Lane options applied to a chart after generation, like the ones of IIDX
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LaneOption {
+    /// Lanes are reversed
+    Mirror,
+    /// Lanes are shuffled once for the whole chart
+    Random,
+    /// Lanes are rotated by a random offset, and mirrored in half chance
+    RRandom,
+    /// Notes are placed on random lanes chord by chord, avoiding the lanes of the previous chord if possible
+    SRandom,
+    /// Like `SRandom`, but avoiding the lanes of the previous beat if possible
+    HRandom,
+    /// A key of every chord without a scratch is moved to the scratch
+    AllScratch,
+}
+
+fn random_index(len: usize, rng: &mut RNG) -> usize {
+    (rng.next() % len as u64) as usize
+}
+
+/// Moves every note of the chords according to `permutation`, which maps old lanes to new ones
+fn permute<'a>(chords: impl Iterator<Item = &'a mut Chord>, permutation: &[u8]) {
+    for chord in chords {
+        for lane in chord.lanes.iter_mut() {
+            *lane = permutation[*lane as usize];
+        }
+        chord.lanes.sort_unstable();
+        for long_note in chord.long_notes.iter_mut() {
+            long_note.lane = permutation[long_note.lane as usize];
+        }
+    }
+}
+
+/// Places notes on random lanes chord by chord. Lanes played within `window(beat_length)` chords before
+/// are avoided if possible, and lanes held by long notes are never used.
+fn scatter<'a>(
+    chords: impl Iterator<Item = (&'a mut Chord, usize)>,
+    lanes: usize,
+    window: impl Fn(usize) -> usize,
+    rng: &mut RNG,
+) {
+    let mut held_until = vec![None; lanes];
+    let mut last_played: Vec<Option<usize>> = vec![None; lanes];
+    let mut last_keyed = None;
+
+    for (t, (chord, beat_length)) in chords.enumerate() {
+        if chord.lanes.is_empty() {
+            continue;
+        }
+
+        let window = window(beat_length);
+        let mut free: Vec<u8> = (0..lanes as u8)
+            .filter(|&lane| held_until[lane as usize].is_none_or(|until| until < t))
+            .collect();
+        let mut new_lanes = Vec::new();
+        let mut long_notes = Vec::new();
+
+        for &lane in chord.lanes.iter() {
+            let recent = |lane: u8| {
+                last_played[lane as usize]
+                    .is_some_and(|played| t - played <= window || Some(played) == last_keyed)
+            };
+            let preferred: Vec<_> = (0..free.len()).filter(|&i| !recent(free[i])).collect();
+            let idx = if preferred.is_empty() {
+                random_index(free.len(), rng)
+            } else {
+                preferred[random_index(preferred.len(), rng)]
+            };
+            let new_lane = free.swap_remove(idx);
+
+            if let Some(length) = chord.long_note_length(lane) {
+                held_until[new_lane as usize] = Some(t + length);
+                long_notes.push(LongNote {
+                    lane: new_lane,
+                    length,
+                });
+            }
+            new_lanes.push(new_lane);
+        }
+
+        for &lane in new_lanes.iter() {
+            last_played[lane as usize] = Some(t);
+        }
+        last_keyed = Some(t);
+        new_lanes.sort_unstable();
+        chord.lanes = new_lanes;
+        chord.long_notes = long_notes;
+    }
+}
+
+/// Moves a random key of every chord without a scratch to the scratch, unless a backspin is held
+fn all_scratch<'a>(chords: impl Iterator<Item = &'a mut Chord>, rng: &mut RNG) {
+    let mut held: usize = 0;
+
+    for chord in chords {
+        held = held.saturating_sub(1);
+        if held == 0 && !chord.scratch && !chord.lanes.is_empty() {
+            let lane = chord.lanes.remove(random_index(chord.lanes.len(), rng));
+            chord.scratch = true;
+            if let Some(idx) = chord
+                .long_notes
+                .iter()
+                .position(|long_note| long_note.lane == lane)
+            {
+                chord.backspin = Some(chord.long_notes.remove(idx).length);
+            }
+        }
+        if let Some(length) = chord.backspin {
+            held = length + 1;
+        }
+    }
+}
+
+fn apply<'a>(
+    chords: impl Iterator<Item = (&'a mut Chord, usize)>,
+    option: LaneOption,
+    lanes: usize,
+    has_scratch: bool,
+    rng: &mut RNG,
+) {
+    match option {
+        LaneOption::Mirror => {
+            let permutation: Vec<_> = (0..lanes as u8).rev().collect();
+            permute(chords.map(|(chord, _)| chord), &permutation);
+        }
+        LaneOption::Random => {
+            let mut permutation: Vec<_> = (0..lanes as u8).collect();
+            for i in (1..lanes).rev() {
+                permutation.swap(i, random_index(i + 1, rng));
+            }
+            permute(chords.map(|(chord, _)| chord), &permutation);
+        }
+        LaneOption::RRandom => {
+            let offset = 1 + random_index(lanes - 1, rng);
+            let mirror = rng.next_f32() < 0.5;
+            let permutation: Vec<_> = (0..lanes)
+                .map(|lane| {
+                    let lane = (lane + offset) % lanes;
+                    (if mirror { lanes - 1 - lane } else { lane }) as u8
+                })
+                .collect();
+            permute(chords.map(|(chord, _)| chord), &permutation);
+        }
+        LaneOption::SRandom => scatter(chords, lanes, |_| 0, rng),
+        LaneOption::HRandom => scatter(chords, lanes, |beat_length| beat_length, rng),
+        LaneOption::AllScratch => {
+            if has_scratch {
+                all_scratch(chords.map(|(chord, _)| chord), rng);
+            }
+        }
+    }
+}
+
+/// Applies a lane option to the chart. The result only depends on the chart and `rng`.
+pub fn transform_chart(chart: &mut Chart, option: LaneOption, rng: &mut RNG) {
+    let lanes = chart.key_mode.lanes();
+    let has_scratch = chart.key_mode.has_scratch();
+    let chords = chart.bars.iter_mut().flat_map(|bar| {
+        let beat_length = (bar.len() / 4).max(1);
+        bar.iter_mut().map(move |chord| (chord, beat_length))
+    });
+
+    apply(chords, option, lanes, has_scratch, rng);
+}
+
+/// Applies lane options to each side of the DP chart, in order of the 1P side and the 2P side.
+pub fn transform_chart_dp(chart: &mut ChartDp, options: [Option<LaneOption>; 2], rng: &mut RNG) {
+    let lanes = chart.key_mode.lanes();
+    let has_scratch = chart.key_mode.has_scratch();
+
+    for (side, option) in options.into_iter().enumerate() {
+        let Some(option) = option else {
+            continue;
+        };
+        let chords = chart.bars.iter_mut().flat_map(|bar| {
+            let beat_length = (bar.len() / 4).max(1);
+            bar.iter_mut()
+                .map(move |chords| (&mut chords[side], beat_length))
+        });
+
+        apply(chords, option, lanes, has_scratch, rng);
+    }
+}
+
+/// Swaps the 1P side and the 2P side of the DP chart (FLIP)
+pub fn flip(chart: &mut ChartDp) {
+    for chords in chart.bars.iter_mut().flatten() {
+        chords.swap(0, 1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{flip, transform_chart, transform_chart_dp, LaneOption};
+    use crate::{
+        chord::ChordDensity,
+        generate::{
+            generate_chart, generate_chart_dp, Chart, ChartParams, LongNoteParams, NotesParams,
+            Scatter,
+        },
+        rng::RNG,
+    };
+
+    fn test_chart(long_notes: bool) -> Chart {
+        let chart_params = ChartParams::new(150.0, 16, 199024);
+        let mut notes_params = NotesParams::new(
+            ChordDensity::new(vec![vec![200]]),
+            6.0,
+            Scatter::new(0.0, 0.0, false),
+        );
+        if long_notes {
+            notes_params.set_long_notes(LongNoteParams::new(0.2, 1, 4));
+        }
+        generate_chart(&chart_params, &notes_params)
+    }
+
+    fn chord_sizes(chart: &Chart) -> Vec<(usize, usize)> {
+        chart
+            .bars
+            .iter()
+            .flatten()
+            .map(|chord| (chord.lanes.len(), chord.long_notes.len()))
+            .collect()
+    }
+
+    #[test]
+    fn test_mirror() {
+        let original = test_chart(true);
+        let mut chart = test_chart(true);
+        transform_chart(&mut chart, LaneOption::Mirror, &mut RNG::new_u64(2024));
+        let first = &original.bars[0][0];
+        let mirrored: Vec<_> = first.lanes.iter().rev().map(|&lane| 6 - lane).collect();
+        assert_eq!(chart.bars[0][0].lanes, mirrored);
+    }
+
+    #[test]
+    fn test_random() {
+        let original = test_chart(true);
+        let mut chart = test_chart(true);
+        transform_chart(&mut chart, LaneOption::Random, &mut RNG::new_u64(2024));
+        assert_eq!(chord_sizes(&chart), chord_sizes(&original));
+    }
+
+    #[test]
+    fn test_r_random() {
+        let original = test_chart(true);
+        let mut chart = test_chart(true);
+        transform_chart(&mut chart, LaneOption::RRandom, &mut RNG::new_u64(2024));
+        assert_eq!(chord_sizes(&chart), chord_sizes(&original));
+    }
+
+    #[test]
+    fn test_s_random() {
+        let original = test_chart(true);
+        let mut chart = test_chart(true);
+        transform_chart(&mut chart, LaneOption::SRandom, &mut RNG::new_u64(2024));
+        assert_eq!(chord_sizes(&chart), chord_sizes(&original));
+
+        // Two notes per chord on 7 lanes never need jacks
+        let mut chart = test_chart(false);
+        transform_chart(&mut chart, LaneOption::SRandom, &mut RNG::new_u64(2024));
+        let chords: Vec<_> = chart.bars.iter().flatten().collect();
+        assert!(chords.windows(2).all(|pair| pair[1]
+            .lanes
+            .iter()
+            .all(|lane| !pair[0].lanes.contains(lane))));
+    }
+
+    #[test]
+    fn test_h_random() {
+        let original = test_chart(true);
+        let mut chart = test_chart(true);
+        transform_chart(&mut chart, LaneOption::HRandom, &mut RNG::new_u64(2024));
+        assert_eq!(chord_sizes(&chart), chord_sizes(&original));
+
+        let chords: Vec<_> = chart.bars.iter().flatten().collect();
+        for (i, chord) in chords.iter().enumerate() {
+            for long_note in chord.long_notes.iter() {
+                let held = &chords[i + 1..(i + 1 + long_note.length).min(chords.len())];
+                assert!(held
+                    .iter()
+                    .all(|chord| !chord.lanes.contains(&long_note.lane)));
+            }
+        }
+    }
+
+    #[test]
+    fn test_all_scratch() {
+        let mut chart = test_chart(true);
+        transform_chart(&mut chart, LaneOption::AllScratch, &mut RNG::new_u64(2024));
+        let mut held: usize = 0;
+        for chord in chart.bars.iter().flatten() {
+            held = held.saturating_sub(1);
+            assert!(chord.scratch || held > 0);
+            if let Some(length) = chord.backspin {
+                held = length + 1;
+            }
+        }
+    }
+
+    #[test]
+    fn test_flip() {
+        let chart_params = ChartParams::new(150.0, 4, 199024);
+        let notes_params = NotesParams::new(
+            ChordDensity::new(vec![vec![200]]),
+            0.0,
+            Scatter::new(0.0, 0.0, false),
+        );
+        let original = generate_chart_dp(&chart_params, &notes_params, &notes_params);
+        let mut chart = generate_chart_dp(&chart_params, &notes_params, &notes_params);
+        flip(&mut chart);
+        assert_eq!(chart.bars[0][0][0], original.bars[0][0][1]);
+
+        let mut rng = RNG::new_u64(2024);
+        transform_chart_dp(&mut chart, [None, Some(LaneOption::Mirror)], &mut rng);
+        assert_eq!(chart.bars[0][0][0], original.bars[0][0][1]);
+        let mirrored: Vec<_> = original.bars[0][0][0]
+            .lanes
+            .iter()
+            .rev()
+            .map(|&lane| 6 - lane)
+            .collect();
+        assert_eq!(chart.bars[0][0][1].lanes, mirrored);
+    }
+}
@@ -13,27 +13,27 @@ pub fn write_riff<const CHANNELS: usize>(
     let byte_per_second = sample_rate * block_align as u32;
     let samples_bytes = (samples.len() * block_align as usize) as u32;
 
-    buf.write(b"RIFF")?;
-    buf.write(&u32::to_le_bytes(samples_bytes + NON_DATA_SIZE))?;
-    buf.write(b"WAVE")?;
+    buf.write_all(b"RIFF")?;
+    buf.write_all(&u32::to_le_bytes(samples_bytes + NON_DATA_SIZE))?;
+    buf.write_all(b"WAVE")?;
 
     // Format Subchunk
-    buf.write(b"fmt ")?;
-    buf.write(&16u32.to_le_bytes())?; // Subchunk Size
-    buf.write(&PCM.to_le_bytes())?;
-    buf.write(&(CHANNELS as u16).to_le_bytes())?;
-    buf.write(&sample_rate.to_le_bytes())?;
-    buf.write(&byte_per_second.to_le_bytes())?;
-    buf.write(&block_align.to_le_bytes())?;
-    buf.write(&bits_per_sample.to_le_bytes())?;
+    buf.write_all(b"fmt ")?;
+    buf.write_all(&16u32.to_le_bytes())?; // Subchunk Size
+    buf.write_all(&PCM.to_le_bytes())?;
+    buf.write_all(&(CHANNELS as u16).to_le_bytes())?;
+    buf.write_all(&sample_rate.to_le_bytes())?;
+    buf.write_all(&byte_per_second.to_le_bytes())?;
+    buf.write_all(&block_align.to_le_bytes())?;
+    buf.write_all(&bits_per_sample.to_le_bytes())?;
 
     // Data Subchunk
-    buf.write(b"data")?;
-    buf.write(&samples_bytes.to_le_bytes())?; // Subchunk Size
+    buf.write_all(b"data")?;
+    buf.write_all(&samples_bytes.to_le_bytes())?; // Subchunk Size
 
     for sample in samples.iter() {
         for channel in sample.as_ref().iter() {
-            buf.write(&channel.to_le_bytes())?;
+            buf.write_all(&channel.to_le_bytes())?;
         }
     }
 
@@ -1,8 +1,13 @@
 use base64::prelude::*;
 use generator::{
-    bms::{chart_dp_to_bms, chart_to_bms},
+    bms::{write_generated_chart, ChartFormat, JudgeRank, LongNoteMode, Metadata},
     chord::ChordDensity,
-    generate::{generate_chart, generate_chart_dp, ChartParams, NotesParams, Scatter},
+    config::{GeneratorConfig, PRESET_NAMES},
+    generate::{
+        generate_chart, generate_chart_dp, generate_chart_dp_with_schedule,
+        generate_chart_with_schedule, ChartParams, NotesParams, NotesSchedule, Scatter,
+    },
+    key_mode::KeyMode,
     keysound::{ChordKeySound, ChordRoot, ChordType},
 };
 use wasm_bindgen::prelude::*;
@@ -18,28 +23,135 @@ static CHORD_PROGRESSION: [(ChordRoot, ChordType); 8] = [
     (ChordRoot::A, ChordType::Major),
 ];
 
+fn key_mode(keys: u8) -> Result<KeyMode, String> {
+    match keys {
+        5 => Ok(KeyMode::Beat5),
+        7 => Ok(KeyMode::Beat7),
+        9 => Ok(KeyMode::PopN9),
+        _ => Err(format!("{keys} キーには対応していません")),
+    }
+}
+
+#[wasm_bindgen]
+pub fn bms_extension(keys: u8) -> Result<String, String> {
+    Ok(key_mode(keys)?.extension().to_owned())
+}
+
+/// Headers of the BMS file other than the title. Empty texts are not written.
+#[wasm_bindgen]
+#[derive(Clone, Default)]
+pub struct JsMetadata {
+    metadata: Metadata,
+}
+
+#[wasm_bindgen]
+impl JsMetadata {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        JsMetadata::default()
+    }
+
+    pub fn set_subtitle(&mut self, subtitle: String) {
+        self.metadata.subtitle = subtitle;
+    }
+
+    pub fn set_genre(&mut self, genre: String) {
+        self.metadata.genre = genre;
+    }
+
+    pub fn set_artist(&mut self, artist: String) {
+        self.metadata.artist = artist;
+    }
+
+    pub fn set_subartist(&mut self, subartist: String) {
+        self.metadata.subartist = subartist;
+    }
+
+    /// Overrides the level estimated from the chart
+    pub fn set_playlevel(&mut self, playlevel: u32) {
+        self.metadata.playlevel = Some(playlevel);
+    }
+
+    /// Overrides the difficulty estimated from the chart (1: BEGINNER to 5: INSANE)
+    pub fn set_difficulty(&mut self, difficulty: u32) {
+        self.metadata.difficulty = Some(difficulty);
+    }
+
+    /// #RANK (0: VERY HARD, 1: HARD, 2: NORMAL, 3: EASY)
+    pub fn set_rank(&mut self, rank: u32) {
+        self.metadata.rank = JudgeRank::Rank(rank);
+    }
+
+    /// #DEFEXRANK in percentage, which replaces #RANK
+    pub fn set_defexrank(&mut self, rank: u32) {
+        self.metadata.rank = JudgeRank::DefExRank(rank);
+    }
+
+    pub fn set_stagefile(&mut self, stagefile: String) {
+        self.metadata.stagefile = stagefile;
+    }
+
+    pub fn set_banner(&mut self, banner: String) {
+        self.metadata.banner = banner;
+    }
+
+    pub fn set_backbmp(&mut self, backbmp: String) {
+        self.metadata.backbmp = backbmp;
+    }
+
+    /// Replaces the comment describing the generation
+    pub fn set_comment(&mut self, comment: String) {
+        self.metadata.comment = comment;
+    }
+
+    /// Adds a header written as `#COMMAND VALUE`
+    pub fn push_header(&mut self, command: &str, value: &str) {
+        self.metadata.push_header(command, value);
+    }
+
+    /// Metadata of a chart titled `title`, with `comment` unless a comment is set
+    fn to_metadata(&self, title: &str, comment: String) -> Metadata {
+        let mut metadata = self.metadata.clone();
+        metadata.title = title.to_owned();
+        if metadata.comment.is_empty() {
+            metadata.comment = comment;
+        }
+        metadata
+    }
+}
+
 #[wasm_bindgen]
 pub struct JsChartParams {
     bars: usize,
     bpm: f32,
     title: String,
     seed: u64,
+    keys: u8,
+    metadata: JsMetadata,
 }
 
 #[wasm_bindgen]
 impl JsChartParams {
     #[wasm_bindgen(constructor)]
-    pub fn new(bars: usize, bpm: f32, title: String, seed: u64) -> Self {
+    pub fn new(bars: usize, bpm: f32, title: String, seed: u64, keys: u8) -> Self {
         JsChartParams {
             bars,
             bpm,
             title,
             seed,
+            keys,
+            metadata: JsMetadata::default(),
         }
     }
 
-    fn to_chart_params(&self) -> ChartParams {
-        ChartParams::new(self.bpm, self.bars, self.seed)
+    pub fn set_metadata(&mut self, metadata: &JsMetadata) {
+        self.metadata = metadata.clone();
+    }
+
+    fn to_chart_params(&self) -> Result<ChartParams, String> {
+        let mut chart_params = ChartParams::new(self.bpm, self.bars, self.seed);
+        chart_params.set_key_mode(key_mode(self.keys)?);
+        Ok(chart_params)
     }
 }
 
@@ -69,15 +181,74 @@ impl JsNotesParams {
     }
 
     fn to_notes_params(&self) -> NotesParams {
-        let chord_density = ChordDensity::from_power_of_two(&self.chord_density);
+        self.to_section_notes_params(1.0, self.jack_tolerance, self.scatter_strength)
+    }
+
+    fn to_section_notes_params(
+        &self,
+        density_scale: f32,
+        jack_tolerance: f32,
+        scatter_strength: f32,
+    ) -> NotesParams {
+        let chord_density =
+            ChordDensity::from_power_of_two(&self.chord_density).scaled(density_scale);
 
         let scatter = Scatter::new(
-            self.scatter_strength.abs(),
+            scatter_strength.abs(),
             self.scatter_decay_rate,
-            self.scatter_strength < 0.0,
+            scatter_strength < 0.0,
         );
 
-        NotesParams::new(chord_density, self.jack_tolerance, scatter)
+        NotesParams::new(chord_density, jack_tolerance, scatter)
+    }
+
+    fn to_notes_schedule(&self, js_sections: &[JsSection], interpolate: bool) -> NotesSchedule {
+        let mut schedule = NotesSchedule::new(self.to_notes_params());
+        let mut start_bar = 0;
+
+        for section in js_sections {
+            let notes_params = self.to_section_notes_params(
+                section.density_scale,
+                section.jack_tolerance,
+                section.scatter_strength,
+            );
+            if start_bar == 0 {
+                schedule = NotesSchedule::new(notes_params);
+            } else {
+                schedule.push_section(start_bar, notes_params);
+            }
+            start_bar += section.bars.max(1);
+        }
+        schedule.set_interpolate(interpolate);
+
+        schedule
+    }
+}
+
+/// Section of the chart, in which the chord density of each side is multiplied by `density_scale`
+#[wasm_bindgen]
+pub struct JsSection {
+    bars: usize,
+    density_scale: f32,
+    jack_tolerance: f32,
+    scatter_strength: f32,
+}
+
+#[wasm_bindgen]
+impl JsSection {
+    #[wasm_bindgen(constructor)]
+    pub fn new(
+        bars: usize,
+        density_scale: f32,
+        jack_tolerance: f32,
+        scatter_strength: f32,
+    ) -> Self {
+        JsSection {
+            bars,
+            density_scale,
+            jack_tolerance,
+            scatter_strength,
+        }
     }
 }
 
@@ -90,90 +261,114 @@ pub fn data_uri(content: &[u8], mime: &str) -> String {
 pub fn generate_bms(
     js_chart_params: JsChartParams,
     js_notes_params: Vec<JsNotesParams>,
-) -> Option<Vec<u8>> {
-    assert!((1..=2).contains(&js_notes_params.len()));
-
-    let chart_params = js_chart_params.to_chart_params();
-    if js_notes_params.len() == 1 {
-        let notes_params = js_notes_params[0].to_notes_params();
-        let chart = generate_chart(&chart_params, &notes_params);
-
-        let notes: usize = chart
-            .bars
-            .iter()
-            .flatten()
-            .map(|chord| chord.lanes.len())
-            .sum();
-        let total = f32::max(1000.0 - 1000000.0 / (1000.0 + notes as f32), 250.0);
-        let duration = 240.0 / chart.bpm * chart.bars.len() as f32;
-        let density = notes as f32 / duration;
-        let genre = format!("密度: {density:.02} notes/s");
-        let artist = format!(
-            "jacks: {:.01}, scatter: {:.01}, seed: {:?}",
-            js_notes_params[0].jack_tolerance,
-            js_notes_params[0].scatter_strength,
-            js_chart_params.seed,
-        );
-
-        let mut keysounds = ChordKeySound::new(CHORD_PROGRESSION.to_vec());
+    js_sections: Vec<JsSection>,
+    interpolate: bool,
+) -> Result<Vec<u8>, String> {
+    let chart_params = js_chart_params.to_chart_params()?;
+    if !(1..=2).contains(&js_notes_params.len()) {
+        return Err(format!(
+            "譜面のパラメータは 1 つか 2 つ必要ですが {} つ渡されました",
+            js_notes_params.len()
+        ));
+    }
+    if js_notes_params.len() == 2 && !key_mode(js_chart_params.keys)?.supports_dp() {
+        return Err(format!(
+            "{} キーは DP に対応していません",
+            js_chart_params.keys
+        ));
+    }
 
-        let mut bms: Vec<u8> = Vec::new();
+    let jack_and_scatter = Some((
+        js_notes_params[0].jack_tolerance,
+        js_notes_params[0].scatter_strength,
+    ));
+    let metadata = |comment| {
+        js_chart_params
+            .metadata
+            .to_metadata(&js_chart_params.title, comment)
+    };
+    let format = ChartFormat::Bms(LongNoteMode::Channel);
+    let mut keysounds = ChordKeySound::new(CHORD_PROGRESSION.to_vec());
+    let mut bms: Vec<u8> = Vec::new();
 
-        if chart_to_bms(
+    if js_notes_params.len() == 1 {
+        let schedule = js_notes_params[0].to_notes_schedule(&js_sections, interpolate);
+        let chart = generate_chart_with_schedule(&chart_params, &schedule);
+        write_generated_chart(
             &mut bms,
             &chart,
-            &js_chart_params.title,
-            &genre,
-            &artist,
-            total,
+            format,
+            jack_and_scatter,
+            js_chart_params.seed,
+            metadata,
             &mut keysounds,
         )
-        .is_ok()
-        {
-            Some(bms)
-        } else {
-            None
-        }
     } else {
-        let notes_params_left = js_notes_params[0].to_notes_params();
-        let notes_params_right = js_notes_params[1].to_notes_params();
-        let chart = generate_chart_dp(&chart_params, &notes_params_left, &notes_params_right);
-
-        let notes: usize = chart
-            .bars
-            .iter()
-            .flatten()
-            .flat_map(|chords| chords.iter().map(|chord| chord.lanes.len()))
-            .sum();
-        let total = f32::max(1000.0 - 1000000.0 / (1000.0 + notes as f32), 250.0);
-        let duration = 240.0 / chart.bpm * chart.bars.len() as f32;
-        let density = notes as f32 / duration;
-        let genre = format!("密度: {density:.02} notes/s");
-        let artist = format!(
-            "jacks: {:.01}, scatter: {:.01}, seed: {:?}",
-            js_notes_params[0].jack_tolerance,
-            js_notes_params[0].scatter_strength,
+        let schedule_left = js_notes_params[0].to_notes_schedule(&js_sections, interpolate);
+        let schedule_right = js_notes_params[1].to_notes_schedule(&js_sections, interpolate);
+        let chart = generate_chart_dp_with_schedule(&chart_params, &schedule_left, &schedule_right);
+        write_generated_chart(
+            &mut bms,
+            &chart,
+            format,
+            jack_and_scatter,
             js_chart_params.seed,
-        );
+            metadata,
+            &mut keysounds,
+        )
+    }
+    .map_err(|err| err.to_string())?;
+    Ok(bms)
+}
 
-        let mut keysounds = ChordKeySound::new(CHORD_PROGRESSION.to_vec());
+#[wasm_bindgen]
+pub fn preset_names() -> Vec<String> {
+    PRESET_NAMES.map(String::from).to_vec()
+}
 
-        let mut bms: Vec<u8> = Vec::new();
+/// TOML of a built-in preset
+#[wasm_bindgen]
+pub fn preset_config(name: &str) -> Option<String> {
+    GeneratorConfig::preset(name).map(|config| config.to_toml())
+}
 
-        if chart_dp_to_bms(
+/// Generates a BMS from a TOML config. `seed` is used if the config has none.
+#[wasm_bindgen]
+pub fn generate_bms_from_config(config: &str, seed: u64) -> Result<Vec<u8>, String> {
+    let config = GeneratorConfig::from_toml(config)?;
+    let seed = config.seed.unwrap_or(seed);
+    let chart_params = config.chart_params(seed);
+    let notes_params = config.notes_params();
+    let mut keysounds = ChordKeySound::new(config.chord_progression());
+    let mut bms: Vec<u8> = Vec::new();
+
+    let jack_and_scatter = Some((config.jack_tolerance, config.scatter));
+    let metadata = |comment| config.metadata(comment);
+    let format = ChartFormat::Bms(LongNoteMode::Channel);
+
+    let written = if config.dp {
+        let chart = generate_chart_dp(&chart_params, &notes_params, &notes_params);
+        write_generated_chart(
             &mut bms,
             &chart,
-            &js_chart_params.title,
-            &genre,
-            &artist,
-            total,
+            format,
+            jack_and_scatter,
+            seed,
+            metadata,
             &mut keysounds,
         )
-        .is_ok()
-        {
-            Some(bms)
-        } else {
-            None
-        }
-    }
+    } else {
+        let chart = generate_chart(&chart_params, &notes_params);
+        write_generated_chart(
+            &mut bms,
+            &chart,
+            format,
+            jack_and_scatter,
+            seed,
+            metadata,
+            &mut keysounds,
+        )
+    };
+
+    written.map(|_| bms).map_err(|err| err.to_string())
 }